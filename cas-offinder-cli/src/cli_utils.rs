@@ -1,36 +1,112 @@
+use crate::output::OutputFormat;
 use cas_offinder_lib::*;
 use std::fs::File;
 use std::io::{BufRead, BufReader, Read};
-fn get_dev_ty(arg: &str) -> Result<OclDeviceType> {
-    let dev_parse_err = CliError::ArgumentError("2nd argument must be one of {{C|G|A}}");
-    if arg.len() != 1 {
-        return Err(dev_parse_err);
-    }
-    let firstc = arg.chars().next().unwrap();
-    match firstc {
-        'C' => Ok(OclDeviceType::CPU),
-        'G' => Ok(OclDeviceType::GPU),
-        'A' => Ok(OclDeviceType::ACCEL),
-        _ => Err(dev_parse_err),
+use std::path::Path;
+fn get_dev_ty(arg: &str) -> Result<(OclDeviceType, Vec<usize>)> {
+    // a device type letter, optionally followed by comma separated device ids, e.g. G0,2
+    let dev_ty = match arg.chars().next() {
+        Some('C') => OclDeviceType::CPU,
+        Some('G') => OclDeviceType::GPU,
+        Some('A') => OclDeviceType::ACCEL,
+        _ => {
+            return Err(CliError::ArgumentError(
+                "2nd argument must be one of {{C|G|A}}",
+            ))
+        }
+    };
+    let id_list = &arg[1..];
+    if id_list.is_empty() {
+        return Ok((dev_ty, Vec::new()));
+    }
+    let mut dev_ids: Vec<usize> = Vec::new();
+    for id_str in id_list.split(',') {
+        let id = id_str
+            .parse::<usize>()
+            .map_err(|_| CliError::ArgumentError("Device ids must be numbers, e.g. G0,2"))?;
+        if !dev_ids.contains(&id) {
+            dev_ids.push(id);
+        }
     }
+    Ok((dev_ty, dev_ids))
 }
+const DEFAULT_INDEX_MISMATCHES: u32 = 4;
 pub struct SearchRunInfo {
     pub genome_path: String,
     pub out_path: String,
     pub dev_ty: OclDeviceType,
-    pub search_filter: Vec<u8>,
+    // indices into the numbered device list of dev_ty, empty for all devices
+    pub dev_ids: Vec<usize>,
+    // thread budget of the Rust CPU backend, from --threads
+    pub n_threads: Option<usize>,
+    // search filter of every pattern, picked by pattern length
+    pub search_filters: Vec<Vec<u8>>,
     pub patterns: Vec<Vec<u8>>,
     pub pattern_infos: Vec<String>,
-    pub pattern_len: usize,
+    pub max_mismatches: Vec<u32>,
+    pub max_dna_bulge: u32,
+    pub max_rna_bulge: u32,
+    // drop hits whose protospacer is fully soft-masked
+    pub skip_repeats: bool,
+    // only search inside these regions
+    pub regions: Option<GenomeRegions>,
+    // never search inside these regions
+    pub excluded: Option<GenomeRegions>,
+    pub chrom_filter: ChromFilter,
+    pub out_format: OutputFormat,
+    pub track_name: Option<String>,
+    // add a CFD column to tab separated output
+    pub with_cfd: bool,
+    // drop hits with a CFD score below this
+    pub min_cfd: Option<f64>,
+    // per-guide summary file, None when the hits go to stdout and no --summary is given
+    pub summary_path: Option<String>,
+    // write the summary to the output file instead of the hits
+    pub summary_only: bool,
+    // genes and features from a GTF/GFF3 file, to annotate the hits with
+    pub annotation: Option<GeneAnnotation>,
+    // genome index from cas-offinder index, searched instead of the genome
+    pub index_path: Option<String>,
+}
+pub struct IndexRunInfo {
+    pub genome_path: String,
+    pub search_filter: Vec<u8>,
+    // highest mismatch threshold the index will be searched with
     pub max_mismatches: u32,
+    pub index_path: String,
 }
 struct InFileInfo {
     genome_path: String,
-    search_filter: Vec<u8>,
+    search_filters: Vec<Vec<u8>>,
     patterns: Vec<Vec<u8>>,
     pattern_infos: Vec<String>,
-    pattern_len: usize,
-    max_mismatches: u32,
+    max_mismatches: Vec<u32>,
+    max_dna_bulge: u32,
+    max_rna_bulge: u32,
+}
+fn parse_bulge_size(arg: &str) -> Result<u32> {
+    arg.parse::<u32>()
+        .map_err(|_| CliError::ArgumentError("Bulge sizes must be unsigned integers"))
+}
+fn parse_search_filter_line(line: &str) -> Result<(Vec<u8>, u32, u32)> {
+    let lineparts: Vec<&str> = line.split_ascii_whitespace().collect();
+    let (max_dna_bulge, max_rna_bulge) = match lineparts.len() {
+        1 => (0, 0),
+        3 => (
+            parse_bulge_size(lineparts[1])?,
+            parse_bulge_size(lineparts[2])?,
+        ),
+        _ => {
+            return Err(CliError::ArgumentError(
+                "Search filter line must have following elements: <pattern> [<DNA bulge size> <RNA bulge size>]",
+            ))
+        }
+    };
+    Ok((
+        lineparts[0].as_bytes().to_vec(),
+        max_dna_bulge,
+        max_rna_bulge,
+    ))
 }
 fn parse_and_validate_input(in_path: &String) -> Result<InFileInfo> {
     let file = if in_path != "-" {
@@ -50,19 +126,33 @@ fn parse_and_validate_input(in_path: &String) -> Result<InFileInfo> {
     let searcher_line = line_iter
         .next()
         .ok_or(CliError::ArgumentError(file_too_short_err))??;
-    let search_filter = searcher_line.trim_end().as_bytes().to_vec();
+    let (search_filter, max_dna_bulge, max_rna_bulge) = parse_search_filter_line(&searcher_line)?;
     if !is_mixedbase_str(&search_filter) {
         return Err(mixed_base_error);
     }
-    let pattern_len = search_filter.len();
+    // patterns of other lengths get their own filter on a line of its own
+    let mut search_filters: Vec<Vec<u8>> = vec![search_filter];
 
     let mut patterns: Vec<Vec<u8>> = Vec::new();
     let mut pattern_infos: Vec<String> = Vec::new();
+    let mut max_mismatches: Vec<u32> = Vec::new();
     let mut is_using_info_opt: Option<bool> = None;
-    let mut mismatches_opt: Option<u32> = None;
     for line_r in line_iter {
         let line = line_r?;
         let lineparts: Vec<&str> = line.split_ascii_whitespace().collect();
+        if lineparts.len() == 1 {
+            let extra_filter = lineparts[0].as_bytes().to_vec();
+            if !is_mixedbase_str(&extra_filter) {
+                return Err(mixed_base_error);
+            }
+            if search_filters.iter().any(|f| f.len() == extra_filter.len()) {
+                return Err(CliError::ArgumentError(
+                    "Only one search filter per pattern length is allowed",
+                ));
+            }
+            search_filters.push(extra_filter);
+            continue;
+        }
         if lineparts.len() != 2 && lineparts.len() != 3 {
             return Err(CliError::ArgumentError(
                 "Pattern line must have following elements: <patterns> <mismatches> [<label>]",
@@ -77,61 +167,211 @@ fn parse_and_validate_input(in_path: &String) -> Result<InFileInfo> {
                 "2nd element of each pattern in mismatches must be an unsigned integer",
             )
         })?;
-        mismatches_opt = mismatches_opt.or(Some(cur_mismatches));
         is_using_info_opt = is_using_info_opt.or(Some(lineparts.len() == 3));
-        let mismatches = mismatches_opt.unwrap();
         let is_using_info = is_using_info_opt.unwrap();
-        if mismatches != cur_mismatches {
-            return Err(CliError::ArgumentError("In this version of cas-offinder, all mismatches on every line of input file must be the same"));
-        }
         let cur_info = if is_using_info {
             lineparts.get(2).ok_or(CliError::ArgumentError("Pattern lines in input file must be consistently have either 2 or 3 elements, no mixing and matching allowed"))?
         } else {
             lineparts[0]
         };
 
-        if pattern_buf.len() != pattern_len {
-            return Err(CliError::ArgumentError(
-                "All patters in input file must be same length",
-            ));
-        }
         patterns.push(pattern_buf);
         pattern_infos.push(cur_info.to_string());
+        max_mismatches.push(cur_mismatches);
     }
-    match mismatches_opt {
-        None => Err(CliError::ArgumentError(
+    if patterns.is_empty() {
+        return Err(CliError::ArgumentError(
             "Input file must contain at least 1 pattern line",
-        )),
-        Some(max_mismatches) => Ok(InFileInfo {
-            genome_path,
-            search_filter,
-            patterns,
-            pattern_infos,
-            pattern_len,
-            max_mismatches,
-        }),
+        ));
     }
+    let pattern_filters = patterns
+        .iter()
+        .map(|p| {
+            search_filters
+                .iter()
+                .find(|f| f.len() == p.len())
+                .cloned()
+                .ok_or(CliError::ArgumentError(
+                    "Every pattern needs a search filter of the same length",
+                ))
+        })
+        .collect::<Result<Vec<Vec<u8>>>>()?;
+    Ok(InFileInfo {
+        genome_path,
+        search_filters: pattern_filters,
+        patterns,
+        pattern_infos,
+        max_mismatches,
+        max_dna_bulge,
+        max_rna_bulge,
+    })
+}
+fn option_value<'a>(arg_iter: &mut impl Iterator<Item = &'a String>) -> Result<&'a String> {
+    arg_iter
+        .next()
+        .ok_or(CliError::ArgumentError("Option is missing its value"))
 }
 pub fn parse_and_validate_args(args: &Vec<String>) -> Result<SearchRunInfo> {
     //Usage: cas-offinder {{input_file/directory}} {{mismatches}} {{C|G|A}}[device_id(s)] {{output_file}} {{pattern1}} [{{optional patterns}}...]
-    if args.len() < 4 {
+    // options start with --, "-" alone still means stdin/stdout
+    let mut positional: Vec<&String> = Vec::new();
+    let mut skip_repeats = false;
+    let mut regions: Option<GenomeRegions> = None;
+    let mut exclude_paths: Vec<&Path> = Vec::new();
+    let mut chrom_filter = ChromFilter::default();
+    let mut out_format = OutputFormat::Tsv;
+    let mut track_name: Option<String> = None;
+    let mut with_cfd = false;
+    let mut min_cfd: Option<f64> = None;
+    let mut summary_path: Option<String> = None;
+    let mut summary_only = false;
+    let mut annotation: Option<GeneAnnotation> = None;
+    let mut n_threads: Option<usize> = None;
+    let mut index_path: Option<String> = None;
+    let mut arg_iter = args.iter().skip(1);
+    while let Some(arg) = arg_iter.next() {
+        match arg.as_str() {
+            "--skip-repeats" => skip_repeats = true,
+            "--regions" => {
+                regions = Some(GenomeRegions::from_bed(Path::new(option_value(
+                    &mut arg_iter,
+                )?))?)
+            }
+            "--exclude" => exclude_paths.push(Path::new(option_value(&mut arg_iter)?)),
+            "--include-chrom" => chrom_filter.include(option_value(&mut arg_iter)?)?,
+            "--exclude-chrom" => chrom_filter.exclude(option_value(&mut arg_iter)?)?,
+            "--format" => {
+                out_format = OutputFormat::parse(option_value(&mut arg_iter)?)
+                    .ok_or(CliError::ArgumentError("Unknown output format"))?
+            }
+            "--track-name" => track_name = Some(option_value(&mut arg_iter)?.clone()),
+            "--cfd" => with_cfd = true,
+            "--summary" => summary_path = Some(option_value(&mut arg_iter)?.clone()),
+            "--summary-only" => summary_only = true,
+            "--annotation" => {
+                annotation = Some(GeneAnnotation::from_file(Path::new(option_value(
+                    &mut arg_iter,
+                )?))?)
+            }
+            "--min-cfd" => {
+                min_cfd = Some(
+                    option_value(&mut arg_iter)?
+                        .parse::<f64>()
+                        .map_err(|_| CliError::ArgumentError("--min-cfd must be a number"))?,
+                )
+            }
+            "--index" => index_path = Some(option_value(&mut arg_iter)?.clone()),
+            "--threads" => {
+                n_threads = Some(
+                    option_value(&mut arg_iter)?
+                        .parse::<usize>()
+                        .ok()
+                        .filter(|n| *n > 0)
+                        .ok_or(CliError::ArgumentError(
+                            "--threads must be a positive number",
+                        ))?,
+                )
+            }
+            opt if opt.starts_with("--") => return Err(CliError::ArgumentError("Unknown option")),
+            _ => positional.push(arg),
+        }
+    }
+    if track_name.is_some() && out_format != OutputFormat::Bed {
+        return Err(CliError::ArgumentError(
+            "--track-name only applies to --format bed",
+        ));
+    }
+    if summary_only && summary_path.is_some() {
+        return Err(CliError::ArgumentError(
+            "--summary-only writes the summary to the output file, it can not be combined with --summary",
+        ));
+    }
+    let excluded = if exclude_paths.is_empty() {
+        None
+    } else {
+        Some(GenomeRegions::from_bed_files(&exclude_paths)?)
+    };
+    if positional.len() < 3 {
         return Err(CliError::ArgumentError(
             "Too few arguments, expected 3 arguments",
         ));
     }
-    let in_filename = &args[1];
-    let device_ty_str = &args[2];
-    let out_filename = &args[3];
+    let in_filename = positional[0];
+    let device_ty_str = positional[1];
+    let out_filename = positional[2];
     let parsed_in_file = parse_and_validate_input(in_filename)?;
+    let (dev_ty, dev_ids) = get_dev_ty(device_ty_str)?;
+    if index_path.is_some()
+        && (parsed_in_file.max_dna_bulge > 0 || parsed_in_file.max_rna_bulge > 0)
+    {
+        return Err(CliError::ArgumentError(
+            "Bulges can not be searched with --index",
+        ));
+    }
+    // by default the summary is written next to the hit list
+    if summary_only {
+        summary_path = Some(out_filename.clone());
+    } else if summary_path.is_none() && out_filename != "-" {
+        summary_path = Some(format!("{}.summary.tsv", out_filename));
+    }
     Ok(SearchRunInfo {
         genome_path: parsed_in_file.genome_path,
-        search_filter: parsed_in_file.search_filter,
+        search_filters: parsed_in_file.search_filters,
         patterns: parsed_in_file.patterns,
         pattern_infos: parsed_in_file.pattern_infos,
-        pattern_len: parsed_in_file.pattern_len,
         max_mismatches: parsed_in_file.max_mismatches,
+        max_dna_bulge: parsed_in_file.max_dna_bulge,
+        max_rna_bulge: parsed_in_file.max_rna_bulge,
+        skip_repeats,
+        regions,
+        excluded,
+        chrom_filter,
+        out_format,
+        track_name,
+        with_cfd,
+        min_cfd,
+        summary_path,
+        summary_only,
+        annotation,
+        index_path,
         out_path: out_filename.clone(),
-        dev_ty: get_dev_ty(device_ty_str)?,
+        dev_ty,
+        dev_ids,
+        n_threads,
+    })
+}
+pub fn parse_index_args(args: &[String]) -> Result<IndexRunInfo> {
+    //Usage: cas-offinder index [--max-mismatches N] {{genome_path}} {{search_filter}} {{index_file}}
+    let mut positional: Vec<&String> = Vec::new();
+    let mut max_mismatches = DEFAULT_INDEX_MISMATCHES;
+    let mut arg_iter = args.iter().skip(2);
+    while let Some(arg) = arg_iter.next() {
+        match arg.as_str() {
+            "--max-mismatches" => {
+                max_mismatches = option_value(&mut arg_iter)?.parse::<u32>().map_err(|_| {
+                    CliError::ArgumentError("--max-mismatches must be an unsigned integer")
+                })?
+            }
+            opt if opt.starts_with("--") => return Err(CliError::ArgumentError("Unknown option")),
+            _ => positional.push(arg),
+        }
+    }
+    if positional.len() != 3 {
+        return Err(CliError::ArgumentError(
+            "index expects 3 arguments: {genome_path} {search_filter} {index_file}",
+        ));
+    }
+    let search_filter = positional[1].as_bytes().to_vec();
+    if !is_mixedbase_str(&search_filter) {
+        return Err(CliError::ArgumentError(
+            "Search filter needs to be a mixed base string",
+        ));
+    }
+    Ok(IndexRunInfo {
+        genome_path: positional[0].clone(),
+        search_filter,
+        max_mismatches,
+        index_path: positional[2].clone(),
     })
 }
 
@@ -150,4 +390,48 @@ mod tests {
         string_to_bit4(&mut actual_out, input_data, offset, mixed_base);
         assert_eq!(actual_out, expected_out);
     }
+    #[test]
+    fn test_get_dev_ty() {
+        let (dev_ty, dev_ids) = get_dev_ty("G0,2").unwrap();
+        assert!(matches!(dev_ty, OclDeviceType::GPU));
+        assert_eq!(dev_ids, vec![0, 2]);
+        let (dev_ty, dev_ids) = get_dev_ty("C").unwrap();
+        assert!(matches!(dev_ty, OclDeviceType::CPU));
+        assert!(dev_ids.is_empty());
+        assert_eq!(get_dev_ty("A1,1").unwrap().1, vec![1]);
+        assert!(get_dev_ty("").is_err());
+        assert!(get_dev_ty("X0").is_err());
+        assert!(get_dev_ty("G0,").is_err());
+        assert!(get_dev_ty("Gx").is_err());
+    }
+    #[test]
+    fn test_summary_only_with_summary() {
+        let args: Vec<String> = [
+            "cas-offinder",
+            "--summary-only",
+            "--summary",
+            "summary.tsv",
+            "in.txt",
+            "C",
+            "out",
+        ]
+        .iter()
+        .map(|a| a.to_string())
+        .collect();
+        assert!(matches!(
+            parse_and_validate_args(&args),
+            Err(CliError::ArgumentError(_))
+        ));
+    }
+    #[test]
+    fn test_track_name_needs_bed() {
+        let args: Vec<String> = ["cas-offinder", "--track-name", "hits", "in.txt", "C", "out"]
+            .iter()
+            .map(|a| a.to_string())
+            .collect();
+        assert!(matches!(
+            parse_and_validate_args(&args),
+            Err(CliError::ArgumentError(_))
+        ));
+    }
 }
@@ -1,18 +1,23 @@
 mod cli_utils;
+mod output;
+mod sam;
+mod summary;
 
 use crate::cli_utils::parse_and_validate_args;
-use crate::cli_utils::SearchRunInfo;
+use crate::cli_utils::{parse_index_args, IndexRunInfo, SearchRunInfo};
+use crate::output::{create_hit_writer, HitWriterConfig, RunMetadata};
+use crate::sam::ChromSizes;
+use crate::summary::{write_guide_summary, GuideSummary};
 use cas_offinder_lib::*;
 use std::env;
 use std::fs::File;
 use std::io::BufWriter;
-use std::io::Read;
 use std::io::Write;
 use std::path::Path;
 use std::sync::mpsc;
+use std::sync::{Arc, Mutex};
 use std::thread;
 use std::time::Instant;
-use std::fs;
 
 fn get_usage(device_strs: &[String]) -> String {
     const PKG_VERSION: &str = env!("CARGO_PKG_VERSION");
@@ -29,14 +34,49 @@ Website: {}
 
 Usage: cas-offinder [options] {{input_filename|-}} {{C|G|A}}[device_id(s)] {{output_filename|-}}
 (C: using CPUs, G: using GPUs, A: using accelerators)
+(append device ids from the device list below to use only those devices, e.g. G0,2)
+
+Options:
+--skip-repeats  drop hits whose protospacer lies fully inside soft-masked (lowercase) repeats
+--regions FILE  only search the intervals of a BED file, hits have to overlap an interval
+--exclude FILE  skip the intervals of a BED file, e.g. a blacklist (can be given more than once)
+--include-chrom NAME  only search chromosomes matching NAME (can be given more than once)
+--exclude-chrom NAME  skip chromosomes matching NAME (can be given more than once)
+    NAME is an exact name, a glob like chrUn_* or a regular expression like re:_alt$
+--format FORMAT  output format, one of tsv (default), bed, sam, bam or jsonl
+--track-name NAME  start BED output with a UCSC track line, coloring hits by mismatch count
+--cfd  add the CFD score of every hit as last column of the tab separated output
+--min-cfd SCORE  drop hits with a CFD score below SCORE (hits CFD does not apply to are kept)
+--summary FILE  write the per-guide hit counts and MIT/CFD specificity to FILE
+    (default: output_filename.summary.tsv, none when writing to stdout)
+--summary-only  write only the per-guide summary, to output_filename (not with --summary)
+--threads N  number of threads of the CPU search and of index searches, counting the genome
+    reader and the writer (ignored when OpenCL devices are used)
+    (default: OMP_NUM_THREADS, RAYON_NUM_THREADS or SLURM_CPUS_PER_TASK, else all cores)
+--annotation FILE  annotate hits with the feature class (exon, intron, UTR, promoter, intergenic),
+    genes and distance to the nearest gene from a GTF or GFF3 file (XF, XG, XI and XD tags in SAM/BAM)
+--index FILE  search a genome index from cas-offinder index instead of the genome, for patterns
+    of the index's search filter without bulges (the genome line of the input file is ignored)
+
+Usage: cas-offinder index [--max-mismatches N] {{genome_path}} {{search_filter}} {{index_filename}}
+Builds a genome index for one search filter, for searches of up to N mismatches (default: 4).
+The index takes about 4 * (N + 2) bytes per search filter site plus 5 bits per genome base
+    (around 21 GB for hg38 with NRG and N = 4), building it needs as much memory.
 
 Example input file:
 /var/chromosomes/human_hg19
 NNNNNNNNNNNNNNNNNNNNNRG
 GGCCGACCTGTCGCTGACGCNNN 5
 CGCCAGCGTCAGCGACAGGTNNN 5
-ACGGCGCCAGCGTCAGCGACNNN 5
-GTCGCTGACGCTGGCGCCGTNNN 5
+ACGGCGCCAGCGTCAGCGACNNN 3
+GTCGCTGACGCTGGCGCCGTNNN 3
+
+Patterns of another length need their own search filter line, e.g. for Cas12a:
+TTTVNNNNNNNNNNNNNNNNNNNNNNN
+NNNNGATTGTGAAGAGTAAGAAGTTCC 4
+
+To also search for bulges, append the maximum DNA and RNA bulge sizes to the search filter line:
+NNNNNNNNNNNNNNNNNNNNNRG 1 1
 
 Available device list:
 {}
@@ -44,118 +84,255 @@ Available device list:
         PKG_VERSION, AUTHORS, HOMEPAGE, dev_info
     )
 }
-fn get_usage_with_devices() -> String {
-    let run_config = match OclRunConfig::new(OclDeviceType::ALL) {
-        Err(err) => panic!("OpenCL runtime errored on load with error: {}", err),
-        Ok(cfg) => cfg,
-    };
-    
-    get_usage(&run_config.get_device_strs())
+fn get_usage_with_devices() -> Result<String> {
+    Ok(get_usage(&OclRunConfig::list_devices()?))
 }
 fn main() {
     let args: Vec<String> = env::args().collect();
+    if let Err(err) = run(args) {
+        eprintln!("Error: {}", err);
+        std::process::exit(1);
+    }
+}
+fn run(args: Vec<String>) -> Result<()> {
     if args.len() < 2 {
-        eprintln!("{}", get_usage_with_devices());
-        return;
+        eprintln!("{}", get_usage_with_devices()?);
+        return Ok(());
+    }
+    if args[1] == "index" {
+        return run_index(&args);
     }
     let start_time = Instant::now();
-    let run_info: SearchRunInfo = parse_and_validate_args(&args).unwrap();
+    let run_info: SearchRunInfo = parse_and_validate_args(&args)?;
+    let mut run_config = OclRunConfig::with_device_ids(run_info.dev_ty, &run_info.dev_ids)?;
+    if let Some(n_threads) = run_info.n_threads {
+        // index searches always run on the CPU
+        if !run_config.is_empty() && run_info.index_path.is_none() {
+            eprintln!("Warning: --threads is ignored, the search runs on OpenCL devices");
+        }
+        run_config = run_config.threads(n_threads);
+    }
+    let metadata = RunMetadata {
+        genome_path: run_info.genome_path.clone(),
+        devices: run_config.get_device_strs()?,
+        patterns: run_info.patterns.clone(),
+        labels: run_info.pattern_infos.clone(),
+        search_filters: run_info.search_filters.clone(),
+        max_mismatches: run_info.max_mismatches.clone(),
+        max_dna_bulge: run_info.max_dna_bulge,
+        max_rna_bulge: run_info.max_rna_bulge,
+    };
 
-    let (src_sender, src_receiver): (
-        mpsc::SyncSender<ChromChunkInfo>,
-        mpsc::Receiver<ChromChunkInfo>,
-    ) = mpsc::sync_channel(4);
     let (dest_sender, dest_receiver): (mpsc::SyncSender<Vec<Match>>, mpsc::Receiver<Vec<Match>>) =
         mpsc::sync_channel(4);
-    let send_thread = thread::spawn(move || {
-        let genome_path = Path::new(&run_info.genome_path);
-        let is_folder = fs::metadata(genome_path).unwrap().is_dir();
-        if is_folder {
-            read_fasta_folder(&src_sender, genome_path).unwrap();
+    // the flank lets hits that only partly overlap a region still be found
+    let max_pattern_len = run_info.patterns.iter().map(|p| p.len()).max().unwrap();
+    let flank = (max_pattern_len + run_info.max_dna_bulge as usize - 1) as u64;
+    let padded_regions = run_info.regions.as_ref().map(|r| r.with_flank(flank));
+    // excluded regions are blanked before the search and checked again on the hits
+    let excluded = Arc::new(run_info.excluded);
+    let excluded_hits = excluded.clone();
+    // chromosome lengths are taken before filtering, as SAM/BAM headers list whole chromosomes
+    let chrom_sizes: Arc<Mutex<ChromSizes>> = Arc::default();
+    let chrom_sizes_seen = chrom_sizes.clone();
+    let is_bulge_search = run_info.max_dna_bulge > 0 || run_info.max_rna_bulge > 0;
+    let n_patterns = run_info.patterns.len();
+    let guide_patterns = run_info.patterns.clone();
+    let search_filters = run_info.search_filters.clone();
+    let writer_config = HitWriterConfig {
+        format: run_info.out_format,
+        is_bulge_search,
+        track_name: run_info.track_name,
+        with_cfd: run_info.with_cfd,
+        with_annotation: run_info.annotation.is_some(),
+        chrom_sizes,
+        metadata,
+    };
+    let mut guide_summaries: Vec<GuideSummary> = run_info
+        .max_mismatches
+        .iter()
+        .map(|max_mm| GuideSummary::new(*max_mm))
+        .collect();
+    let write_thread = thread::spawn(move || -> Result<()> {
+        let mut hit_writer = if run_info.summary_only {
+            None
         } else {
-            let mut file = File::open(genome_path).unwrap();
-            let mut first_byte = [0_u8; 1];
-            file.read_exact(&mut first_byte).unwrap();
-            if first_byte[0] == b'>' {
-                read_fasta(&src_sender, genome_path).unwrap();
+            let out_writer = if run_info.out_path != "-" {
+                Box::new(File::create(&run_info.out_path)?) as Box<dyn Write>
             } else {
-                read_2bit(&src_sender, genome_path).unwrap();
-            }
-        }
-    });
-    let result_count = thread::spawn(move || {
-        let out_writer = if run_info.out_path != "-" {
-            Box::new(File::create(run_info.out_path).unwrap()) as Box<dyn Write>
-        } else {
-            Box::new(std::io::stdout()) as Box<dyn Write>
+                Box::new(std::io::stdout()) as Box<dyn Write>
+            };
+            Some(create_hit_writer(
+                writer_config,
+                Box::new(BufWriter::new(out_writer)),
+            )?)
         };
-        let mut out_buf_writer = BufWriter::new(out_writer);
-        let mut search_filter_buf = vec![0_u8; cdiv(run_info.pattern_len, 2)];
-        string_to_bit4(&mut search_filter_buf, &run_info.search_filter, 0, true);
-        let mut dna_buf = vec![0_u8; cdiv(run_info.pattern_len, 2)];
-        let mut marked_dna_buf: Vec<u8> = vec![0_u8; run_info.pattern_len];
+        if let Some(hit_writer) = hit_writer.as_mut() {
+            hit_writer.write_header()?;
+        }
         for chunk in dest_receiver.iter() {
-            for m in chunk {
-                dna_buf.fill(0);
-                string_to_bit4(&mut dna_buf, &m.dna_seq, 0, false);
-                let n_search_matches: u32 = dna_buf
-                    .iter()
-                    .zip(search_filter_buf.iter())
-                    .map(|(x1, x2)| (*x1 & *x2).count_ones())
-                    .sum();
-                if n_search_matches as usize == run_info.pattern_len {
-                    let dir = if m.is_forward { '+' } else { '-' };
-                    marked_dna_buf.clone_from_slice(&m.dna_seq);
-                    for (dnac, rnac) in marked_dna_buf.iter_mut().zip(m.rna_seq.iter()) {
-                        if !cmp_chars(*dnac, *rnac) {
-                            *dnac |= !0xdf;
-                        }
+            for mut m in chunk {
+                if run_info.skip_repeats && m.is_in_repeat() {
+                    continue;
+                }
+                if let (Some(min_cfd), Some(score)) = (run_info.min_cfd, m.cfd_score) {
+                    if score < min_cfd {
+                        continue;
+                    }
+                }
+                // hits lying only in the flank were searched but are not wanted
+                if let Some(regions) = &run_info.regions {
+                    if !regions.overlaps_match(&m) {
+                        continue;
                     }
-                    let rna_str = std::str::from_utf8(&m.rna_seq).unwrap();
-                    let dna_str = std::str::from_utf8(&marked_dna_buf).unwrap();
-                    write!(
-                        out_buf_writer,
-                        "{}\t{}\t{}\t{}\t{}\t{}\r\n",
-                        rna_str, m.chr_name, m.chrom_idx, dna_str, dir, m.mismatches
-                    )
-                    .unwrap();
                 }
+                // blanking does not stop hits of filters with N at the excluded bases
+                if let Some(excluded) = excluded_hits.as_ref() {
+                    if excluded.overlaps_match(&m) {
+                        continue;
+                    }
+                }
+                let guide_idx = m.pattern_idx as usize % n_patterns;
+                if let Some(annotation) = &run_info.annotation {
+                    let end = m.chrom_idx + m.genome_len();
+                    m.annotation = Some(annotation.annotate(&m.chr_name, m.chrom_idx, end));
+                }
+                if let Some(hit_writer) = hit_writer.as_mut() {
+                    let label = &run_info.pattern_infos[guide_idx];
+                    hit_writer.write_hit(&m, label)?;
+                }
+                guide_summaries[guide_idx].add(&m);
             }
         }
+        if let Some(hit_writer) = hit_writer.as_mut() {
+            hit_writer.finish()?;
+        }
+        if let Some(summary_path) = &run_info.summary_path {
+            write_guide_summary(
+                summary_path,
+                &run_info.pattern_infos,
+                &guide_patterns,
+                &guide_summaries,
+            )?;
+        }
+        Ok(())
     });
 
-    let run_config = match OclRunConfig::new(run_info.dev_ty) {
-        Err(err) => panic!("OpenCL runtime errored on load with error: {}", err),
-        Ok(cfg) => cfg,
-    };
-    let reversed_byte_patterns: Vec<Vec<u8>> = run_info
-        .patterns
-        .iter()
-        .map(|v| reverse_compliment_char(v))
-        .collect();
-    let mut all_patterns: Vec<Vec<u8>> = run_info.patterns.clone();
-    all_patterns.extend_from_slice(&reversed_byte_patterns);
+    if let Some(index_path) = &run_info.index_path {
+        let search_res = search_index(
+            Path::new(index_path),
+            &run_info.patterns,
+            &run_info.max_mismatches,
+            &search_filters,
+            &run_info.chrom_filter,
+            &chrom_sizes_seen,
+            run_config.n_search_threads(INDEX_RESERVED_THREADS),
+            dest_sender,
+        );
+        first_error([search_res, join_worker(write_thread)])?;
+        eprintln!("Completed in {}s", start_time.elapsed().as_secs_f64());
+        return Ok(());
+    }
+    let (src_sender, src_receiver): (
+        mpsc::SyncSender<ChromChunkInfo>,
+        mpsc::Receiver<ChromChunkInfo>,
+    ) = mpsc::sync_channel(4);
+    let (genome_sender, genome_receiver): (
+        mpsc::SyncSender<ChromChunkInfo>,
+        mpsc::Receiver<ChromChunkInfo>,
+    ) = mpsc::sync_channel(4);
+    let send_thread = thread::spawn(move || {
+        read_genome(
+            &genome_sender,
+            Path::new(&run_info.genome_path),
+            &run_info.chrom_filter,
+        )
+    });
+    let filter_thread = thread::spawn(move || {
+        filter_chunks(genome_receiver, &src_sender, |chunk| {
+            chrom_sizes_seen.lock().unwrap().record(&chunk);
+            let chunk = match &padded_regions {
+                Some(r) => restrict_to_regions(chunk, r)?,
+                None => chunk,
+            };
+            match excluded.as_ref() {
+                Some(e) => exclude_regions(chunk, e),
+                None => Some(chunk),
+            }
+        })
+    });
 
-    let all_patterns_4bit: Vec<Vec<u8>> = all_patterns
+    // reverse patterns share the threshold of their forward pattern
+    let all_mismatches: Vec<u32> = run_info
+        .max_mismatches
         .iter()
-        .map(|pat| {
-            let mut buf = vec![0_u8; cdiv(pat.len(), 2)];
-            string_to_bit4(&mut buf, pat, 0, true);
-            buf
-        })
+        .chain(run_info.max_mismatches.iter())
+        .copied()
         .collect();
+    let (all_pattern_lens, all_patterns_4bit) = encode_patterns(&run_info.patterns);
+    let (_, all_filters_4bit) = encode_patterns(&search_filters);
 
-    search(
+    let search_res = search(
         run_config,
-        run_info.max_mismatches,
-        run_info.pattern_len,
+        &all_mismatches,
+        run_info.max_dna_bulge,
+        run_info.max_rna_bulge,
+        &all_pattern_lens,
         &all_patterns_4bit,
+        &all_filters_4bit,
         src_receiver,
         dest_sender,
     );
-    send_thread.join().unwrap();
-    result_count.join().unwrap();
+    // a failing thread makes its neighbours fail with send errors, the root cause is reported
+    first_error([
+        join_worker(send_thread),
+        join_worker(filter_thread),
+        search_res,
+        join_worker(write_thread),
+    ])?;
     let tot_time = start_time.elapsed();
     eprintln!("Completed in {}s", tot_time.as_secs_f64());
-    // assert_eq!(result_count.join().unwrap(), expected_results);
+    Ok(())
+}
+
+// looks the patterns up in a genome index, giving the hits a scan without bulges gives
+#[allow(clippy::too_many_arguments)]
+fn search_index(
+    index_path: &Path,
+    patterns: &[Vec<u8>],
+    max_mismatches: &[u32],
+    search_filters: &[Vec<u8>],
+    chrom_filter: &ChromFilter,
+    chrom_sizes: &Mutex<ChromSizes>,
+    n_threads: usize,
+    dest: mpsc::SyncSender<Vec<Match>>,
+) -> Result<()> {
+    let index = GenomeIndex::read(index_path)?;
+    if search_filters.iter().any(|f| f != index.search_filter()) {
+        return Err(CliError::ArgumentError(
+            "The search filter must be the one the index was built with",
+        ));
+    }
+    for (chr_name, len) in index.chroms() {
+        if chrom_filter.is_kept(chr_name) {
+            chrom_sizes.lock().unwrap().record_len(chr_name, len);
+        }
+    }
+    index.search(patterns, max_mismatches, chrom_filter, n_threads, &dest)
+}
+fn run_index(args: &[String]) -> Result<()> {
+    let start_time = Instant::now();
+    let run_info: IndexRunInfo = parse_index_args(args)?;
+    let index = GenomeIndex::build(
+        Path::new(&run_info.genome_path),
+        &run_info.search_filter,
+        run_info.max_mismatches,
+    )?;
+    index.write(Path::new(&run_info.index_path))?;
+    eprintln!(
+        "Indexed {} sites in {}s",
+        index.n_sites(),
+        start_time.elapsed().as_secs_f64()
+    );
+    Ok(())
 }
@@ -0,0 +1,535 @@
+use crate::sam::{ChromSizes, SamWriter};
+use cas_offinder_lib::*;
+use std::io::{Result, Write};
+use std::sync::{Arc, Mutex};
+
+/*
+Writers for the supported output formats. Every hit that passes the filters in
+main.rs is handed to a HitWriter together with the label of its guide.
+*/
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum OutputFormat {
+    // cas-offinder 2 (or 3, when searching for bulges) tab separated output
+    Tsv,
+    // BED9 plus the aligned sequences, mismatches and bulge info
+    Bed,
+    // guide alignments against the genome, for viewing in IGV or processing with samtools
+    Sam,
+    Bam,
+    // one JSON object per line, starting with the run parameters
+    Jsonl,
+}
+
+impl OutputFormat {
+    pub fn parse(name: &str) -> Option<OutputFormat> {
+        match name {
+            "tsv" => Some(OutputFormat::Tsv),
+            "bed" => Some(OutputFormat::Bed),
+            "sam" => Some(OutputFormat::Sam),
+            "bam" => Some(OutputFormat::Bam),
+            "jsonl" => Some(OutputFormat::Jsonl),
+            _ => None,
+        }
+    }
+}
+
+pub struct HitWriterConfig {
+    pub format: OutputFormat,
+    pub is_bulge_search: bool,
+    // write a UCSC track line in front of BED output
+    pub track_name: Option<String>,
+    // add a CFD column to the tab separated output
+    pub with_cfd: bool,
+    // hits carry gene annotations, adds their columns to the tab separated header
+    pub with_annotation: bool,
+    // lengths of the chromosomes searched, for the SAM/BAM header
+    pub chrom_sizes: Arc<Mutex<ChromSizes>>,
+    pub metadata: RunMetadata,
+}
+
+// parameters of the run, written in front of JSON Lines output
+#[derive(Default)]
+pub struct RunMetadata {
+    pub genome_path: String,
+    pub devices: Vec<String>,
+    // one entry per guide, in input file order
+    pub patterns: Vec<Vec<u8>>,
+    pub labels: Vec<String>,
+    pub search_filters: Vec<Vec<u8>>,
+    pub max_mismatches: Vec<u32>,
+    pub max_dna_bulge: u32,
+    pub max_rna_bulge: u32,
+}
+
+pub trait HitWriter {
+    fn write_header(&mut self) -> Result<()>;
+    fn write_hit(&mut self, m: &Match, label: &str) -> Result<()>;
+    fn finish(&mut self) -> Result<()>;
+}
+
+pub fn create_hit_writer(
+    config: HitWriterConfig,
+    out: Box<dyn Write>,
+) -> Result<Box<dyn HitWriter>> {
+    Ok(match config.format {
+        OutputFormat::Tsv => Box::new(TsvWriter {
+            out,
+            is_bulge_search: config.is_bulge_search,
+            with_cfd: config.with_cfd,
+            with_annotation: config.with_annotation,
+        }),
+        OutputFormat::Bed => Box::new(BedWriter {
+            out,
+            track_name: config.track_name,
+        }),
+        OutputFormat::Sam => Box::new(SamWriter::new(out, false, config.chrom_sizes)?),
+        OutputFormat::Bam => Box::new(SamWriter::new(out, true, config.chrom_sizes)?),
+        OutputFormat::Jsonl => Box::new(JsonlWriter {
+            out,
+            metadata: config.metadata,
+        }),
+    })
+}
+
+// the dna alignment with mismatched bases in lowercase
+pub fn mark_mismatches(m: &Match) -> Vec<u8> {
+    let mut marked_dna = m.dna_seq.clone();
+    for (dnac, rnac) in marked_dna.iter_mut().zip(m.rna_seq.iter()) {
+        if *rnac != b'-' && !cmp_chars(*dnac, *rnac) {
+            *dnac |= !0xdf;
+        }
+    }
+    marked_dna
+}
+
+// CFD score column, . when CFD does not apply to the hit
+fn cfd_str(m: &Match) -> String {
+    match m.cfd_score {
+        Some(score) => format!("{:.6}", score),
+        None => ".".to_string(),
+    }
+}
+
+// feature class, gene names, gene ids and distance to the nearest gene, empty without annotation
+fn annotation_cols(m: &Match) -> String {
+    let Some(annotation) = &m.annotation else {
+        return String::new();
+    };
+    let join_or_dot = |items: &[String]| {
+        if items.is_empty() {
+            ".".to_string()
+        } else {
+            items.join(",")
+        }
+    };
+    format!(
+        "\t{}\t{}\t{}\t{}",
+        annotation.class.as_str(),
+        join_or_dot(&annotation.gene_names),
+        join_or_dot(&annotation.gene_ids),
+        annotation
+            .gene_distance
+            .map_or(".".to_string(), |d| d.to_string())
+    )
+}
+
+fn strand_char(m: &Match) -> char {
+    if m.is_forward {
+        '+'
+    } else {
+        '-'
+    }
+}
+
+struct TsvWriter {
+    out: Box<dyn Write>,
+    is_bulge_search: bool,
+    with_cfd: bool,
+    with_annotation: bool,
+}
+
+impl HitWriter for TsvWriter {
+    fn write_header(&mut self) -> Result<()> {
+        if self.is_bulge_search {
+            write!(
+                self.out,
+                "#Id\tBulge Type\tcrRNA\tDNA\tChromosome\tLocation\tDirection\tMismatches\tBulge Size{}{}\r\n",
+                if self.with_cfd { "\tCFD" } else { "" },
+                if self.with_annotation {
+                    "\tFeature\tGenes\tGene ids\tGene distance"
+                } else {
+                    ""
+                }
+            )?;
+        }
+        Ok(())
+    }
+    fn write_hit(&mut self, m: &Match, label: &str) -> Result<()> {
+        let marked_dna = mark_mismatches(m);
+        let rna_str = String::from_utf8_lossy(&m.rna_seq);
+        let dna_str = String::from_utf8_lossy(&marked_dna);
+        // extra columns are appended so that the cas-offinder columns stay in place
+        let mut extra_cols = if self.with_cfd {
+            format!("\t{}", cfd_str(m))
+        } else {
+            String::new()
+        };
+        extra_cols.push_str(&annotation_cols(m));
+        if self.is_bulge_search {
+            write!(
+                self.out,
+                "{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}{}\r\n",
+                label,
+                m.bulge_type.as_str(),
+                rna_str,
+                dna_str,
+                m.chr_name,
+                m.chrom_idx,
+                strand_char(m),
+                m.mismatches,
+                m.bulge_size,
+                extra_cols
+            )
+        } else {
+            write!(
+                self.out,
+                "{}\t{}\t{}\t{}\t{}\t{}{}\r\n",
+                rna_str,
+                m.chr_name,
+                m.chrom_idx,
+                dna_str,
+                strand_char(m),
+                m.mismatches,
+                extra_cols
+            )
+        }
+    }
+    fn finish(&mut self) -> Result<()> {
+        self.out.flush()
+    }
+}
+
+struct BedWriter {
+    out: Box<dyn Write>,
+    track_name: Option<String>,
+}
+
+// BED score: 1000 for perfect matches, less for every mismatch
+fn bed_score(mismatches: u32) -> u32 {
+    1000 / (mismatches + 1)
+}
+
+// itemRgb colors by mismatch count, from red for perfect matches to dark blue for 6 or more
+const BED_COLORS: [&str; 7] = [
+    "178,24,43",
+    "214,96,77",
+    "244,165,130",
+    "146,197,222",
+    "67,147,195",
+    "33,102,172",
+    "5,48,97",
+];
+
+fn bed_color(mismatches: u32) -> &'static str {
+    BED_COLORS[std::cmp::min(mismatches as usize, BED_COLORS.len() - 1)]
+}
+
+impl HitWriter for BedWriter {
+    fn write_header(&mut self) -> Result<()> {
+        if let Some(track_name) = &self.track_name {
+            writeln!(
+                self.out,
+                "track name=\"{}\" description=\"Cas-OFFinder off-targets, colored by mismatch count\" itemRgb=\"On\"",
+                track_name
+            )?;
+        }
+        Ok(())
+    }
+    fn write_hit(&mut self, m: &Match, label: &str) -> Result<()> {
+        // BED names can not contain whitespace, fasta names keep only their first word
+        let chrom = m.chr_name.split_ascii_whitespace().next().unwrap_or("");
+        let name: String = label
+            .chars()
+            .map(|c| if c.is_whitespace() { '_' } else { c })
+            .collect();
+        writeln!(
+            self.out,
+            "{}\t{}\t{}\t{}_{}mm\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}{}",
+            chrom,
+            m.chrom_idx,
+            m.chrom_idx + m.genome_len(),
+            name,
+            m.mismatches,
+            bed_score(m.mismatches),
+            strand_char(m),
+            // thickStart and thickEnd cover the whole hit
+            m.chrom_idx,
+            m.chrom_idx + m.genome_len(),
+            bed_color(m.mismatches),
+            String::from_utf8_lossy(&m.rna_seq),
+            String::from_utf8_lossy(&mark_mismatches(m)),
+            m.mismatches,
+            m.bulge_type.as_str(),
+            m.bulge_size,
+            cfd_str(m),
+            annotation_cols(m)
+        )
+    }
+    fn finish(&mut self) -> Result<()> {
+        self.out.flush()
+    }
+}
+
+// JSON string literal, escaping quotes, backslashes and control characters
+fn json_str(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len() + 2);
+    escaped.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            '\n' => escaped.push_str("\\n"),
+            '\r' => escaped.push_str("\\r"),
+            '\t' => escaped.push_str("\\t"),
+            c if (c as u32) < 0x20 => escaped.push_str(&format!("\\u{:04x}", c as u32)),
+            c => escaped.push(c),
+        }
+    }
+    escaped.push('"');
+    escaped
+}
+
+fn json_list<T, F: Fn(&T) -> String>(items: &[T], to_json: F) -> String {
+    let items: Vec<String> = items.iter().map(to_json).collect();
+    format!("[{}]", items.join(","))
+}
+
+// positions in the aligned guide whose base does not match the genome
+fn mismatch_positions(m: &Match) -> Vec<usize> {
+    m.dna_seq
+        .iter()
+        .zip(m.rna_seq.iter())
+        .enumerate()
+        .filter(|(_, (dnac, rnac))| **dnac != b'-' && **rnac != b'-' && !cmp_chars(**dnac, **rnac))
+        .map(|(idx, _)| idx)
+        .collect()
+}
+
+// ,"annotation":{...} field of annotated hits
+fn json_annotation(m: &Match) -> String {
+    let Some(annotation) = &m.annotation else {
+        return String::new();
+    };
+    format!(
+        ",\"annotation\":{{\"feature\":{},\"gene_names\":{},\"gene_ids\":{},\"gene_distance\":{}}}",
+        json_str(annotation.class.as_str()),
+        json_list(&annotation.gene_names, |n| json_str(n)),
+        json_list(&annotation.gene_ids, |id| json_str(id)),
+        annotation
+            .gene_distance
+            .map_or("null".to_string(), |d| d.to_string())
+    )
+}
+
+struct JsonlWriter {
+    out: Box<dyn Write>,
+    metadata: RunMetadata,
+}
+
+impl HitWriter for JsonlWriter {
+    fn write_header(&mut self) -> Result<()> {
+        let meta = &self.metadata;
+        let guides: Vec<String> = (0..meta.patterns.len())
+            .map(|idx| {
+                format!(
+                    "{{\"index\":{},\"label\":{},\"sequence\":{},\"search_filter\":{},\"max_mismatches\":{}}}",
+                    idx,
+                    json_str(&meta.labels[idx]),
+                    json_str(&String::from_utf8_lossy(&meta.patterns[idx])),
+                    json_str(&String::from_utf8_lossy(&meta.search_filters[idx])),
+                    meta.max_mismatches[idx]
+                )
+            })
+            .collect();
+        writeln!(
+            self.out,
+            "{{\"type\":\"run\",\"version\":{},\"genome\":{},\"devices\":{},\"max_dna_bulge\":{},\"max_rna_bulge\":{},\"guides\":[{}]}}",
+            json_str(env!("CARGO_PKG_VERSION")),
+            json_str(&meta.genome_path),
+            json_list(&meta.devices, |d| json_str(d)),
+            meta.max_dna_bulge,
+            meta.max_rna_bulge,
+            guides.join(",")
+        )
+    }
+    fn write_hit(&mut self, m: &Match, label: &str) -> Result<()> {
+        let chrom = m.chr_name.split_ascii_whitespace().next().unwrap_or("");
+        writeln!(
+            self.out,
+            "{{\"type\":\"hit\",\"label\":{},\"guide_index\":{},\"chrom\":{},\"pos\":{},\"end\":{},\"strand\":\"{}\",\"guide\":{},\"dna\":{},\"mismatches\":{},\"mismatch_positions\":{},\"bulge_type\":\"{}\",\"bulge_size\":{},\"cfd\":{}{}}}",
+            json_str(label),
+            m.pattern_idx as usize % self.metadata.patterns.len(),
+            json_str(chrom),
+            m.chrom_idx,
+            m.chrom_idx + m.genome_len(),
+            strand_char(m),
+            json_str(&String::from_utf8_lossy(&m.rna_seq)),
+            json_str(&String::from_utf8_lossy(&m.dna_seq)),
+            m.mismatches,
+            json_list(&mismatch_positions(m), |idx| idx.to_string()),
+            m.bulge_type.as_str(),
+            m.bulge_size,
+            m.cfd_score.map_or("null".to_string(), |s| format!("{:.6}", s)),
+            json_annotation(m)
+        )
+    }
+    fn finish(&mut self) -> Result<()> {
+        self.out.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    // lets the test read back what a boxed writer wrote
+    #[derive(Clone, Default)]
+    struct SharedBuf(Rc<RefCell<Vec<u8>>>);
+    impl Write for SharedBuf {
+        fn write(&mut self, buf: &[u8]) -> Result<usize> {
+            self.0.borrow_mut().write(buf)
+        }
+        fn flush(&mut self) -> Result<()> {
+            Ok(())
+        }
+    }
+
+    // a reverse strand hit with one mismatch, on a chromosome whose fasta name has a description
+    fn reverse_hit() -> Match {
+        Match {
+            chr_name: "chr1 description".to_string(),
+            chrom_idx: 100,
+            is_forward: false,
+            ..test_match(b"ACGTAGG", b"ACCTNRG")
+        }
+    }
+
+    #[test]
+    fn test_bed_output() {
+        let buf = SharedBuf::default();
+        let config = HitWriterConfig {
+            format: OutputFormat::Bed,
+            is_bulge_search: false,
+            track_name: Some("offtargets".to_string()),
+            with_cfd: false,
+            with_annotation: false,
+            chrom_sizes: Arc::default(),
+            metadata: RunMetadata::default(),
+        };
+        let mut writer = create_hit_writer(config, Box::new(buf.clone())).unwrap();
+        writer.write_header().unwrap();
+        writer.write_hit(&reverse_hit(), "guide 1").unwrap();
+        writer.finish().unwrap();
+        let written = String::from_utf8(buf.0.borrow().clone()).unwrap();
+        let lines: Vec<&str> = written.lines().collect();
+        assert!(lines[0].starts_with("track name=\"offtargets\""));
+        assert!(lines[0].ends_with("itemRgb=\"On\""));
+        assert_eq!(
+            lines[1],
+            "chr1\t100\t107\tguide_1_1mm\t500\t-\t100\t107\t214,96,77\tACCTNRG\tACgTAGG\t1\tX\t0\t."
+        );
+        assert_eq!(bed_color(0), "178,24,43");
+        assert_eq!(bed_color(9), "5,48,97");
+    }
+    #[test]
+    fn test_annotation_columns() {
+        let mut m = reverse_hit();
+        assert_eq!(annotation_cols(&m), "");
+        m.annotation = Some(HitAnnotation {
+            class: FeatureClass::Intron,
+            gene_ids: vec!["G1".to_string(), "G2".to_string()],
+            gene_names: vec!["ABC1".to_string(), "G2".to_string()],
+            gene_distance: Some(0),
+        });
+        assert_eq!(annotation_cols(&m), "\tintron\tABC1,G2\tG1,G2\t0");
+        assert_eq!(
+            json_annotation(&m),
+            ",\"annotation\":{\"feature\":\"intron\",\"gene_names\":[\"ABC1\",\"G2\"],\"gene_ids\":[\"G1\",\"G2\"],\"gene_distance\":0}"
+        );
+        m.annotation = Some(HitAnnotation {
+            class: FeatureClass::Intergenic,
+            gene_ids: Vec::new(),
+            gene_names: Vec::new(),
+            gene_distance: None,
+        });
+        assert_eq!(annotation_cols(&m), "\tintergenic\t.\t.\t.");
+    }
+    #[test]
+    fn test_tsv_cfd_column() {
+        let buf = SharedBuf::default();
+        let config = HitWriterConfig {
+            format: OutputFormat::Tsv,
+            is_bulge_search: false,
+            track_name: None,
+            with_cfd: true,
+            with_annotation: false,
+            chrom_sizes: Arc::default(),
+            metadata: RunMetadata::default(),
+        };
+        let mut writer = create_hit_writer(config, Box::new(buf.clone())).unwrap();
+        writer.write_header().unwrap();
+        let mut m = reverse_hit();
+        m.cfd_score = Some(0.25);
+        writer.write_hit(&m, "guide 1").unwrap();
+        writer.write_hit(&reverse_hit(), "guide 1").unwrap();
+        writer.finish().unwrap();
+        let written = String::from_utf8(buf.0.borrow().clone()).unwrap();
+        assert_eq!(
+            written,
+            "ACCTNRG\tchr1 description\t100\tACgTAGG\t-\t1\t0.250000\r\n\
+             ACCTNRG\tchr1 description\t100\tACgTAGG\t-\t1\t.\r\n"
+        );
+    }
+    #[test]
+    fn test_jsonl_output() {
+        let buf = SharedBuf::default();
+        let config = HitWriterConfig {
+            format: OutputFormat::Jsonl,
+            is_bulge_search: false,
+            track_name: None,
+            with_cfd: false,
+            with_annotation: false,
+            chrom_sizes: Arc::default(),
+            metadata: RunMetadata {
+                genome_path: "/genomes/\"hg38\".2bit".to_string(),
+                devices: vec!["CPU".to_string()],
+                patterns: vec![b"ACCTNNN".to_vec()],
+                labels: vec!["guide\t1".to_string()],
+                search_filters: vec![b"NNNNNRG".to_vec()],
+                max_mismatches: vec![2],
+                max_dna_bulge: 0,
+                max_rna_bulge: 0,
+            },
+        };
+        let mut writer = create_hit_writer(config, Box::new(buf.clone())).unwrap();
+        writer.write_header().unwrap();
+        writer.write_hit(&reverse_hit(), "guide\t1").unwrap();
+        writer.finish().unwrap();
+        let written = String::from_utf8(buf.0.borrow().clone()).unwrap();
+        let lines: Vec<&str> = written.lines().collect();
+        assert_eq!(
+            lines[0],
+            format!(
+                "{{\"type\":\"run\",\"version\":\"{}\",\"genome\":\"/genomes/\\\"hg38\\\".2bit\",\"devices\":[\"CPU\"],\"max_dna_bulge\":0,\"max_rna_bulge\":0,\"guides\":[{{\"index\":0,\"label\":\"guide\\t1\",\"sequence\":\"ACCTNNN\",\"search_filter\":\"NNNNNRG\",\"max_mismatches\":2}}]}}",
+                env!("CARGO_PKG_VERSION")
+            )
+        );
+        assert_eq!(
+            lines[1],
+            "{\"type\":\"hit\",\"label\":\"guide\\t1\",\"guide_index\":0,\"chrom\":\"chr1\",\"pos\":100,\"end\":107,\"strand\":\"-\",\"guide\":\"ACCTNRG\",\"dna\":\"ACGTAGG\",\"mismatches\":1,\"mismatch_positions\":[2],\"bulge_type\":\"X\",\"bulge_size\":0,\"cfd\":null}"
+        );
+    }
+}
@@ -0,0 +1,513 @@
+use crate::output::HitWriter;
+use cas_offinder_lib::*;
+use flate2::write::DeflateEncoder;
+use flate2::{Compression, Crc};
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Result, Seek, SeekFrom, Write};
+use std::sync::{Arc, Mutex};
+
+/*
+SAM/BAM output, with the guide as query sequence aligned against the genome.
+The @SQ header needs the length of every chromosome, which is only known once the
+whole genome has been read. Records are therefore spooled to an anonymous temporary
+file, which the system removes once it is closed, and written out behind the header
+when the search is done.
+*/
+
+// names and lengths of the chromosomes read so far, filled in while chunks stream by
+#[derive(Default)]
+pub struct ChromSizes {
+    names: Vec<String>,
+    lens: Vec<u64>,
+    idxs: HashMap<String, usize>,
+}
+
+// SAM reference names can not contain whitespace, fasta names keep only their first word
+fn ref_name(chr_name: &str) -> &str {
+    chr_name.split_ascii_whitespace().next().unwrap_or("")
+}
+
+impl ChromSizes {
+    pub fn record(&mut self, chunk: &ChromChunkInfo) {
+        self.record_len(&chunk.chr_name, chunk.chunk_end);
+    }
+    // the chromosome is at least len long
+    pub fn record_len(&mut self, chr_name: &str, len: u64) {
+        let name = ref_name(chr_name);
+        let idx = match self.idxs.get(name) {
+            Some(idx) => *idx,
+            None => {
+                self.idxs.insert(name.to_string(), self.names.len());
+                self.names.push(name.to_string());
+                self.lens.push(0);
+                self.names.len() - 1
+            }
+        };
+        self.lens[idx] = self.lens[idx].max(len);
+    }
+    fn ref_idx(&self, chr_name: &str) -> i32 {
+        self.idxs
+            .get(ref_name(chr_name))
+            .map_or(-1, |idx| *idx as i32)
+    }
+}
+
+const CIGAR_OPS: &[u8; 9] = b"MIDNSHP=X";
+
+struct SamAlignment {
+    // guide in genome orientation, without gaps
+    seq: Vec<u8>,
+    cigar: Vec<(u32, u8)>,
+    md: String,
+    nm: u32,
+}
+
+fn push_cigar(cigar: &mut Vec<(u32, u8)>, op: u8) {
+    match cigar.last_mut() {
+        Some((len, last_op)) if *last_op == op => *len += 1,
+        _ => cigar.push((1, op)),
+    }
+}
+
+fn sam_alignment(m: &Match) -> SamAlignment {
+    // SAM describes the alignment on the forward strand of the genome
+    let (dna, rna) = if m.is_forward {
+        (m.dna_seq.clone(), m.rna_seq.clone())
+    } else {
+        (
+            reverse_compliment_char(&m.dna_seq),
+            reverse_compliment_char(&m.rna_seq),
+        )
+    };
+    let mut seq: Vec<u8> = Vec::with_capacity(rna.len());
+    let mut cigar: Vec<(u32, u8)> = Vec::new();
+    let mut md = String::new();
+    let mut nm = 0;
+    let mut md_matches = 0;
+    let mut in_deletion = false;
+    for (dnac, rnac) in dna.iter().zip(rna.iter()) {
+        let dna_base = dnac.to_ascii_uppercase();
+        if *rnac == b'-' {
+            // genome base without a guide base: DNA bulge
+            push_cigar(&mut cigar, b'D');
+            if !in_deletion {
+                md.push_str(&format!("{}^", md_matches));
+                md_matches = 0;
+            }
+            md.push(dna_base as char);
+            in_deletion = true;
+            nm += 1;
+            continue;
+        }
+        in_deletion = false;
+        seq.push(*rnac);
+        if *dnac == b'-' {
+            // guide base without a genome base: RNA bulge
+            push_cigar(&mut cigar, b'I');
+            nm += 1;
+        } else if cmp_chars(*dnac, *rnac) {
+            push_cigar(&mut cigar, b'M');
+            md_matches += 1;
+        } else {
+            push_cigar(&mut cigar, b'M');
+            md.push_str(&format!("{}{}", md_matches, dna_base as char));
+            md_matches = 0;
+            nm += 1;
+        }
+    }
+    md.push_str(&md_matches.to_string());
+    SamAlignment { seq, cigar, md, nm }
+}
+
+enum TagValue {
+    Str(String),
+    Int(i32),
+}
+
+// gene annotation as the custom tags XF (feature class), XG (gene names), XI (gene ids) and
+// XD (distance to the nearest gene), tags without a value are left out
+fn annotation_tags(m: &Match) -> Vec<(&'static str, TagValue)> {
+    let mut tags: Vec<(&'static str, TagValue)> = Vec::new();
+    let Some(annotation) = &m.annotation else {
+        return tags;
+    };
+    tags.push(("XF", TagValue::Str(annotation.class.as_str().to_string())));
+    if !annotation.gene_ids.is_empty() {
+        tags.push(("XG", TagValue::Str(annotation.gene_names.join(","))));
+        tags.push(("XI", TagValue::Str(annotation.gene_ids.join(","))));
+    }
+    if let Some(distance) = annotation.gene_distance {
+        tags.push(("XD", TagValue::Int(distance.min(i32::MAX as u64) as i32)));
+    }
+    tags
+}
+
+fn query_name(label: &str) -> String {
+    label
+        .chars()
+        .map(|c| {
+            if c.is_whitespace() || c == '@' {
+                '_'
+            } else {
+                c
+            }
+        })
+        .collect()
+}
+
+// bin of a 0-based, half open interval, as given in the SAM specification
+fn reg2bin(beg: i64, end: i64) -> u16 {
+    let end = end - 1;
+    // first bin of each level, from 16KB bins up to 64MB bins
+    for (shift, level_offset) in [(14, 4681), (17, 585), (20, 73), (23, 9), (26, 1)] {
+        if beg >> shift == end >> shift {
+            return (level_offset + (beg >> shift)) as u16;
+        }
+    }
+    0
+}
+
+fn bam_base(c: u8) -> u8 {
+    b"=ACMGRSVTWYHKDBN"
+        .iter()
+        .position(|b| *b == c.to_ascii_uppercase())
+        .unwrap_or(15) as u8
+}
+
+// writes the BGZF flavour of gzip used by BAM: independent gzip members of at most 64KB
+struct BgzfWriter<W: Write> {
+    out: W,
+    buf: Vec<u8>,
+}
+
+const BGZF_BLOCK_SIZE: usize = 0xff00;
+
+impl<W: Write> BgzfWriter<W> {
+    fn new(out: W) -> BgzfWriter<W> {
+        BgzfWriter {
+            out,
+            buf: Vec::with_capacity(BGZF_BLOCK_SIZE),
+        }
+    }
+    fn write_block(&mut self, data: &[u8]) -> Result<()> {
+        let mut encoder = DeflateEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(data)?;
+        let compressed = encoder.finish()?;
+        let mut crc = Crc::new();
+        crc.update(data);
+        // total block size - 1, header (18) + data + footer (8)
+        let bsize = (compressed.len() + 25) as u16;
+        self.out.write_all(&[
+            0x1f, 0x8b, 8, 4, 0, 0, 0, 0, 0, 0xff, 6, 0, b'B', b'C', 2, 0,
+        ])?;
+        self.out.write_all(&bsize.to_le_bytes())?;
+        self.out.write_all(&compressed)?;
+        self.out.write_all(&crc.sum().to_le_bytes())?;
+        self.out.write_all(&(data.len() as u32).to_le_bytes())?;
+        Ok(())
+    }
+    fn finish(&mut self) -> Result<()> {
+        self.flush()?;
+        // empty block marks the end of the file
+        self.write_block(&[])?;
+        self.out.flush()
+    }
+}
+
+impl<W: Write> Write for BgzfWriter<W> {
+    fn write(&mut self, data: &[u8]) -> Result<usize> {
+        let n_taken = data.len().min(BGZF_BLOCK_SIZE - self.buf.len());
+        self.buf.extend_from_slice(&data[..n_taken]);
+        if self.buf.len() == BGZF_BLOCK_SIZE {
+            self.flush()?;
+        }
+        Ok(n_taken)
+    }
+    fn flush(&mut self) -> Result<()> {
+        if !self.buf.is_empty() {
+            let block = std::mem::take(&mut self.buf);
+            self.write_block(&block)?;
+            self.buf = block;
+            self.buf.clear();
+        }
+        Ok(())
+    }
+}
+
+pub struct SamWriter {
+    out: Option<Box<dyn Write>>,
+    is_bam: bool,
+    chrom_sizes: Arc<Mutex<ChromSizes>>,
+    spool: BufWriter<File>,
+}
+
+impl SamWriter {
+    pub fn new(
+        out: Box<dyn Write>,
+        is_bam: bool,
+        chrom_sizes: Arc<Mutex<ChromSizes>>,
+    ) -> Result<SamWriter> {
+        // a uniquely named file, unlinked right away where the system allows it
+        let spool = BufWriter::new(tempfile::tempfile()?);
+        Ok(SamWriter {
+            out: Some(out),
+            is_bam,
+            chrom_sizes,
+            spool,
+        })
+    }
+    fn header_text(&self) -> String {
+        let chrom_sizes = self.chrom_sizes.lock().unwrap();
+        let mut text = String::from("@HD\tVN:1.6\tSO:unsorted\n");
+        for (name, len) in chrom_sizes.names.iter().zip(chrom_sizes.lens.iter()) {
+            text.push_str(&format!("@SQ\tSN:{}\tLN:{}\n", name, len));
+        }
+        text.push_str(&format!(
+            "@PG\tID:cas-offinder\tPN:cas-offinder\tVN:{}\n",
+            env!("CARGO_PKG_VERSION")
+        ));
+        text
+    }
+    fn write_bam_header(&self, out: &mut dyn Write) -> Result<()> {
+        let text = self.header_text();
+        let chrom_sizes = self.chrom_sizes.lock().unwrap();
+        out.write_all(b"BAM\x01")?;
+        out.write_all(&(text.len() as i32).to_le_bytes())?;
+        out.write_all(text.as_bytes())?;
+        out.write_all(&(chrom_sizes.names.len() as i32).to_le_bytes())?;
+        for (name, len) in chrom_sizes.names.iter().zip(chrom_sizes.lens.iter()) {
+            out.write_all(&(name.len() as i32 + 1).to_le_bytes())?;
+            out.write_all(name.as_bytes())?;
+            out.write_all(&[0])?;
+            out.write_all(&(*len as i32).to_le_bytes())?;
+        }
+        Ok(())
+    }
+    fn write_bam_record(&mut self, m: &Match, label: &str, aln: &SamAlignment) -> Result<()> {
+        let ref_idx = self.chrom_sizes.lock().unwrap().ref_idx(&m.chr_name);
+        let read_name = query_name(label);
+        let pos = m.chrom_idx as i64;
+        let mut rec: Vec<u8> = Vec::new();
+        rec.extend_from_slice(&ref_idx.to_le_bytes());
+        rec.extend_from_slice(&(pos as i32).to_le_bytes());
+        rec.push(read_name.len() as u8 + 1);
+        rec.push(255); // mapq
+        rec.extend_from_slice(&reg2bin(pos, pos + m.genome_len() as i64).to_le_bytes());
+        rec.extend_from_slice(&(aln.cigar.len() as u16).to_le_bytes());
+        let flag: u16 = if m.is_forward { 0 } else { 16 };
+        rec.extend_from_slice(&flag.to_le_bytes());
+        rec.extend_from_slice(&(aln.seq.len() as i32).to_le_bytes());
+        rec.extend_from_slice(&(-1_i32).to_le_bytes()); // next ref
+        rec.extend_from_slice(&(-1_i32).to_le_bytes()); // next pos
+        rec.extend_from_slice(&0_i32.to_le_bytes()); // template length
+        rec.extend_from_slice(read_name.as_bytes());
+        rec.push(0);
+        for (len, op) in aln.cigar.iter() {
+            let op_idx = CIGAR_OPS.iter().position(|c| c == op).unwrap() as u32;
+            rec.extend_from_slice(&(len << 4 | op_idx).to_le_bytes());
+        }
+        for pair in aln.seq.chunks(2) {
+            let low = pair.get(1).map_or(0, |c| bam_base(*c));
+            rec.push(bam_base(pair[0]) << 4 | low);
+        }
+        rec.extend(std::iter::repeat_n(0xff_u8, aln.seq.len())); // no qualities
+        rec.extend_from_slice(b"NMi");
+        rec.extend_from_slice(&(aln.nm as i32).to_le_bytes());
+        rec.extend_from_slice(b"MDZ");
+        rec.extend_from_slice(aln.md.as_bytes());
+        rec.push(0);
+        for (tag, value) in annotation_tags(m) {
+            rec.extend_from_slice(tag.as_bytes());
+            match value {
+                TagValue::Str(text) => {
+                    rec.push(b'Z');
+                    rec.extend_from_slice(text.as_bytes());
+                    rec.push(0);
+                }
+                TagValue::Int(num) => {
+                    rec.push(b'i');
+                    rec.extend_from_slice(&num.to_le_bytes());
+                }
+            }
+        }
+        self.spool.write_all(&(rec.len() as i32).to_le_bytes())?;
+        self.spool.write_all(&rec)
+    }
+}
+
+impl HitWriter for SamWriter {
+    fn write_header(&mut self) -> Result<()> {
+        // written in finish, once all chromosome lengths are known
+        Ok(())
+    }
+    fn write_hit(&mut self, m: &Match, label: &str) -> Result<()> {
+        let aln = sam_alignment(m);
+        if self.is_bam {
+            return self.write_bam_record(m, label, &aln);
+        }
+        let cigar: String = aln
+            .cigar
+            .iter()
+            .map(|(len, op)| format!("{}{}", len, *op as char))
+            .collect();
+        let tags: String = annotation_tags(m)
+            .into_iter()
+            .map(|(tag, value)| match value {
+                TagValue::Str(text) => format!("\t{}:Z:{}", tag, text),
+                TagValue::Int(num) => format!("\t{}:i:{}", tag, num),
+            })
+            .collect();
+        writeln!(
+            self.spool,
+            "{}\t{}\t{}\t{}\t255\t{}\t*\t0\t0\t{}\t*\tNM:i:{}\tMD:Z:{}{}",
+            query_name(label),
+            if m.is_forward { 0 } else { 16 },
+            ref_name(&m.chr_name),
+            m.chrom_idx + 1,
+            cigar,
+            String::from_utf8_lossy(&aln.seq),
+            aln.nm,
+            aln.md,
+            tags
+        )
+    }
+    fn finish(&mut self) -> Result<()> {
+        self.spool.flush()?;
+        let mut spooled = self.spool.get_ref().try_clone()?;
+        spooled.seek(SeekFrom::Start(0))?;
+        let mut records = BufReader::new(spooled);
+        let out = self.out.take().unwrap();
+        if self.is_bam {
+            let mut bgzf = BgzfWriter::new(out);
+            self.write_bam_header(&mut bgzf)?;
+            std::io::copy(&mut records, &mut bgzf)?;
+            bgzf.finish()?;
+        } else {
+            let mut out = out;
+            out.write_all(self.header_text().as_bytes())?;
+            std::io::copy(&mut records, &mut out)?;
+            out.flush()?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flate2::read::MultiGzDecoder;
+    use std::io::Read;
+
+    fn hit(dna: &[u8], rna: &[u8], is_forward: bool) -> Match {
+        Match {
+            chrom_idx: 100,
+            is_forward,
+            ..test_match(dna, rna)
+        }
+    }
+
+    #[test]
+    fn test_sam_alignment() {
+        let aln = sam_alignment(&hit(b"ACTTAGG", b"ACGTNRG", true));
+        assert_eq!(aln.seq, b"ACGTNRG");
+        assert_eq!(aln.cigar, vec![(7, b'M')]);
+        assert_eq!(aln.md, "2T4");
+        assert_eq!(aln.nm, 1);
+    }
+    #[test]
+    fn test_sam_alignment_bulges() {
+        let aln = sam_alignment(&hit(b"ACTTGAGG", b"AC-TGNNN", true));
+        assert_eq!(aln.cigar, vec![(2, b'M'), (1, b'D'), (5, b'M')]);
+        assert_eq!(aln.md, "2^T5");
+        assert_eq!(aln.nm, 1);
+        let aln = sam_alignment(&hit(b"AC-GTAGG", b"ACCGTNNN", true));
+        assert_eq!(aln.seq, b"ACCGTNNN");
+        assert_eq!(aln.cigar, vec![(2, b'M'), (1, b'I'), (5, b'M')]);
+        assert_eq!(aln.md, "7");
+    }
+    #[test]
+    fn test_sam_alignment_reverse() {
+        // reverse hits are stored in guide orientation and flipped back onto the genome
+        let aln = sam_alignment(&hit(b"ACGTAGG", b"ACGTNGG", false));
+        assert_eq!(aln.seq, b"CCNACGT");
+        assert_eq!(aln.md, "7");
+    }
+    #[test]
+    fn test_sam_annotation_tags() {
+        let dir = tempfile::tempdir().unwrap();
+        let sam_path = dir.path().join("hits.sam");
+        let out = Box::new(File::create(&sam_path).unwrap());
+        let mut writer = SamWriter::new(out, false, Arc::default()).unwrap();
+        writer.write_header().unwrap();
+        let mut m = hit(b"ACTTAGG", b"ACGTNRG", true);
+        writer.write_hit(&m, "guide 1").unwrap();
+        m.annotation = Some(HitAnnotation {
+            class: FeatureClass::Utr5,
+            gene_ids: vec!["G1".to_string(), "G2".to_string()],
+            gene_names: vec!["ABC1".to_string(), "G2".to_string()],
+            gene_distance: Some(0),
+        });
+        writer.write_hit(&m, "guide 1").unwrap();
+        writer.finish().unwrap();
+        let sam = std::fs::read_to_string(&sam_path).unwrap();
+        let records: Vec<&str> = sam.lines().filter(|l| !l.starts_with('@')).collect();
+        assert!(records[0].ends_with("\tNM:i:1\tMD:Z:2T4"));
+        assert!(records[1].ends_with("\tMD:Z:2T4\tXF:Z:5'UTR\tXG:Z:ABC1,G2\tXI:Z:G1,G2\tXD:i:0"));
+    }
+    #[test]
+    fn test_bam_output() {
+        let dir = tempfile::tempdir().unwrap();
+        let bam_path = dir.path().join("hits.bam");
+        let chrom_sizes: Arc<Mutex<ChromSizes>> = Arc::default();
+        chrom_sizes
+            .lock()
+            .unwrap()
+            .record_len("chr1 description", 1000);
+        let out = Box::new(File::create(&bam_path).unwrap());
+        let mut writer = SamWriter::new(out, true, chrom_sizes).unwrap();
+        writer.write_header().unwrap();
+        writer
+            .write_hit(&hit(b"ACTTAGG", b"ACGTNRG", true), "guide 1")
+            .unwrap();
+        writer.finish().unwrap();
+
+        let mut bam: Vec<u8> = Vec::new();
+        MultiGzDecoder::new(File::open(&bam_path).unwrap())
+            .read_to_end(&mut bam)
+            .unwrap();
+        let int_at = |at: usize| i32::from_le_bytes(bam[at..at + 4].try_into().unwrap());
+        assert_eq!(&bam[..4], b"BAM\x01");
+        let text_len = int_at(4) as usize;
+        let text = std::str::from_utf8(&bam[8..8 + text_len]).unwrap();
+        assert!(text.starts_with("@HD\tVN:1.6\tSO:unsorted\n@SQ\tSN:chr1\tLN:1000\n"));
+        let refs_at = 8 + text_len;
+        assert_eq!(int_at(refs_at), 1);
+        assert_eq!(int_at(refs_at + 4), 5);
+        assert_eq!(&bam[refs_at + 8..refs_at + 13], b"chr1\0");
+        assert_eq!(int_at(refs_at + 13), 1000);
+
+        let rec_at = refs_at + 17;
+        let rec = &bam[rec_at + 4..];
+        assert_eq!(int_at(rec_at) as usize, rec.len());
+        // reference, position, name length, mapq, bin, cigar length, flag, sequence length
+        assert_eq!(&rec[..4], &0_i32.to_le_bytes());
+        assert_eq!(&rec[4..8], &100_i32.to_le_bytes());
+        assert_eq!(rec[8], 8);
+        assert_eq!(rec[9], 255);
+        assert_eq!(&rec[10..12], &reg2bin(100, 107).to_le_bytes());
+        assert_eq!(&rec[12..14], &1_u16.to_le_bytes());
+        assert_eq!(&rec[14..16], &0_u16.to_le_bytes());
+        assert_eq!(&rec[16..20], &7_i32.to_le_bytes());
+        assert_eq!(&rec[32..40], b"guide_1\0");
+        assert_eq!(&rec[40..44], &(7_u32 << 4).to_le_bytes());
+        assert_eq!(&rec[44..48], &[0x12, 0x48, 0xf5, 0x40]);
+        assert_eq!(&rec[48..55], &[0xff; 7]);
+        assert_eq!(&rec[55..], b"NMi\x01\0\0\0MDZ2T4\0");
+    }
+    #[test]
+    fn test_reg2bin() {
+        assert_eq!(reg2bin(0, 1), 4681);
+        assert_eq!(reg2bin(16383, 16385), 585);
+    }
+}
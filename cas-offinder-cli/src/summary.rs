@@ -0,0 +1,134 @@
+use cas_offinder_lib::*;
+use std::collections::HashSet;
+use std::fs::File;
+use std::io::{BufWriter, Result, Write};
+
+/*
+Per-guide summary of a run, one line per guide of the input file.
+Counts are collected hit by hit, so the hit list itself never has to be kept.
+*/
+
+pub struct GuideSummary {
+    // number of hits with 0, 1, ..., max mismatches
+    by_mismatches: Vec<u64>,
+    n_forward: u64,
+    n_reverse: u64,
+    chroms: HashSet<String>,
+    specificity: GuideSpecificity,
+}
+
+impl GuideSummary {
+    pub fn new(max_mismatches: u32) -> GuideSummary {
+        GuideSummary {
+            by_mismatches: vec![0; max_mismatches as usize + 1],
+            n_forward: 0,
+            n_reverse: 0,
+            chroms: HashSet::new(),
+            specificity: GuideSpecificity::default(),
+        }
+    }
+    pub fn add(&mut self, m: &Match) {
+        let mm_idx = (m.mismatches as usize).min(self.by_mismatches.len() - 1);
+        self.by_mismatches[mm_idx] += 1;
+        if m.is_forward {
+            self.n_forward += 1;
+        } else {
+            self.n_reverse += 1;
+        }
+        let chrom = m.chr_name.split_ascii_whitespace().next().unwrap_or("");
+        if !self.chroms.contains(chrom) {
+            self.chroms.insert(chrom.to_string());
+        }
+        self.specificity.add(m);
+    }
+    // hit counts by mismatch number, as shown by CRISPOR, e.g. 1-0-2-14
+    fn mismatch_profile(&self) -> String {
+        let counts: Vec<String> = self.by_mismatches.iter().map(|c| c.to_string()).collect();
+        counts.join("-")
+    }
+}
+
+fn score_str(score: Option<f64>) -> String {
+    match score {
+        Some(score) => format!("{:.2}", score),
+        None => ".".to_string(),
+    }
+}
+
+fn write_summary_lines(
+    out: &mut dyn Write,
+    labels: &[String],
+    patterns: &[Vec<u8>],
+    summaries: &[GuideSummary],
+) -> Result<()> {
+    writeln!(
+        out,
+        "#Id\tcrRNA\tHits\tMismatch profile\tForward\tReverse\tChromosomes\tMIT specificity\tCFD specificity"
+    )?;
+    for ((label, pattern), summary) in labels.iter().zip(patterns.iter()).zip(summaries.iter()) {
+        writeln!(
+            out,
+            "{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}",
+            label,
+            String::from_utf8_lossy(pattern),
+            summary.specificity.n_hits,
+            summary.mismatch_profile(),
+            summary.n_forward,
+            summary.n_reverse,
+            summary.chroms.len(),
+            score_str(summary.specificity.mit_specificity()),
+            score_str(summary.specificity.cfd_specificity())
+        )?;
+    }
+    out.flush()
+}
+
+pub fn write_guide_summary(
+    path: &str,
+    labels: &[String],
+    patterns: &[Vec<u8>],
+    summaries: &[GuideSummary],
+) -> Result<()> {
+    let out = if path != "-" {
+        Box::new(File::create(path)?) as Box<dyn Write>
+    } else {
+        Box::new(std::io::stdout()) as Box<dyn Write>
+    };
+    write_summary_lines(&mut BufWriter::new(out), labels, patterns, summaries)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hit(chr_name: &str, mismatches: u32, is_forward: bool) -> Match {
+        Match {
+            chr_name: chr_name.to_string(),
+            mismatches,
+            is_forward,
+            ..test_match(b"ACGTAGG", b"ACGTNRG")
+        }
+    }
+
+    #[test]
+    fn test_guide_summary() {
+        let mut summary = GuideSummary::new(3);
+        summary.add(&hit("chr1 description", 0, true));
+        summary.add(&hit("chr1", 2, false));
+        summary.add(&hit("chr2", 2, true));
+        summary.add(&hit("chr2", 3, true));
+        let mut out: Vec<u8> = Vec::new();
+        write_summary_lines(
+            &mut out,
+            &["guide1".to_string()],
+            &[b"ACGTNNN".to_vec()],
+            &[summary],
+        )
+        .unwrap();
+        let written = String::from_utf8(out).unwrap();
+        assert_eq!(
+            written.lines().nth(1).unwrap(),
+            "guide1\tACGTNNN\t4\t1-0-2-1\t3\t1\t2\t.\t."
+        );
+    }
+}
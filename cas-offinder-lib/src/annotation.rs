@@ -0,0 +1,404 @@
+use crate::cli_err::{CliError, Result};
+use crate::read_fasta::is_gzip_file;
+use flate2::read::MultiGzDecoder;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufRead, BufReader, Read};
+use std::path::Path;
+
+/*
+Gene annotation of hits from GTF or GFF3 files (optionally gzip compressed).
+Every hit gets the most specific feature class it overlaps, in the order
+5'UTR, 3'UTR, UTR, exon (including CDS), intron, promoter and intergenic,
+together with the genes it overlaps and the distance to the nearest gene.
+Chromosomes are keyed by the first word of their name, like the genome readers.
+*/
+
+// promoter window upstream of the transcription start of every gene
+pub const PROMOTER_LEN: u64 = 1000;
+
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug)]
+pub enum FeatureClass {
+    Utr5,
+    Utr3,
+    Utr,
+    Exon,
+    Intron,
+    Promoter,
+    Intergenic,
+}
+
+impl FeatureClass {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            FeatureClass::Utr5 => "5'UTR",
+            FeatureClass::Utr3 => "3'UTR",
+            FeatureClass::Utr => "UTR",
+            FeatureClass::Exon => "exon",
+            FeatureClass::Intron => "intron",
+            FeatureClass::Promoter => "promoter",
+            FeatureClass::Intergenic => "intergenic",
+        }
+    }
+    fn from_feature_type(feature_type: &str) -> Option<FeatureClass> {
+        match feature_type {
+            "five_prime_UTR" | "five_prime_utr" | "5UTR" => Some(FeatureClass::Utr5),
+            "three_prime_UTR" | "three_prime_utr" | "3UTR" => Some(FeatureClass::Utr3),
+            "UTR" => Some(FeatureClass::Utr),
+            "exon" | "CDS" => Some(FeatureClass::Exon),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Gene {
+    pub id: String,
+    pub name: String,
+    pub start: u64,
+    pub end: u64,
+    pub is_forward: bool,
+}
+
+impl Gene {
+    // half open promoter window, upstream of the gene on its own strand
+    fn promoter(&self) -> (u64, u64) {
+        if self.is_forward {
+            (self.start.saturating_sub(PROMOTER_LEN), self.start)
+        } else {
+            (self.end, self.end + PROMOTER_LEN)
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct HitAnnotation {
+    pub class: FeatureClass,
+    // overlapping genes
+    pub gene_ids: Vec<String>,
+    pub gene_names: Vec<String>,
+    // 0 when inside a gene, None when the chromosome has no genes
+    pub gene_distance: Option<u64>,
+}
+
+// half open intervals sorted by start, with the running maximum of the ends
+// so that all intervals overlapping a query are found by binary search
+struct IntervalIndex<T> {
+    intervals: Vec<(u64, u64, T)>,
+    max_ends: Vec<u64>,
+}
+
+impl<T> IntervalIndex<T> {
+    fn new(mut intervals: Vec<(u64, u64, T)>) -> IntervalIndex<T> {
+        intervals.sort_by_key(|iv| (iv.0, iv.1));
+        let mut max_end = 0;
+        let max_ends = intervals
+            .iter()
+            .map(|iv| {
+                max_end = max_end.max(iv.1);
+                max_end
+            })
+            .collect();
+        IntervalIndex {
+            intervals,
+            max_ends,
+        }
+    }
+    fn overlapping(&self, start: u64, end: u64) -> impl Iterator<Item = &(u64, u64, T)> {
+        let last = self.intervals.partition_point(|iv| iv.0 < end);
+        let first = self.max_ends[..last].partition_point(|max_end| *max_end <= start);
+        self.intervals[first..last]
+            .iter()
+            .filter(move |iv| iv.1 > start)
+    }
+    // distance from [start, end) to the closest interval, 0 if one overlaps
+    fn distance(&self, start: u64, end: u64) -> Option<u64> {
+        let n_before = self.intervals.partition_point(|iv| iv.0 < end);
+        let upstream = n_before
+            .checked_sub(1)
+            .map(|idx| start.saturating_sub(self.max_ends[idx]));
+        let downstream = self.intervals.get(n_before).map(|iv| iv.0 - end);
+        match (upstream, downstream) {
+            (Some(up), Some(down)) => Some(up.min(down)),
+            (up, down) => up.or(down),
+        }
+    }
+}
+
+#[derive(Default)]
+struct ChromFeatures {
+    genes: Vec<(u64, u64, Gene)>,
+    features: Vec<(u64, u64, FeatureClass)>,
+}
+
+struct ChromIndex {
+    genes: IntervalIndex<Gene>,
+    promoters: IntervalIndex<()>,
+    features: IntervalIndex<FeatureClass>,
+}
+
+pub struct GeneAnnotation {
+    chroms: HashMap<String, ChromIndex>,
+}
+
+fn chrom_key(chr_name: &str) -> &str {
+    chr_name.split_ascii_whitespace().next().unwrap_or("")
+}
+
+// GFF3 attributes are key=value;key=value, GTF attributes are key "value"; key "value";
+fn get_attribute<'a>(attributes: &'a str, key: &str) -> Option<&'a str> {
+    attributes.split(';').find_map(|attr| {
+        let attr = attr.trim();
+        let (attr_key, value) = attr.split_once(['=', ' '])?;
+        (attr_key == key).then(|| value.trim().trim_matches('"'))
+    })
+}
+
+fn parse_coord(field: &str) -> Result<u64> {
+    field
+        .parse::<u64>()
+        .map_err(|_| CliError::BadFileFormat("GTF/GFF3 start and end must be unsigned integers"))
+}
+
+fn read_features(reader: impl BufRead) -> Result<HashMap<String, ChromFeatures>> {
+    let mut chroms: HashMap<String, ChromFeatures> = HashMap::new();
+    // GTF files often have no gene lines, so genes span all features sharing a gene_id on one
+    // chromosome, genes of the pseudoautosomal regions have the same id on chrX and chrY
+    let mut gtf_genes: HashMap<(String, String), Gene> = HashMap::new();
+    for line_r in reader.lines() {
+        let line = line_r?;
+        if line.starts_with('#') || line.trim().is_empty() {
+            continue;
+        }
+        let fields: Vec<&str> = line.split('\t').collect();
+        if fields.len() < 9 {
+            return Err(CliError::BadFileFormat(
+                "GTF/GFF3 lines need 9 tab separated columns",
+            ));
+        }
+        let chrom = fields[0].to_string();
+        let feature_type = fields[2];
+        // 1-based inclusive to 0-based half open
+        let start = parse_coord(fields[3])?.saturating_sub(1);
+        let end = parse_coord(fields[4])?;
+        let is_forward = fields[6] != "-";
+        let attributes = fields[8];
+        if let Some(class) = FeatureClass::from_feature_type(feature_type) {
+            chroms
+                .entry(chrom.clone())
+                .or_default()
+                .features
+                .push((start, end, class));
+        }
+        if let Some(gene_id) = get_attribute(attributes, "gene_id") {
+            let name = get_attribute(attributes, "gene_name")
+                .or(get_attribute(attributes, "Name"))
+                .unwrap_or(gene_id);
+            let gene = gtf_genes
+                .entry((chrom, gene_id.to_string()))
+                .or_insert(Gene {
+                    id: gene_id.to_string(),
+                    name: name.to_string(),
+                    start,
+                    end,
+                    is_forward,
+                });
+            gene.start = gene.start.min(start);
+            gene.end = gene.end.max(end);
+        } else if feature_type.ends_with("gene") {
+            let id = get_attribute(attributes, "ID").unwrap_or("");
+            let id = id.strip_prefix("gene:").unwrap_or(id);
+            let name = get_attribute(attributes, "Name").unwrap_or(id);
+            let gene = Gene {
+                id: id.to_string(),
+                name: name.to_string(),
+                start,
+                end,
+                is_forward,
+            };
+            chroms
+                .entry(chrom)
+                .or_default()
+                .genes
+                .push((start, end, gene));
+        }
+    }
+    for ((chrom, _), gene) in gtf_genes {
+        chroms
+            .entry(chrom)
+            .or_default()
+            .genes
+            .push((gene.start, gene.end, gene));
+    }
+    Ok(chroms)
+}
+
+impl GeneAnnotation {
+    pub fn from_file(fname: &Path) -> Result<GeneAnnotation> {
+        let file = File::open(fname)?;
+        let source = if is_gzip_file(fname)? {
+            Box::new(MultiGzDecoder::new(file)) as Box<dyn Read>
+        } else {
+            Box::new(file) as Box<dyn Read>
+        };
+        GeneAnnotation::from_reader(BufReader::new(source))
+    }
+    pub fn from_reader(reader: impl BufRead) -> Result<GeneAnnotation> {
+        let chroms = read_features(reader)?
+            .into_iter()
+            .map(|(chrom, features)| {
+                let promoters = features
+                    .genes
+                    .iter()
+                    .map(|(_, _, gene)| {
+                        let (start, end) = gene.promoter();
+                        (start, end, ())
+                    })
+                    .collect();
+                let index = ChromIndex {
+                    genes: IntervalIndex::new(features.genes),
+                    promoters: IntervalIndex::new(promoters),
+                    features: IntervalIndex::new(features.features),
+                };
+                (chrom, index)
+            })
+            .collect();
+        Ok(GeneAnnotation { chroms })
+    }
+    // annotation of the genome interval [start, end)
+    pub fn annotate(&self, chr_name: &str, start: u64, end: u64) -> HitAnnotation {
+        let Some(index) = self.chroms.get(chrom_key(chr_name)) else {
+            return HitAnnotation {
+                class: FeatureClass::Intergenic,
+                gene_ids: Vec::new(),
+                gene_names: Vec::new(),
+                gene_distance: None,
+            };
+        };
+        let genes: Vec<&Gene> = index
+            .genes
+            .overlapping(start, end)
+            .map(|(_, _, gene)| gene)
+            .collect();
+        let feature_class = index
+            .features
+            .overlapping(start, end)
+            .map(|(_, _, class)| *class)
+            .min();
+        let class = match feature_class {
+            Some(class) => class,
+            None if !genes.is_empty() => FeatureClass::Intron,
+            None if index.promoters.overlapping(start, end).next().is_some() => {
+                FeatureClass::Promoter
+            }
+            None => FeatureClass::Intergenic,
+        };
+        HitAnnotation {
+            class,
+            gene_ids: genes.iter().map(|g| g.id.clone()).collect(),
+            gene_names: genes.iter().map(|g| g.name.clone()).collect(),
+            gene_distance: index.genes.distance(start, end),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TEST_GTF: &str = "\
+#!genome-build test
+chr1\ttest\texon\t1001\t1100\t.\t+\t.\tgene_id \"G1\"; gene_name \"ABC1\"; transcript_id \"T1\";
+chr1\ttest\tfive_prime_utr\t1001\t1020\t.\t+\t.\tgene_id \"G1\"; gene_name \"ABC1\"; transcript_id \"T1\";
+chr1\ttest\texon\t2001\t2200\t.\t+\t.\tgene_id \"G1\"; gene_name \"ABC1\"; transcript_id \"T1\";
+chr1\ttest\texon\t5001\t5100\t.\t-\t.\tgene_id \"G2\"; transcript_id \"T2\";
+";
+
+    const TEST_GFF3: &str = "\
+##gff-version 3
+chr2\ttest\tgene\t101\t500\t.\t-\t.\tID=gene:G3;Name=XYZ3
+chr2\ttest\tmRNA\t101\t500\t.\t-\t.\tID=transcript:T3;Parent=gene:G3
+chr2\ttest\tthree_prime_UTR\t101\t150\t.\t-\t.\tParent=transcript:T3
+chr2\ttest\tCDS\t151\t200\t.\t-\t.\tParent=transcript:T3
+";
+
+    #[test]
+    fn test_gtf_annotation() {
+        let annotation = GeneAnnotation::from_reader(TEST_GTF.as_bytes()).unwrap();
+        let utr = annotation.annotate("chr1 description", 1005, 1028);
+        assert_eq!(utr.class, FeatureClass::Utr5);
+        assert_eq!(utr.gene_ids, vec!["G1"]);
+        assert_eq!(utr.gene_names, vec!["ABC1"]);
+        assert_eq!(utr.gene_distance, Some(0));
+        assert_eq!(
+            annotation.annotate("chr1", 1500, 1523).class,
+            FeatureClass::Intron
+        );
+        assert_eq!(
+            annotation.annotate("chr1", 2190, 2213).class,
+            FeatureClass::Exon
+        );
+        let promoter = annotation.annotate("chr1", 500, 523);
+        assert_eq!(promoter.class, FeatureClass::Promoter);
+        assert!(promoter.gene_ids.is_empty());
+        assert_eq!(promoter.gene_distance, Some(477));
+        // G2 is on the reverse strand, so its promoter lies after its end
+        assert_eq!(
+            annotation.annotate("chr1", 5200, 5223).class,
+            FeatureClass::Promoter
+        );
+        let intergenic = annotation.annotate("chr1", 3000, 3023);
+        assert_eq!(intergenic.class, FeatureClass::Intergenic);
+        assert_eq!(intergenic.gene_distance, Some(800));
+        assert_eq!(
+            annotation.annotate("chr1", 5000, 5023).gene_names,
+            vec!["G2"]
+        );
+        assert_eq!(annotation.annotate("chrX", 0, 23).gene_distance, None);
+    }
+    #[test]
+    fn test_gtf_gene_on_two_chroms() {
+        let gtf = "\
+chrX\ttest\texon\t101\t200\t.\t+\t.\tgene_id \"PAR1\"; transcript_id \"T1\";
+chrY\ttest\texon\t10001\t10100\t.\t+\t.\tgene_id \"PAR1\"; transcript_id \"T2\";
+";
+        let annotation = GeneAnnotation::from_reader(gtf.as_bytes()).unwrap();
+        assert_eq!(annotation.annotate("chrX", 150, 173).gene_ids, vec!["PAR1"]);
+        assert_eq!(
+            annotation.annotate("chrY", 10020, 10043).gene_ids,
+            vec!["PAR1"]
+        );
+        // neither copy of the gene spans the range of the other one
+        let between = annotation.annotate("chrX", 5000, 5023);
+        assert_eq!(between.class, FeatureClass::Intergenic);
+        assert_eq!(between.gene_distance, Some(4800));
+        assert_eq!(
+            annotation.annotate("chrY", 5000, 5023).gene_distance,
+            Some(4977)
+        );
+    }
+    #[test]
+    fn test_gff3_annotation() {
+        let annotation = GeneAnnotation::from_reader(TEST_GFF3.as_bytes()).unwrap();
+        let utr = annotation.annotate("chr2", 140, 163);
+        assert_eq!(utr.class, FeatureClass::Utr3);
+        assert_eq!(utr.gene_ids, vec!["G3"]);
+        assert_eq!(utr.gene_names, vec!["XYZ3"]);
+        assert_eq!(
+            annotation.annotate("chr2", 160, 183).class,
+            FeatureClass::Exon
+        );
+        assert_eq!(
+            annotation.annotate("chr2", 300, 323).class,
+            FeatureClass::Intron
+        );
+        assert_eq!(
+            annotation.annotate("chr2", 600, 623).class,
+            FeatureClass::Promoter
+        );
+        assert_eq!(
+            annotation.annotate("chr2", 50, 73).class,
+            FeatureClass::Intergenic
+        );
+    }
+}
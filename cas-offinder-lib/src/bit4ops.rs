@@ -181,6 +181,15 @@ pub fn bit2_to_bit4(out_data: &mut [u8], data: &[u8], n_chrs: usize) {
         memsetbit4(out_data, 0, n_chrs, n_blks * 4);
     }
 }
+// soft-mask bitmaps store one bit per nucleotide
+pub fn setmaskbits(mask: &mut [u8], start: usize, end: usize) {
+    for idx in start..end {
+        mask[idx / 8] |= 1 << (idx % 8);
+    }
+}
+pub fn getmaskbit(mask: &[u8], idx: usize) -> bool {
+    mask[idx / 8] & (1 << (idx % 8)) != 0
+}
 pub fn memsetbit4(dest: &mut [u8], bit4val: u8, start: usize, end: usize) {
     assert!(bit4val <= 0xf);
     if start < end && start % 2 == 1 {
@@ -208,7 +217,7 @@ fn complimentb4(v: u8) -> u8 {
     // only operates on the first 4 bits
     ((v << 2) | (v >> 2)) & 0xf
 }
-fn compliment_char(c: u8) -> u8 {
+pub(crate) fn compliment_char(c: u8) -> u8 {
     let b4 = STR_2_BIT4[true as usize][c as usize];
     let rev_bit4 = complimentb4(b4);
     let rev_char = if b4 != 0 {
@@ -386,4 +395,12 @@ mod tests {
         let actual_out = input.map(is_mixedbase);
         assert_eq!(expected_out, actual_out);
     }
+    #[test]
+    fn test_setmaskbits() {
+        let mut mask = [0_u8; 3];
+        setmaskbits(&mut mask, 5, 11);
+        assert_eq!(mask, [0xe0, 0x07, 0x00]);
+        assert!(getmaskbit(&mask, 5));
+        assert!(!getmaskbit(&mask, 11));
+    }
 }
@@ -0,0 +1,278 @@
+use crate::bit4ops::{bit4_to_string, cdiv, string_to_bit4};
+use crate::chrom_chunk::BulgeType;
+
+/*
+Bulges are searched by expanding every pattern into a set of gapped variants
+that the mismatch kernels can score directly:
+  * a DNA bulge of size b inserts b N's into the pattern, so the extra genome bases
+    line up with wildcards
+  * an RNA bulge of size r deletes r bases from the pattern
+Every variant is padded up to pattern_len + max_dna_bulge so that all variants of a
+pattern share a single length and can be packed and searched together. The padding
+matches no genome base, not even N, and search() raises the mismatch threshold of the
+variant by the padding, so hits running into N's or past the end of a chromosome are kept.
+Bulges are only placed strictly inside the non-N core of a pattern, so the PAM
+region (which is N in the guide) never gets split.
+*/
+
+pub struct PatternVariant {
+    // bit4 encoded variant, padded to the searched length
+    pub pattern: Vec<u8>,
+    // index of the pattern the variant was generated from
+    pub src_idx: u32,
+    // length of the source pattern
+    pub pattern_len: usize,
+    // length of the padded variant
+    pub search_len: usize,
+    pub bulge_type: BulgeType,
+    pub bulge_size: u32,
+    // position in the original pattern where the gap starts
+    pub bulge_pos: usize,
+    // number of trailing padding nucleotides
+    pub padding: usize,
+}
+
+impl PatternVariant {
+    // number of genome nucleotides covered by the unpadded variant
+    pub fn dna_len(&self) -> usize {
+        match self.bulge_type {
+            BulgeType::None => self.pattern_len,
+            BulgeType::Dna => self.pattern_len + self.bulge_size as usize,
+            BulgeType::Rna => self.pattern_len - self.bulge_size as usize,
+        }
+    }
+}
+
+pub fn searched_pattern_len(pattern_len: usize, max_dna_bulge: u32) -> usize {
+    pattern_len + max_dna_bulge as usize
+}
+
+fn encode(chars: &[u8]) -> Vec<u8> {
+    let mut buf = vec![0_u8; cdiv(chars.len(), 2)];
+    string_to_bit4(&mut buf, chars, 0, true);
+    buf
+}
+
+fn pad_to(mut chars: Vec<u8>, len: usize) -> Vec<u8> {
+    // '-' is not a base and encodes to 0
+    chars.resize(len, b'-');
+    chars
+}
+
+pub fn expand_bulges(
+    patterns: &[Vec<u8>],
+    pattern_lens: &[usize],
+    max_dna_bulge: u32,
+    max_rna_bulge: u32,
+) -> Vec<PatternVariant> {
+    let mut variants: Vec<PatternVariant> = Vec::new();
+    for (src_idx, (pattern, &pattern_len)) in patterns.iter().zip(pattern_lens).enumerate() {
+        let search_len = searched_pattern_len(pattern_len, max_dna_bulge);
+        let mut chars = vec![0_u8; pattern_len];
+        bit4_to_string(&mut chars, pattern, 0, pattern_len);
+        let mut seen: Vec<Vec<u8>> = Vec::new();
+        let mut push_variant =
+            |variant: Vec<u8>, bulge_type: BulgeType, bulge_size: u32, bulge_pos: usize| {
+                if seen.contains(&variant) {
+                    // identical variants would only report the same hits twice
+                    return;
+                }
+                let padding = search_len - variant.len();
+                variants.push(PatternVariant {
+                    pattern: encode(&pad_to(variant.clone(), search_len)),
+                    src_idx: src_idx as u32,
+                    pattern_len,
+                    search_len,
+                    bulge_type,
+                    bulge_size,
+                    bulge_pos,
+                    padding,
+                });
+                seen.push(variant);
+            };
+        push_variant(chars.clone(), BulgeType::None, 0, 0);
+
+        let core_start = chars.iter().position(|c| *c != b'N');
+        let core_end = chars.iter().rposition(|c| *c != b'N').map(|p| p + 1);
+        let (core_start, core_end) = match (core_start, core_end) {
+            (Some(start), Some(end)) => (start, end),
+            _ => continue,
+        };
+        for bulge_size in 1..=max_dna_bulge as usize {
+            for pos in (core_start + 1)..core_end {
+                let mut variant = chars[..pos].to_vec();
+                variant.extend(std::iter::repeat_n(b'N', bulge_size));
+                variant.extend_from_slice(&chars[pos..]);
+                push_variant(variant, BulgeType::Dna, bulge_size as u32, pos);
+            }
+        }
+        for bulge_size in 1..=max_rna_bulge as usize {
+            for pos in (core_start + 1)..core_end.saturating_sub(bulge_size) {
+                let mut variant = chars[..pos].to_vec();
+                variant.extend_from_slice(&chars[pos + bulge_size..]);
+                push_variant(variant, BulgeType::Rna, bulge_size as u32, pos);
+            }
+        }
+    }
+    variants
+}
+
+// search filter lined up with the variant, '-' marks the positions the filter does not
+// apply to: the extra genome bases of a DNA bulge and the padding. Guide bases missing
+// from the genome in an RNA bulge are dropped along with their filter bases.
+pub fn variant_search_filter(variant: &PatternVariant, search_filter: &[u8]) -> Vec<u8> {
+    let pos = variant.bulge_pos;
+    let bulge_size = variant.bulge_size as usize;
+    let mut chars: Vec<u8> = match variant.bulge_type {
+        BulgeType::None => search_filter.to_vec(),
+        BulgeType::Dna => search_filter[..pos]
+            .iter()
+            .copied()
+            .chain(std::iter::repeat_n(b'-', bulge_size))
+            .chain(search_filter[pos..].iter().copied())
+            .collect(),
+        BulgeType::Rna => search_filter[..pos]
+            .iter()
+            .chain(search_filter[pos + bulge_size..].iter())
+            .copied()
+            .collect(),
+    };
+    chars.resize(variant.search_len, b'-');
+    encode(&chars)
+}
+
+pub fn gapped_alignment(
+    variant: &PatternVariant,
+    dna: &[u8],
+    pattern: &[u8],
+) -> (Vec<u8>, Vec<u8>) {
+    /* returns (dna, rna) alignment strings of equal length with '-' marking the gaps */
+    let pos = variant.bulge_pos;
+    let gap = std::iter::repeat_n(b'-', variant.bulge_size as usize);
+    match variant.bulge_type {
+        BulgeType::None => (dna.to_vec(), pattern.to_vec()),
+        BulgeType::Dna => {
+            let rna: Vec<u8> = pattern[..pos]
+                .iter()
+                .copied()
+                .chain(gap)
+                .chain(pattern[pos..].iter().copied())
+                .collect();
+            (dna.to_vec(), rna)
+        }
+        BulgeType::Rna => {
+            let dna: Vec<u8> = dna[..pos]
+                .iter()
+                .copied()
+                .chain(gap)
+                .chain(dna[pos..].iter().copied())
+                .collect();
+            (dna, pattern.to_vec())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn decode(variant: &PatternVariant, len: usize) -> Vec<u8> {
+        let mut chars = vec![0_u8; len];
+        bit4_to_string(&mut chars, &variant.pattern, 0, len);
+        chars
+    }
+
+    #[test]
+    fn test_expand_no_bulges() {
+        let variants = expand_bulges(&[encode(b"ACGTNN")], &[6], 0, 0);
+        assert_eq!(variants.len(), 1);
+        assert_eq!(decode(&variants[0], 6), b"ACGTNN");
+        assert_eq!(variants[0].padding, 0);
+    }
+    #[test]
+    fn test_expand_dna_bulge() {
+        let variants = expand_bulges(&[encode(b"ACGTNN")], &[6], 1, 0);
+        let decoded: Vec<Vec<u8>> = variants.iter().map(|v| decode(v, 7)).collect();
+        // the padding is not a base and decodes to 0
+        assert_eq!(
+            decoded,
+            vec![
+                b"ACGTNN\0".to_vec(),
+                b"ANCGTNN".to_vec(),
+                b"ACNGTNN".to_vec(),
+                b"ACGNTNN".to_vec(),
+            ]
+        );
+        assert_eq!(variants[2].bulge_type, BulgeType::Dna);
+        assert_eq!(variants[2].bulge_pos, 2);
+        assert_eq!(variants[2].padding, 0);
+        assert_eq!(variants[0].padding, 1);
+    }
+    #[test]
+    fn test_expand_rna_bulge() {
+        let variants = expand_bulges(&[encode(b"ACCGTNN")], &[7], 0, 1);
+        let decoded: Vec<Vec<u8>> = variants.iter().map(|v| decode(v, 7)).collect();
+        // deleting either C gives the same variant, which is only kept once
+        assert_eq!(
+            decoded,
+            vec![
+                b"ACCGTNN".to_vec(),
+                b"ACGTNN\0".to_vec(),
+                b"ACCTNN\0".to_vec(),
+            ]
+        );
+        assert_eq!(variants[1].bulge_type, BulgeType::Rna);
+        assert_eq!(variants[1].dna_len(), 6);
+    }
+    #[test]
+    fn test_expand_mixed_lengths() {
+        let variants = expand_bulges(&[encode(b"ACGTNN"), encode(b"ACGTNNN")], &[6, 7], 1, 0);
+        let search_lens: Vec<usize> = variants.iter().map(|v| v.search_len).collect();
+        assert_eq!(search_lens, vec![7, 7, 7, 7, 8, 8, 8, 8]);
+        assert_eq!(variants[4].src_idx, 1);
+        assert_eq!(variants[4].padding, 1);
+    }
+    #[test]
+    fn test_gapped_alignment() {
+        let variants = expand_bulges(&[encode(b"ACGTNN")], &[6], 1, 1);
+        let dna_bulge = variants
+            .iter()
+            .find(|v| v.bulge_type == BulgeType::Dna && v.bulge_pos == 2)
+            .unwrap();
+        let (dna, rna) = gapped_alignment(dna_bulge, b"ACTGTAG", b"ACGTNN");
+        assert_eq!(dna, b"ACTGTAG");
+        assert_eq!(rna, b"AC-GTNN");
+        let rna_bulge = variants
+            .iter()
+            .find(|v| v.bulge_type == BulgeType::Rna && v.bulge_pos == 1)
+            .unwrap();
+        let (dna, rna) = gapped_alignment(rna_bulge, b"AGTAG", b"ACGTNN");
+        assert_eq!(dna, b"A-GTAG");
+        assert_eq!(rna, b"ACGTNN");
+    }
+    #[test]
+    fn test_variant_search_filter() {
+        let variants = expand_bulges(&[encode(b"ACGTNN")], &[6], 1, 1);
+        let filters: Vec<Vec<u8>> = variants
+            .iter()
+            .map(|v| {
+                let mut chars = vec![0_u8; v.search_len];
+                let filter = variant_search_filter(v, b"NNNNRG");
+                bit4_to_string(&mut chars, &filter, 0, v.search_len);
+                chars
+            })
+            .collect();
+        // '-' is not a base and decodes to 0
+        assert_eq!(filters[0], b"NNNNRG\0");
+        let dna_bulge = variants
+            .iter()
+            .position(|v| v.bulge_type == BulgeType::Dna && v.bulge_pos == 2)
+            .unwrap();
+        assert_eq!(filters[dna_bulge], b"NN\0NNRG");
+        let rna_bulge = variants
+            .iter()
+            .position(|v| v.bulge_type == BulgeType::Rna && v.bulge_pos == 1)
+            .unwrap();
+        assert_eq!(filters[rna_bulge], b"NNNRG\0\0");
+    }
+}
@@ -0,0 +1,161 @@
+use crate::bit4ops::{cmp_chars, compliment_char};
+
+/*
+Cutting Frequency Determination (CFD) score of SpCas9 off-targets,
+from Doench et al. 2016, Nature Biotechnology 34, 184-191.
+The score is the product of the activity of every spacer mismatch, by position
+and mismatch type, times the activity of the PAM dinucleotide.
+It is only defined for 20 nt spacers followed by a 3 nt PAM without bulges, i.e.
+alignments of 23 columns whose guide ends in NNN.
+*/
+
+pub const CFD_SPACER_LEN: usize = 20;
+const CFD_PAM_LEN: usize = 3;
+
+// mismatch types as guide RNA base : target strand DNA base, in the order of the rows below
+const CFD_MISMATCH_TYPES: [(u8, u8); 12] = [
+    (b'A', b'A'),
+    (b'A', b'C'),
+    (b'A', b'G'),
+    (b'C', b'A'),
+    (b'C', b'C'),
+    (b'C', b'T'),
+    (b'G', b'A'),
+    (b'G', b'G'),
+    (b'G', b'T'),
+    (b'U', b'C'),
+    (b'U', b'G'),
+    (b'U', b'T'),
+];
+
+// activity of a single mismatch at spacer positions 1 (PAM distal) to 20 (PAM proximal)
+#[rustfmt::skip]
+const CFD_MISMATCH_SCORES: [[f64; CFD_SPACER_LEN]; 12] = [
+    // rA:dA
+    [1.0, 0.727272727, 0.705882353, 0.636363636, 0.363636364, 0.714285714, 0.4375, 0.428571429, 0.6, 0.882352941,
+     0.307692308, 0.333333333, 0.3, 0.533333333, 0.2, 0.0, 0.133333333, 0.5, 0.538461538, 0.6],
+    // rA:dC
+    [1.0, 0.8, 0.611111111, 0.625, 0.72, 0.714285714, 0.705882353, 0.733333333, 0.666666667, 0.555555556,
+     0.65, 0.722222222, 0.652173913, 0.466666667, 0.65, 0.192307692, 0.176470588, 0.4, 0.375, 0.764705882],
+    // rA:dG
+    [0.857142857, 0.785714286, 0.428571429, 0.352941176, 0.5, 0.454545455, 0.4375, 0.428571429, 0.571428571, 0.333333333,
+     0.4, 0.263157895, 0.210526316, 0.214285714, 0.272727273, 0.0, 0.176470588, 0.19047619, 0.206896552, 0.227272727],
+    // rC:dA
+    [1.0, 0.909090909, 0.6875, 0.8, 0.636363636, 0.928571429, 0.8125, 0.875, 0.875, 0.9375,
+     0.4, 0.5625, 0.571428571, 0.733333333, 0.933333333, 0.666666667, 0.5, 0.642857143, 0.461538462, 0.3],
+    // rC:dC
+    [0.913043478, 0.695652174, 0.5, 0.5, 0.6, 0.9, 0.611111111, 0.5, 0.529411765, 0.6,
+     0.421052632, 0.476190476, 0.409090909, 0.454545455, 0.05, 0.0, 0.125, 0.043478261, 0.0, 0.0],
+    // rC:dT
+    [1.0, 0.727272727, 0.866666667, 0.842105263, 0.571428571, 0.941176471, 0.8125, 0.8, 0.933333333, 0.933333333,
+     0.8, 1.0, 0.764705882, 0.384615385, 0.944444444, 0.5, 0.470588235, 0.5, 0.307692308, 0.6],
+    // rG:dA
+    [1.0, 0.636363636, 0.5, 0.6, 0.692307692, 0.5, 0.571428571, 0.454545455, 0.5, 0.4,
+     0.384615385, 0.428571429, 0.125, 0.333333333, 0.0, 0.0, 0.0, 0.176470588, 0.125, 0.107142857],
+    // rG:dG
+    [0.714285714, 0.692307692, 0.384615385, 0.529411765, 0.785714286, 0.6875, 0.6, 0.538461538, 0.428571429, 0.5,
+     0.666666667, 0.4, 0.48, 0.3125, 0.294117647, 0.0, 0.25, 0.444444444, 0.4375, 0.5],
+    // rG:dT
+    [0.9, 0.846153846, 0.75, 0.9, 0.866666667, 1.0, 0.933333333, 0.642857143, 0.666666667, 0.9375,
+     1.0, 0.933333333, 0.866666667, 0.75, 0.866666667, 0.307692308, 0.6, 0.9, 0.666666667, 0.8],
+    // rU:dC
+    [0.956521739, 0.84, 0.5, 0.5, 0.777777778, 0.8, 0.692307692, 0.857142857, 0.578947368, 0.6,
+     0.5, 0.5, 0.4, 0.428571429, 0.5, 0.5, 0.111111111, 0.2, 0.538461538, 0.5],
+    // rU:dG
+    [0.9, 0.769230769, 0.75, 0.578947368, 0.866666667, 0.75, 0.8, 0.6, 0.75, 0.8,
+     0.5, 0.8, 0.733333333, 0.727272727, 0.642857143, 0.909090909, 0.692307692, 0.714285714, 0.666666667, 0.8],
+    // rU:dT
+    [1.0, 0.857142857, 0.75, 0.8, 0.5625, 0.6, 0.375, 0.615384615, 0.533333333, 0.6,
+     0.25, 0.076923077, 0.133333333, 0.25, 0.2, 0.0, 0.2, 0.272727273, 0.6, 0.846153846],
+];
+
+// activity of the last two PAM bases, all dinucleotides not listed score 0
+const CFD_PAM_SCORES: [(&[u8; 2], f64); 7] = [
+    (b"AG", 0.259259259),
+    (b"CG", 0.107142857),
+    (b"GA", 0.069444444),
+    (b"GC", 0.022222222),
+    (b"GG", 1.0),
+    (b"GT", 0.016129032),
+    (b"TG", 0.038961039),
+];
+
+fn is_acgt(c: u8) -> bool {
+    matches!(c, b'A' | b'C' | b'G' | b'T')
+}
+
+fn mismatch_score(pos: usize, rnac: u8, dnac: u8) -> f64 {
+    // the table is keyed by the RNA base and the target strand base it pairs with
+    let rna_base = if rnac == b'T' { b'U' } else { rnac };
+    let target_base = compliment_char(dnac);
+    let type_idx = CFD_MISMATCH_TYPES
+        .iter()
+        .position(|t| *t == (rna_base, target_base))
+        .unwrap();
+    CFD_MISMATCH_SCORES[type_idx][pos]
+}
+
+// CFD score of an alignment in guide orientation, None if CFD does not apply to it
+pub fn cfd_score(dna_seq: &[u8], rna_seq: &[u8]) -> Option<f64> {
+    let aln_len = CFD_SPACER_LEN + CFD_PAM_LEN;
+    if dna_seq.len() != aln_len || rna_seq.len() != aln_len {
+        return None;
+    }
+    if rna_seq[CFD_SPACER_LEN..].iter().any(|c| *c != b'N') {
+        return None;
+    }
+    let dna_seq = dna_seq.to_ascii_uppercase();
+    if !dna_seq.iter().all(|c| is_acgt(*c)) {
+        return None;
+    }
+    let mut score = 1.0;
+    for (pos, (dnac, rnac)) in dna_seq.iter().zip(rna_seq.iter()).enumerate() {
+        if pos == CFD_SPACER_LEN {
+            break;
+        }
+        // N in the guide matches anything
+        if cmp_chars(*dnac, *rnac) {
+            continue;
+        }
+        if !is_acgt(*rnac) {
+            return None;
+        }
+        score *= mismatch_score(pos, *rnac, *dnac);
+    }
+    let pam = &dna_seq[CFD_SPACER_LEN + 1..];
+    let pam_score = CFD_PAM_SCORES
+        .iter()
+        .find(|(dinuc, _)| &dinuc[..] == pam)
+        .map_or(0.0, |(_, s)| *s);
+    Some(score * pam_score)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cfd_score() {
+        let guide = b"GGCCGACCTGTCGCTGACGCNNN";
+        assert_eq!(cfd_score(b"GGCCGACCTGTCGCTGACGCTGG", guide), Some(1.0));
+        assert_eq!(
+            cfd_score(b"GGCCGACCTGTCGCTGACGCTAG", guide),
+            Some(0.259259259)
+        );
+        assert_eq!(cfd_score(b"GGCCGACCTGTCGCTGACGCTAA", guide), Some(0.0));
+        // guide G against protospacer A pairs rG with dT on the target strand
+        assert_eq!(cfd_score(b"AGCCGACCTGTCGCTGACGCAGG", guide), Some(0.9));
+        assert_eq!(mismatch_score(15, b'T', b'A'), 0.0);
+        // soft-masked genome bases score like uppercase ones
+        assert_eq!(cfd_score(b"ggccgacctgtcgctgacgctgg", guide), Some(1.0));
+        assert_eq!(cfd_score(b"GGCCGACCTGTCGCTGACGCNGG", guide), None);
+        assert_eq!(
+            cfd_score(b"GGCCGACCTGTCGCTGACGTGG", b"GGCCGACCTGTCGCTGACGNNN"),
+            None
+        );
+        assert_eq!(
+            cfd_score(b"GGCCGACCTGTCGCTGACGCTGG", b"TTTVGACCTGTCGCTGACGCTGG"),
+            None
+        );
+    }
+}
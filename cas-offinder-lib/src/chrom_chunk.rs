@@ -1,10 +1,17 @@
+use crate::annotation::HitAnnotation;
+#[cfg(any(test, feature = "test-util"))]
+use crate::{cfd::cfd_score, cmp_chars};
+
 pub const CHUNK_SIZE: usize = 1 << 16;
 pub const CHUNK_SIZE_BYTES: usize = CHUNK_SIZE / 2;
+pub const CHUNK_MASK_BYTES: usize = CHUNK_SIZE / 8;
 
 pub struct ChromChunkInfo {
     pub chr_name: String,
     // fixed size chunk data
     pub data: Box<[u8; CHUNK_SIZE_BYTES]>,
+    // soft-mask bitmap, bit is set for nucleotides that were lowercase (repeats) in the source
+    pub mask: Box<[u8; CHUNK_MASK_BYTES]>,
     // start and end of data within chromosome, by nucleotide
     pub chunk_start: u64,
     pub chunk_end: u64,
@@ -15,12 +22,90 @@ impl ChromChunkInfo {
         (self.chunk_end - self.chunk_start) as usize
     }
 }
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum BulgeType {
+    None,
+    Dna,
+    Rna,
+}
+
+impl BulgeType {
+    // names used by cas-offinder 3 output
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            BulgeType::None => "X",
+            BulgeType::Dna => "DNA",
+            BulgeType::Rna => "RNA",
+        }
+    }
+}
+
 pub struct Match {
     pub chr_name: String,
+    // alignment strings, with '-' marking bulge gaps
     pub dna_seq: Vec<u8>,
     pub rna_seq: Vec<u8>,
     pub chrom_idx: u64,
     pub pattern_idx: u32,
     pub mismatches: u32,
     pub is_forward: bool,
+    pub bulge_type: BulgeType,
+    pub bulge_size: u32,
+    // number of soft-masked genome bases under the protospacer
+    pub masked_bases: u32,
+    // CFD score, for 20 nt spacers with a 3 nt PAM and no bulges
+    pub cfd_score: Option<f64>,
+    // genes and features at the hit, filled in when an annotation file is given
+    pub annotation: Option<HitAnnotation>,
+}
+
+impl Match {
+    // number of genome bases covered by the hit
+    pub fn genome_len(&self) -> u64 {
+        self.dna_seq.iter().filter(|c| **c != b'-').count() as u64
+    }
+    // genome bases aligned to the non-N part of the guide
+    pub fn protospacer_len(&self) -> u32 {
+        self.dna_seq
+            .iter()
+            .zip(self.rna_seq.iter())
+            .filter(|(d, r)| **d != b'-' && **r != b'N')
+            .count() as u32
+    }
+    pub fn masked_fraction(&self) -> f64 {
+        let len = self.protospacer_len();
+        if len == 0 {
+            0.0
+        } else {
+            self.masked_bases as f64 / len as f64
+        }
+    }
+    pub fn is_in_repeat(&self) -> bool {
+        self.masked_bases > 0 && self.masked_bases == self.protospacer_len()
+    }
+}
+
+// fixture for tests: a hit without bulges at the start of chr1 on the forward strand, with the
+// mismatches and CFD score of the alignment. Other crates get it with the test-util feature.
+#[cfg(any(test, feature = "test-util"))]
+pub fn test_match(dna_seq: &[u8], rna_seq: &[u8]) -> Match {
+    let mismatches = dna_seq
+        .iter()
+        .zip(rna_seq.iter())
+        .filter(|(d, r)| **d != b'-' && **r != b'-' && !cmp_chars(**d, **r))
+        .count() as u32;
+    Match {
+        chr_name: "chr1".to_string(),
+        dna_seq: dna_seq.to_vec(),
+        rna_seq: rna_seq.to_vec(),
+        chrom_idx: 0,
+        pattern_idx: 0,
+        mismatches,
+        is_forward: true,
+        bulge_type: BulgeType::None,
+        bulge_size: 0,
+        masked_bases: 0,
+        cfd_score: cfd_score(dna_seq, rna_seq),
+        annotation: None,
+    }
 }
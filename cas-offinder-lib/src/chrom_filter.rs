@@ -0,0 +1,97 @@
+use crate::cli_err::{CliError, Result};
+use regex::Regex;
+
+/*
+Selects chromosomes/contigs by name, so that alt, random, unplaced and decoy
+contigs can be left out of a search. Names are given as:
+  * an exact name, e.g. chrX
+  * a glob using * and ?, e.g. chrUn_* or *_alt
+  * a regular expression prefixed with re:, e.g. re:^chr[0-9XY]+$
+Only the first word of a chromosome name is matched, as fasta headers often carry
+a description after the name.
+*/
+
+enum NamePattern {
+    Exact(String),
+    Pattern(Regex),
+}
+
+impl NamePattern {
+    fn parse(pattern: &str) -> Result<NamePattern> {
+        let bad_regex = |_| CliError::ArgumentError("Invalid chromosome name pattern");
+        if let Some(expr) = pattern.strip_prefix("re:") {
+            Ok(NamePattern::Pattern(Regex::new(expr).map_err(bad_regex)?))
+        } else if pattern.contains(['*', '?']) {
+            Ok(NamePattern::Pattern(
+                Regex::new(&glob_to_regex(pattern)).map_err(bad_regex)?,
+            ))
+        } else {
+            Ok(NamePattern::Exact(pattern.to_string()))
+        }
+    }
+    fn matches(&self, name: &str) -> bool {
+        match self {
+            NamePattern::Exact(exact) => exact == name,
+            NamePattern::Pattern(expr) => expr.is_match(name),
+        }
+    }
+}
+
+fn glob_to_regex(glob: &str) -> String {
+    let mut expr = String::from("^");
+    for c in glob.chars() {
+        match c {
+            '*' => expr.push_str(".*"),
+            '?' => expr.push('.'),
+            _ => expr.push_str(&regex::escape(&c.to_string())),
+        }
+    }
+    expr.push('$');
+    expr
+}
+
+#[derive(Default)]
+pub struct ChromFilter {
+    // if empty, every chromosome is included
+    include: Vec<NamePattern>,
+    exclude: Vec<NamePattern>,
+}
+
+impl ChromFilter {
+    pub fn include(&mut self, pattern: &str) -> Result<()> {
+        self.include.push(NamePattern::parse(pattern)?);
+        Ok(())
+    }
+    pub fn exclude(&mut self, pattern: &str) -> Result<()> {
+        self.exclude.push(NamePattern::parse(pattern)?);
+        Ok(())
+    }
+    pub fn is_kept(&self, chr_name: &str) -> bool {
+        let name = chr_name.split_ascii_whitespace().next().unwrap_or("");
+        let is_included = self.include.is_empty() || self.include.iter().any(|p| p.matches(name));
+        is_included && !self.exclude.iter().any(|p| p.matches(name))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_chrom_filter() {
+        let mut filter = ChromFilter::default();
+        assert!(filter.is_kept("chrUn_KI270302v1"));
+        filter.exclude("chrUn_*").unwrap();
+        filter.exclude("*_alt").unwrap();
+        filter.exclude("chrM").unwrap();
+        assert!(filter.is_kept("chr1"));
+        assert!(filter.is_kept("chrMT"));
+        assert!(!filter.is_kept("chrM"));
+        assert!(!filter.is_kept("chrUn_KI270302v1"));
+        assert!(!filter.is_kept("chr6_GL000250v2_alt description"));
+        filter.include("re:^chr[0-9]+").unwrap();
+        assert!(filter.is_kept("chr12"));
+        assert!(!filter.is_kept("chrX"));
+        assert!(filter.include("re:(").is_err());
+    }
+}
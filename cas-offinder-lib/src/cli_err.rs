@@ -1,7 +1,11 @@
+use opencl3::error_codes::ClError;
+use std::any::Any;
+use std::fmt;
 use std::io;
 use std::num;
 use std::string;
 use std::sync::mpsc::SendError;
+use std::thread::{JoinHandle, ScopedJoinHandle};
 
 #[derive(Debug)]
 pub enum CliError {
@@ -11,6 +15,10 @@ pub enum CliError {
     FromUtf8Error(string::FromUtf8Error),
     SendError(String),
     ArgumentError(&'static str),
+    // OpenCL runtime, device and kernel build errors
+    OclError(String),
+    // a worker thread panicked, with the panic message
+    ThreadPanic(String),
 }
 pub type Result<T> = std::result::Result<T, CliError>;
 
@@ -36,3 +44,90 @@ impl<T> From<SendError<T>> for CliError {
         CliError::SendError(error.to_string())
     }
 }
+impl From<ClError> for CliError {
+    fn from(error: ClError) -> Self {
+        CliError::OclError(error.to_string())
+    }
+}
+impl<T> From<crossbeam_channel::SendError<T>> for CliError {
+    fn from(error: crossbeam_channel::SendError<T>) -> Self {
+        CliError::SendError(error.to_string())
+    }
+}
+
+impl fmt::Display for CliError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CliError::IoError(err) => write!(f, "{}", err),
+            CliError::BadFileFormat(msg) => write!(f, "Bad file format: {}", msg),
+            CliError::ParseIntError(err) => write!(f, "Invalid number: {}", err),
+            CliError::FromUtf8Error(err) => write!(f, "Invalid text: {}", err),
+            CliError::SendError(msg) => write!(f, "Pipeline stopped early: {}", msg),
+            CliError::ArgumentError(msg) => write!(f, "{}", msg),
+            CliError::OclError(msg) => write!(f, "OpenCL error: {}", msg),
+            CliError::ThreadPanic(msg) => write!(f, "Worker thread panicked: {}", msg),
+        }
+    }
+}
+impl std::error::Error for CliError {}
+
+fn panic_message(payload: Box<dyn Any + Send>) -> String {
+    if let Some(msg) = payload.downcast_ref::<&str>() {
+        msg.to_string()
+    } else if let Some(msg) = payload.downcast_ref::<String>() {
+        msg.clone()
+    } else {
+        "unknown panic".to_string()
+    }
+}
+
+pub fn join_worker<T>(handle: JoinHandle<Result<T>>) -> Result<T> {
+    handle
+        .join()
+        .map_err(|payload| CliError::ThreadPanic(panic_message(payload)))?
+}
+pub fn join_scoped_worker<T>(handle: ScopedJoinHandle<'_, Result<T>>) -> Result<T> {
+    handle
+        .join()
+        .map_err(|payload| CliError::ThreadPanic(panic_message(payload)))?
+}
+
+pub fn first_error(results: impl IntoIterator<Item = Result<()>>) -> Result<()> {
+    /* when one stage of a pipeline fails, the stages next to it fail with SendErrors,
+    so the error of the stage that failed first is the one that is not a SendError */
+    let errors: Vec<CliError> = results.into_iter().filter_map(|r| r.err()).collect();
+    match errors
+        .iter()
+        .position(|err| !matches!(err, CliError::SendError(_)))
+    {
+        Some(idx) => Err(errors.into_iter().nth(idx).unwrap()),
+        None => errors.into_iter().next().map_or(Ok(()), Err),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    #[test]
+    fn test_first_error() {
+        assert!(first_error(vec![Ok(()), Ok(())]).is_ok());
+        let err = first_error(vec![
+            Err(CliError::SendError("closed".to_string())),
+            Err(CliError::BadFileFormat("bad")),
+            Ok(()),
+        ]);
+        assert!(matches!(err, Err(CliError::BadFileFormat("bad"))));
+        let err = first_error(vec![Ok(()), Err(CliError::SendError("closed".to_string()))]);
+        assert!(matches!(err, Err(CliError::SendError(_))));
+    }
+    #[test]
+    fn test_join_worker() {
+        let ok_handle = thread::spawn(|| -> Result<u32> { Ok(3) });
+        assert_eq!(join_worker(ok_handle).unwrap(), 3);
+        let panic_handle = thread::spawn(|| -> Result<u32> { panic!("worker failed") });
+        let err = join_worker(panic_handle).unwrap_err();
+        assert_eq!(err.to_string(), "Worker thread panicked: worker failed");
+    }
+}
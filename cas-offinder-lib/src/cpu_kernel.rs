@@ -0,0 +1,228 @@
+#[cfg(target_arch = "x86_64")]
+use std::arch::x86_64::*;
+
+/*
+Match counting of the Rust CPU backend. The genome is searched as u64 blocks of
+16 nucleotides, and every step compares one pattern against the genome at
+BLOCKS_PER_EXEC positions, one block apart. Matching nucleotides share a bit,
+so the match count is the popcount of genome & pattern.
+
+The search filter is checked first, so only positions passing it are scored.
+A filter nucleotide passes if it shares a bit with the genome, nucleotides
+without any bits (bulges and padding) always pass. An N in the filter only
+rejects unknown bases, so positions are picked by the selective nucleotides
+(e.g. the RG of NRG), 64 positions at a time, and only the hits are checked
+against the whole filter.
+
+The scalar version runs everywhere. On x86_64 the four positions fit a 256 bit
+register, counted with AVX-512 VPOPCNTDQ or with the AVX2 nibble lookup.
+*/
+
+pub const BLOCKS_PER_EXEC: usize = 4;
+// byte counters of the AVX2 version hold at most 8 matches per block
+pub const MAX_SIMD_PATTERN_BLOCKS: usize = 31;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CpuKernel {
+    Scalar,
+    Avx2,
+    Avx512,
+}
+
+impl CpuKernel {
+    // fastest kernel the processor supports
+    pub fn detect() -> CpuKernel {
+        [CpuKernel::Avx512, CpuKernel::Avx2]
+            .into_iter()
+            .find(|kernel| kernel.is_supported())
+            .unwrap_or(CpuKernel::Scalar)
+    }
+    pub fn is_supported(&self) -> bool {
+        match self {
+            CpuKernel::Scalar => true,
+            #[cfg(target_arch = "x86_64")]
+            CpuKernel::Avx2 => is_x86_feature_detected!("avx2"),
+            #[cfg(target_arch = "x86_64")]
+            CpuKernel::Avx512 => {
+                is_x86_feature_detected!("avx2")
+                    && is_x86_feature_detected!("avx512vl")
+                    && is_x86_feature_detected!("avx512vpopcntdq")
+            }
+            #[cfg(not(target_arch = "x86_64"))]
+            _ => false,
+        }
+    }
+    pub fn name(&self) -> &'static str {
+        match self {
+            CpuKernel::Scalar => "scalar",
+            CpuKernel::Avx2 => "AVX2",
+            CpuKernel::Avx512 => "AVX-512",
+        }
+    }
+}
+
+// counts of the BLOCKS_PER_EXEC positions, if any of them reaches min_matches
+pub type BlockCounts = Option<[u32; BLOCKS_PER_EXEC]>;
+
+const NIBBLE_LOW_BITS: u64 = 0x1111_1111_1111_1111;
+
+// lowest bit of every nibble that has any bit set
+pub fn nonzero_nibbles(block: u64) -> u64 {
+    let folded = block | (block >> 1);
+    (folded | (folded >> 2)) & NIBBLE_LOW_BITS
+}
+
+// bit n is set if nucleotide n of the block shares a bit with filter_nucl
+pub fn nucl_match_mask(block: u64, filter_nucl: u64) -> u64 {
+    let mut bits = nonzero_nibbles(block & (filter_nucl * NIBBLE_LOW_BITS));
+    // packs the lowest nibble bits into the low 16 bits
+    bits = (bits | (bits >> 3)) & 0x0303_0303_0303_0303;
+    bits = (bits | (bits >> 6)) & 0x000f_000f_000f_000f;
+    bits = (bits | (bits >> 12)) & 0x0000_00ff_0000_00ff;
+    (bits | (bits >> 24)) & 0xffff
+}
+
+// bit o is set if the genome at position o passes the filter, filter_care holds the
+// nonzero_nibbles of the filter blocks
+#[inline(always)]
+pub fn filter_lanes_scalar(window: &[u64], filter: &[u64], filter_care: &[u64]) -> u32 {
+    let mut lanes = 0;
+    for o in 0..BLOCKS_PER_EXEC {
+        let passes = filter
+            .iter()
+            .zip(filter_care)
+            .enumerate()
+            .all(|(k, (f, care))| nonzero_nibbles(window[k + o] & f) & care == *care);
+        lanes |= (passes as u32) << o;
+    }
+    lanes
+}
+
+#[inline(always)]
+pub fn count_matches_scalar(window: &[u64], pattern: &[u64], min_matches: u32) -> BlockCounts {
+    let mut num_matches = [0_u32; BLOCKS_PER_EXEC];
+    for (k, pattern_block) in pattern.iter().enumerate() {
+        for (o, count) in num_matches.iter_mut().enumerate() {
+            *count += (window[k + o] & pattern_block).count_ones();
+        }
+    }
+    num_matches
+        .iter()
+        .any(|count| *count >= min_matches)
+        .then_some(num_matches)
+}
+
+#[cfg(target_arch = "x86_64")]
+#[target_feature(enable = "avx2")]
+#[inline]
+fn reaching_counts(counts: __m256i, min_matches: u32) -> BlockCounts {
+    let threshold = _mm256_set1_epi64x(min_matches as i64 - 1);
+    let reached = _mm256_cmpgt_epi64(counts, threshold);
+    if _mm256_movemask_epi8(reached) == 0 {
+        return None;
+    }
+    let mut lanes = [0_u64; BLOCKS_PER_EXEC];
+    unsafe { _mm256_storeu_si256(lanes.as_mut_ptr() as *mut __m256i, counts) };
+    Some(lanes.map(|count| count as u32))
+}
+
+#[cfg(target_arch = "x86_64")]
+#[target_feature(enable = "avx2")]
+#[inline]
+fn load_window(window: &[u64], k: usize) -> __m256i {
+    assert!(window.len() >= k + BLOCKS_PER_EXEC);
+    unsafe { _mm256_loadu_si256(window[k..].as_ptr() as *const __m256i) }
+}
+
+#[cfg(target_arch = "x86_64")]
+#[target_feature(enable = "avx2")]
+#[inline]
+pub fn count_matches_avx2(window: &[u64], pattern: &[u64], min_matches: u32) -> BlockCounts {
+    // popcount of every nibble by table lookup, summed per byte and then per lane
+    let nibble_counts = _mm256_setr_epi8(
+        0, 1, 1, 2, 1, 2, 2, 3, 1, 2, 2, 3, 2, 3, 3, 4, 0, 1, 1, 2, 1, 2, 2, 3, 1, 2, 2, 3, 2, 3,
+        3, 4,
+    );
+    let low_nibbles = _mm256_set1_epi8(0x0f);
+    let mut byte_counts = _mm256_setzero_si256();
+    for (k, pattern_block) in pattern.iter().enumerate() {
+        let bits = _mm256_and_si256(
+            load_window(window, k),
+            _mm256_set1_epi64x(*pattern_block as i64),
+        );
+        let low = _mm256_shuffle_epi8(nibble_counts, _mm256_and_si256(bits, low_nibbles));
+        let high = _mm256_shuffle_epi8(
+            nibble_counts,
+            _mm256_and_si256(_mm256_srli_epi16(bits, 4), low_nibbles),
+        );
+        byte_counts = _mm256_add_epi8(byte_counts, _mm256_add_epi8(low, high));
+    }
+    let counts = _mm256_sad_epu8(byte_counts, _mm256_setzero_si256());
+    reaching_counts(counts, min_matches)
+}
+
+#[cfg(target_arch = "x86_64")]
+#[target_feature(enable = "avx2,avx512vl,avx512vpopcntdq")]
+#[inline]
+pub fn count_matches_avx512(window: &[u64], pattern: &[u64], min_matches: u32) -> BlockCounts {
+    let mut counts = _mm256_setzero_si256();
+    for (k, pattern_block) in pattern.iter().enumerate() {
+        let bits = _mm256_and_si256(
+            load_window(window, k),
+            _mm256_set1_epi64x(*pattern_block as i64),
+        );
+        counts = _mm256_add_epi64(counts, _mm256_popcnt_epi64(bits));
+    }
+    reaching_counts(counts, min_matches)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_kernels_agree() {
+        // xorshift, so the test does not need a rng crate
+        let mut state = 0x2545f4914f6cdd1d_u64;
+        let mut next = || {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            state
+        };
+        let window: Vec<u64> = (0..BLOCKS_PER_EXEC + 3).map(|_| next()).collect();
+        let pattern: Vec<u64> = (0..3).map(|_| next()).collect();
+        let counts = count_matches_scalar(&window, &pattern, 0).unwrap();
+        assert_eq!(count_matches_scalar(&window, &pattern, 200), None);
+        // an N and a T per block, the other nucleotides are don't cares
+        let filter: Vec<u64> = vec![0x1f; pattern.len()];
+        let filter_care: Vec<u64> = filter.iter().map(|f| nonzero_nibbles(*f)).collect();
+        assert_eq!(nucl_match_mask(0x8000_0000_0000_0c4f, 0xc), 0x8007);
+        let lanes = filter_lanes_scalar(&window, &filter, &filter_care);
+        for o in 0..BLOCKS_PER_EXEC {
+            let expected = (0..filter.len()).all(|k| {
+                (0..16).all(|nucl| {
+                    let f = (filter[k] >> (4 * nucl)) & 0xf;
+                    f == 0 || (window[k + o] >> (4 * nucl)) & f != 0
+                })
+            });
+            assert_eq!(lanes & (1 << o) != 0, expected);
+        }
+        #[cfg(target_arch = "x86_64")]
+        for kernel in [CpuKernel::Avx2, CpuKernel::Avx512] {
+            if !kernel.is_supported() {
+                continue;
+            }
+            let simd_counts = |min_matches| unsafe {
+                match kernel {
+                    CpuKernel::Avx2 => count_matches_avx2(&window, &pattern, min_matches),
+                    _ => count_matches_avx512(&window, &pattern, min_matches),
+                }
+            };
+            assert_eq!(simd_counts(0), Some(counts));
+            let max_count = *counts.iter().max().unwrap();
+            assert_eq!(simd_counts(max_count), Some(counts));
+            assert_eq!(simd_counts(max_count + 1), None);
+        }
+    }
+}
@@ -0,0 +1,872 @@
+use crate::bulge::{expand_bulges, PatternVariant};
+use crate::chrom_chunk::{ChromChunkInfo, Match};
+use crate::chrom_filter::ChromFilter;
+use crate::cli_err::{first_error, join_scoped_worker, join_worker, CliError, Result};
+use crate::search::variant_match;
+use crate::searcher::{encode_patterns, read_genome};
+use crate::{
+    cdiv, getmaskbit, is_mixedbase_str, reverse_compliment_char, roundup, setmaskbits,
+    string_to_bit4,
+};
+use memmap2::Mmap;
+use std::fs::File;
+use std::io::Write;
+use std::ops::{Deref, Range};
+use std::path::Path;
+use std::sync::mpsc;
+use std::thread;
+
+/*
+On-disk seed index of a genome for one search filter, e.g. NNNNNNNNNNNNNNNNNNNNNRG.
+
+The index holds the 4 bit genome with its soft-mask and, for either strand, every
+site where the search filter passes. The N positions of the filter are split into
+max_mismatches + 1 seeds, and the sites are listed once per seed, grouped by the
+genome bases under the seed. By the pigeonhole principle a hit with m mismatches
+matches at least max_mismatches + 1 - m of the seeds exactly, so a guide only needs
+to look up its own seed sequences. The candidate sites are verified like the scan
+does, by the popcount of genome & guide, so both find the same hits.
+
+Building the index reads the genome once, after that every search only touches the
+candidate sites of its guides. Sites are stored relative to their chromosome, as u32.
+
+An index is kept in the layout of its file. A read index maps the file into memory,
+so loading it is instant and a search only pages in the seed tables of its guides'
+seeds and the genome around the candidate sites. Built indexes hold the same bytes
+in memory.
+*/
+
+const INDEX_MAGIC: &[u8; 8] = b"COFIDX02";
+// seeds longer than this are keyed by their first MAX_SEED_LEN nucleotides, which keeps
+// the offset table of a seed at 4^MAX_SEED_LEN entries
+const MAX_SEED_LEN: usize = 10;
+// degenerate guides with more seed sequences than this are verified at every site
+const MAX_SEED_EXPANSIONS: usize = 1 << 12;
+// verified hits are sent on in batches of this many
+const HITS_PER_SEND: usize = 1 << 12;
+
+struct IndexedChrom {
+    name: String,
+    // first nucleotide of the chromosome in the index genome, a multiple of 8
+    start: usize,
+    len: usize,
+}
+
+// the bytes of an index file
+enum IndexData {
+    Built(Vec<u8>),
+    // the file must not change while it is mapped
+    Mapped(Mmap),
+}
+impl Deref for IndexData {
+    type Target = [u8];
+    fn deref(&self) -> &[u8] {
+        match self {
+            IndexData::Built(bytes) => bytes,
+            IndexData::Mapped(map) => map,
+        }
+    }
+}
+
+// a length prefixed array of little-endian u32 values in the index data
+struct U32Array {
+    start: usize,
+    len: usize,
+}
+impl U32Array {
+    fn get(&self, data: &[u8], idx: usize) -> u32 {
+        let at = self.start + 4 * idx;
+        u32::from_le_bytes(data[at..at + 4].try_into().unwrap())
+    }
+}
+
+// sites of one strand, in genome orientation
+struct StrandSites {
+    // filter positions of every seed
+    seeds: Vec<Vec<usize>>,
+    // index of the first site of every chromosome, followed by the number of sites
+    chrom_sites: Vec<u32>,
+    // positions where the filter passes relative to the start of their chromosome, in
+    // genome order
+    sites: U32Array,
+    // per seed, indices into sites grouped by the bases under the seed, the sites with
+    // key k are seed_sites[seed_offsets[k]..seed_offsets[k + 1]]. Sites with an unknown
+    // base in the seed can not match it and are left out
+    seed_sites: Vec<U32Array>,
+    seed_offsets: Vec<U32Array>,
+}
+
+// the sites of a strand while the index is built, laid out like StrandSites
+struct BuiltStrand {
+    chrom_sites: Vec<u32>,
+    sites: Vec<u32>,
+    seed_sites: Vec<Vec<u32>>,
+    seed_offsets: Vec<Vec<u32>>,
+}
+
+pub struct GenomeIndex {
+    search_filter: Vec<u8>,
+    max_mismatches: u32,
+    chroms: Vec<IndexedChrom>,
+    data: IndexData,
+    // byte ranges of the 4 bit genome and its soft-mask in data
+    genome: Range<usize>,
+    mask: Range<usize>,
+    strands: [StrandSites; 2],
+}
+
+fn nucl(genome: &[u8], idx: usize) -> u8 {
+    (genome[idx / 2] >> (4 * (idx % 2))) & 0xf
+}
+
+fn filter_nucls(filter: &[u8]) -> Vec<u8> {
+    let mut filter_4bit = vec![0_u8; cdiv(filter.len(), 2)];
+    string_to_bit4(&mut filter_4bit, filter, 0, true);
+    (0..filter.len()).map(|i| nucl(&filter_4bit, i)).collect()
+}
+
+fn strand_seeds(filter: &[u8], max_mismatches: u32) -> Result<Vec<Vec<usize>>> {
+    // seeds only cover the N positions of the filter, where the guide decides the match
+    let open: Vec<usize> = (0..filter.len()).filter(|i| filter[*i] == 0xf).collect();
+    let n_seeds = max_mismatches as usize + 1;
+    if open.len() < n_seeds {
+        return Err(CliError::ArgumentError(
+            "The search filter has too few N positions for this many mismatches",
+        ));
+    }
+    Ok((0..n_seeds)
+        .map(|j| {
+            let seed = &open[j * open.len() / n_seeds..(j + 1) * open.len() / n_seeds];
+            seed[..seed.len().min(MAX_SEED_LEN)].to_vec()
+        })
+        .collect())
+}
+
+// the bases under the seed as 2 bit key, None if one of them is unknown
+fn seed_key(genome: &[u8], site: usize, seed: &[usize]) -> Option<usize> {
+    seed.iter()
+        .try_fold(0_usize, |key, i| match nucl(genome, site + i) {
+            0 => None,
+            base => Some((key << 2) | base.trailing_zeros() as usize),
+        })
+}
+
+// every key a degenerate guide can match the seed with, None if there are too many
+fn guide_seed_keys(pattern: &[u8], seed: &[usize]) -> Option<Vec<usize>> {
+    let mut keys: Vec<usize> = vec![0];
+    for i in seed.iter() {
+        let bases = nucl(pattern, *i);
+        if keys.len() * bases.count_ones() as usize > MAX_SEED_EXPANSIONS {
+            return None;
+        }
+        keys = keys
+            .iter()
+            .flat_map(|key| {
+                (0..4)
+                    .filter(move |b| bases & (1 << b) != 0)
+                    .map(move |b| (key << 2) | b)
+            })
+            .collect();
+    }
+    Some(keys)
+}
+
+impl BuiltStrand {
+    fn find(
+        genome: &[u8],
+        chroms: &[IndexedChrom],
+        filter: &[u8],
+        max_mismatches: u32,
+    ) -> Result<BuiltStrand> {
+        let seeds = strand_seeds(filter, max_mismatches)?;
+        // selective positions first, as they reject most sites
+        let mut checked: Vec<usize> = (0..filter.len()).filter(|i| filter[*i] != 0).collect();
+        checked.sort_by_key(|i| filter[*i] == 0xf);
+        let mut chrom_sites: Vec<u32> = Vec::with_capacity(chroms.len() + 1);
+        let mut sites: Vec<u32> = Vec::new();
+        for chrom in chroms.iter() {
+            chrom_sites.push(sites.len() as u32);
+            if chrom.len < filter.len() {
+                continue;
+            }
+            if chrom.len > u32::MAX as usize {
+                return Err(CliError::ArgumentError(
+                    "Chromosomes of an index need to be shorter than 4 Gb",
+                ));
+            }
+            for site in 0..=chrom.len - filter.len() {
+                if checked
+                    .iter()
+                    .all(|i| nucl(genome, chrom.start + site + i) & filter[*i] != 0)
+                {
+                    sites.push(site as u32);
+                }
+            }
+            if sites.len() > u32::MAX as usize {
+                return Err(CliError::ArgumentError(
+                    "Too many search filter sites for an index",
+                ));
+            }
+        }
+        chrom_sites.push(sites.len() as u32);
+        // the position of every site in the index genome
+        let genome_sites: Vec<usize> = chroms
+            .iter()
+            .zip(chrom_sites.windows(2))
+            .flat_map(|(chrom, range)| {
+                sites[range[0] as usize..range[1] as usize]
+                    .iter()
+                    .map(move |site| chrom.start + *site as usize)
+            })
+            .collect();
+        // a counting sort of the sites by seed key
+        let (seed_sites, seed_offsets) = seeds
+            .iter()
+            .map(|seed| {
+                let mut offsets = vec![0_u32; (1 << (2 * seed.len())) + 1];
+                for site in genome_sites.iter() {
+                    if let Some(key) = seed_key(genome, *site, seed) {
+                        offsets[key + 1] += 1;
+                    }
+                }
+                for key in 1..offsets.len() {
+                    offsets[key] += offsets[key - 1];
+                }
+                let mut next = offsets.clone();
+                let mut grouped = vec![0_u32; *offsets.last().unwrap() as usize];
+                for (k, site) in genome_sites.iter().enumerate() {
+                    if let Some(key) = seed_key(genome, *site, seed) {
+                        grouped[next[key] as usize] = k as u32;
+                        next[key] += 1;
+                    }
+                }
+                (grouped, offsets)
+            })
+            .unzip();
+        Ok(BuiltStrand {
+            chrom_sites,
+            sites,
+            seed_sites,
+            seed_offsets,
+        })
+    }
+    fn write(&self, out: &mut impl Write) -> Result<()> {
+        write_u32s(out, &self.chrom_sites)?;
+        write_u32s(out, &self.sites)?;
+        for (seed_sites, offsets) in self.seed_sites.iter().zip(self.seed_offsets.iter()) {
+            write_u32s(out, seed_sites)?;
+            write_u32s(out, offsets)?;
+        }
+        Ok(())
+    }
+}
+
+impl StrandSites {
+    fn n_sites(&self) -> usize {
+        self.sites.len
+    }
+    // indices of the sites matching at least min_seed_hits of the seeds, in genome order
+    fn candidates(&self, data: &[u8], pattern: &[u8], min_seed_hits: usize) -> Result<Vec<u32>> {
+        let mut min_hits = min_seed_hits;
+        let mut candidates: Vec<u32> = Vec::new();
+        for ((seed, seed_sites), offsets) in self
+            .seeds
+            .iter()
+            .zip(self.seed_sites.iter())
+            .zip(self.seed_offsets.iter())
+        {
+            let Some(keys) = guide_seed_keys(pattern, seed) else {
+                // every site counts as matching this seed
+                min_hits -= 1;
+                if min_hits == 0 {
+                    return Ok((0..self.n_sites() as u32).collect());
+                }
+                continue;
+            };
+            for key in keys {
+                let (first, end) = (offsets.get(data, key), offsets.get(data, key + 1));
+                candidates.extend((first..end).map(|i| seed_sites.get(data, i as usize)));
+            }
+        }
+        candidates.sort_unstable();
+        // a site is listed once for every seed it matches
+        let mut kept: Vec<u32> = Vec::new();
+        let mut run_start = 0;
+        for idx in 1..=candidates.len() {
+            if idx == candidates.len() || candidates[idx] != candidates[run_start] {
+                if idx - run_start >= min_hits {
+                    kept.push(candidates[run_start]);
+                }
+                run_start = idx;
+            }
+        }
+        // the seed tables of a read index are only checked as they are used
+        if kept.last().is_some_and(|k| *k as usize >= self.n_sites()) {
+            return Err(CliError::BadFileFormat("index seed table is broken"));
+        }
+        Ok(kept)
+    }
+}
+
+// up to 16 nucleotides of 4 bit data from nucleotide start on, as one word
+fn nucl_block(data: &[u8], start: usize, n_nucl: usize) -> u64 {
+    let mut bytes = [0_u8; 16];
+    let first = start / 2;
+    let n_bytes = (data.len() - first).min(9);
+    bytes[..n_bytes].copy_from_slice(&data[first..first + n_bytes]);
+    let block = (u128::from_le_bytes(bytes) >> (4 * (start % 2))) as u64;
+    if n_nucl < 16 {
+        block & ((1 << (4 * n_nucl)) - 1)
+    } else {
+        block
+    }
+}
+fn mismatches_at(genome: &[u8], site: usize, pattern: &[u8], pattern_len: usize) -> u32 {
+    let matches: u32 = (0..pattern_len)
+        .step_by(16)
+        .map(|i| {
+            let n_nucl = (pattern_len - i).min(16);
+            (nucl_block(genome, site + i, n_nucl) & nucl_block(pattern, i, n_nucl)).count_ones()
+        })
+        .sum();
+    pattern_len as u32 - matches
+}
+
+fn write_u64(out: &mut impl Write, value: u64) -> Result<()> {
+    out.write_all(&value.to_le_bytes())?;
+    Ok(())
+}
+fn write_bytes(out: &mut impl Write, bytes: &[u8]) -> Result<()> {
+    write_u64(out, bytes.len() as u64)?;
+    out.write_all(bytes)?;
+    Ok(())
+}
+fn write_u32s(out: &mut impl Write, values: &[u32]) -> Result<()> {
+    write_u64(out, values.len() as u64)?;
+    for value in values.iter() {
+        out.write_all(&value.to_le_bytes())?;
+    }
+    Ok(())
+}
+
+// walks through index data, every length is checked against the end of the data
+struct IndexReader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+impl IndexReader<'_> {
+    fn take(&mut self, len: usize) -> Result<Range<usize>> {
+        let end = self
+            .pos
+            .checked_add(len)
+            .filter(|end| *end <= self.data.len())
+            .ok_or(CliError::BadFileFormat("index file is truncated"))?;
+        let range = self.pos..end;
+        self.pos = end;
+        Ok(range)
+    }
+    fn u64(&mut self) -> Result<u64> {
+        let range = self.take(8)?;
+        Ok(u64::from_le_bytes(self.data[range].try_into().unwrap()))
+    }
+    fn bytes(&mut self) -> Result<Range<usize>> {
+        let len = self.u64()? as usize;
+        self.take(len)
+    }
+    fn u32s(&mut self) -> Result<U32Array> {
+        let len = self.u64()? as usize;
+        let n_bytes = len
+            .checked_mul(4)
+            .ok_or(CliError::BadFileFormat("index file is truncated"))?;
+        let range = self.take(n_bytes)?;
+        Ok(U32Array {
+            start: range.start,
+            len,
+        })
+    }
+}
+
+impl GenomeIndex {
+    pub fn build(
+        genome_path: &Path,
+        search_filter: &[u8],
+        max_mismatches: u32,
+    ) -> Result<GenomeIndex> {
+        let (sender, receiver) = mpsc::sync_channel(4);
+        let genome_path = genome_path.to_path_buf();
+        let reader_thread =
+            thread::spawn(move || read_genome(&sender, &genome_path, &ChromFilter::default()));
+        let index_res = GenomeIndex::from_chunks(receiver, search_filter, max_mismatches);
+        let reader_res = join_worker(reader_thread);
+        match index_res {
+            Ok(index) => reader_res.map(|_| index),
+            // a failing reader makes the index fail as well, its error is the one reported
+            Err(err) => Err(first_error([reader_res, Err(err)]).unwrap_err()),
+        }
+    }
+    pub fn from_chunks(
+        chunks: impl IntoIterator<Item = ChromChunkInfo>,
+        search_filter: &[u8],
+        max_mismatches: u32,
+    ) -> Result<GenomeIndex> {
+        if search_filter.is_empty() || !is_mixedbase_str(search_filter) {
+            return Err(CliError::ArgumentError(
+                "Search filter needs to be a mixed base string",
+            ));
+        }
+        let mut chroms: Vec<IndexedChrom> = Vec::new();
+        let mut genome: Vec<u8> = Vec::new();
+        let mut mask: Vec<u8> = Vec::new();
+        for chunk in chunks {
+            let is_new_chrom = chroms.last().map(|c| &c.name) != Some(&chunk.chr_name);
+            if is_new_chrom {
+                // chromosomes start at whole mask bytes
+                let start = chroms.last().map_or(0, |c| roundup(c.start + c.len, 8));
+                chroms.push(IndexedChrom {
+                    name: chunk.chr_name.clone(),
+                    start,
+                    len: 0,
+                });
+            }
+            let chrom = chroms.last_mut().unwrap();
+            if chunk.chunk_start != chrom.len as u64 {
+                return Err(CliError::BadFileFormat(
+                    "an index needs the chromosome chunks in order",
+                ));
+            }
+            let dest = chrom.start + chrom.len;
+            let n_nucl = chunk.size();
+            genome.resize(cdiv(dest + n_nucl, 2), 0);
+            mask.resize(cdiv(dest + n_nucl, 8), 0);
+            if dest.is_multiple_of(8) {
+                genome[dest / 2..][..cdiv(n_nucl, 2)]
+                    .copy_from_slice(&chunk.data[..cdiv(n_nucl, 2)]);
+                mask[dest / 8..][..cdiv(n_nucl, 8)].copy_from_slice(&chunk.mask[..cdiv(n_nucl, 8)]);
+                // chunk buffers are not cleared past the chunk end
+                if n_nucl % 2 != 0 {
+                    *genome.last_mut().unwrap() &= 0xf;
+                }
+                if n_nucl % 8 != 0 {
+                    *mask.last_mut().unwrap() &= (1 << (n_nucl % 8)) - 1;
+                }
+            } else {
+                // a chunk after a partial one lands mid byte
+                for i in 0..n_nucl {
+                    genome[(dest + i) / 2] |= nucl(&chunk.data[..], i) << (4 * ((dest + i) % 2));
+                    if getmaskbit(&chunk.mask[..], i) {
+                        setmaskbits(&mut mask, dest + i, dest + i + 1);
+                    }
+                }
+            }
+            chrom.len += n_nucl;
+        }
+        let mut data: Vec<u8> = Vec::new();
+        data.write_all(INDEX_MAGIC)?;
+        write_u64(&mut data, max_mismatches as u64)?;
+        write_bytes(&mut data, search_filter)?;
+        write_u64(&mut data, chroms.len() as u64)?;
+        for chrom in chroms.iter() {
+            write_bytes(&mut data, chrom.name.as_bytes())?;
+            write_u64(&mut data, chrom.start as u64)?;
+            write_u64(&mut data, chrom.len as u64)?;
+        }
+        write_bytes(&mut data, &genome)?;
+        write_bytes(&mut data, &mask)?;
+        // filters and seeds follow from the search filter, only the sites are stored
+        for filter in [
+            filter_nucls(search_filter),
+            filter_nucls(&reverse_compliment_char(search_filter)),
+        ] {
+            BuiltStrand::find(&genome, &chroms, &filter, max_mismatches)?.write(&mut data)?;
+        }
+        GenomeIndex::parse(IndexData::Built(data))
+    }
+    pub fn search_filter(&self) -> &[u8] {
+        &self.search_filter
+    }
+    // highest mismatch threshold the index can search for
+    pub fn max_mismatches(&self) -> u32 {
+        self.max_mismatches
+    }
+    pub fn n_sites(&self) -> usize {
+        self.strands.iter().map(|s| s.n_sites()).sum()
+    }
+    // names and lengths of the indexed chromosomes
+    pub fn chroms(&self) -> impl Iterator<Item = (&str, u64)> {
+        self.chroms.iter().map(|c| (c.name.as_str(), c.len as u64))
+    }
+    pub fn write(&self, path: &Path) -> Result<()> {
+        File::create(path)?.write_all(&self.data)?;
+        Ok(())
+    }
+    // maps the index file into memory, see the module comment
+    pub fn read(path: &Path) -> Result<GenomeIndex> {
+        let file = File::open(path)?;
+        // the index file is not expected to change while it is searched
+        let map = unsafe { Mmap::map(&file)? };
+        GenomeIndex::parse(IndexData::Mapped(map))
+    }
+    fn parse(data: IndexData) -> Result<GenomeIndex> {
+        let mut src = IndexReader {
+            data: &data,
+            pos: 0,
+        };
+        if data[src.take(INDEX_MAGIC.len())?] != INDEX_MAGIC[..] {
+            return Err(CliError::BadFileFormat("not a cas-offinder index file"));
+        }
+        let max_mismatches = src.u64()? as u32;
+        let search_filter = data[src.bytes()?].to_vec();
+        if search_filter.is_empty() || !is_mixedbase_str(&search_filter) {
+            return Err(CliError::BadFileFormat("index has a broken search filter"));
+        }
+        let n_chroms = src.u64()?;
+        let chroms = (0..n_chroms)
+            .map(|_| {
+                Ok(IndexedChrom {
+                    name: String::from_utf8(data[src.bytes()?].to_vec())?,
+                    start: src.u64()? as usize,
+                    len: src.u64()? as usize,
+                })
+            })
+            .collect::<Result<Vec<IndexedChrom>>>()?;
+        // chromosomes follow each other at whole mask bytes, as from_chunks lays them out
+        let mut genome_len: usize = 0;
+        for chrom in chroms.iter() {
+            if chrom.start < genome_len || !chrom.start.is_multiple_of(8) {
+                return Err(CliError::BadFileFormat(
+                    "index chromosomes overlap or are out of order",
+                ));
+            }
+            genome_len = chrom
+                .start
+                .checked_add(chrom.len)
+                .ok_or(CliError::BadFileFormat("index chromosome is too long"))?;
+        }
+        let genome = src.bytes()?;
+        let mask = src.bytes()?;
+        if genome.len() != cdiv(genome_len, 2) || mask.len() != cdiv(genome_len, 8) {
+            return Err(CliError::BadFileFormat(
+                "index genome does not fit its chromosomes",
+            ));
+        }
+        // the small tables are checked here, the sites and seed tables when they are used
+        let mut read_strand = |filter: Vec<u8>| -> Result<StrandSites> {
+            let seeds = strand_seeds(&filter, max_mismatches)?;
+            let chrom_site_array = src.u32s()?;
+            let chrom_sites: Vec<u32> = (0..chrom_site_array.len)
+                .map(|i| chrom_site_array.get(&data, i))
+                .collect();
+            let sites = src.u32s()?;
+            if chrom_sites.len() != chroms.len() + 1
+                || chrom_sites[0] != 0
+                || chrom_sites.windows(2).any(|w| w[0] > w[1])
+                || *chrom_sites.last().unwrap() as usize != sites.len
+            {
+                return Err(CliError::BadFileFormat("index site table is broken"));
+            }
+            let mut seed_sites: Vec<U32Array> = Vec::new();
+            let mut seed_offsets: Vec<U32Array> = Vec::new();
+            for seed in seeds.iter() {
+                let grouped = src.u32s()?;
+                let offsets = src.u32s()?;
+                if offsets.len != (1 << (2 * seed.len())) + 1
+                    || offsets.get(&data, 0) != 0
+                    || (1..offsets.len).any(|k| offsets.get(&data, k - 1) > offsets.get(&data, k))
+                    || offsets.get(&data, offsets.len - 1) as usize != grouped.len
+                {
+                    return Err(CliError::BadFileFormat("index seed table is broken"));
+                }
+                seed_sites.push(grouped);
+                seed_offsets.push(offsets);
+            }
+            Ok(StrandSites {
+                seeds,
+                chrom_sites,
+                sites,
+                seed_sites,
+                seed_offsets,
+            })
+        };
+        let strands = [
+            read_strand(filter_nucls(&search_filter))?,
+            read_strand(filter_nucls(&reverse_compliment_char(&search_filter)))?,
+        ];
+        Ok(GenomeIndex {
+            search_filter,
+            max_mismatches,
+            chroms,
+            data,
+            genome,
+            mask,
+            strands,
+        })
+    }
+    // sends the verified hits of one pattern variant, in genome order
+    fn send_variant_hits(
+        &self,
+        patterns_4bit: &[Vec<u8>],
+        variant: &PatternVariant,
+        max_mismatches: &[u32],
+        chrom_filter: &ChromFilter,
+        dest: &mpsc::SyncSender<Vec<Match>>,
+    ) -> Result<()> {
+        let n_patterns = max_mismatches.len();
+        let src_idx = variant.src_idx as usize;
+        let strand = &self.strands[src_idx / n_patterns];
+        let pattern = &patterns_4bit[src_idx];
+        let max_mm = max_mismatches[src_idx % n_patterns];
+        let (genome, mask) = (
+            &self.data[self.genome.clone()],
+            &self.data[self.mask.clone()],
+        );
+        let mut hits: Vec<Match> = Vec::new();
+        let min_seed_hits = strand.seeds.len() - max_mm as usize;
+        for k in strand.candidates(&self.data, pattern, min_seed_hits)? {
+            // candidates are below the site count, the last entry of chrom_sites
+            let chrom_idx = strand.chrom_sites.partition_point(|first| *first <= k) - 1;
+            let chrom = &self.chroms[chrom_idx];
+            let chrom_pos = strand.sites.get(&self.data, k as usize) as usize;
+            if chrom_pos + variant.pattern_len > chrom.len {
+                return Err(CliError::BadFileFormat("index sites are out of range"));
+            }
+            let site = chrom.start + chrom_pos;
+            let mismatches = mismatches_at(genome, site, pattern, variant.pattern_len);
+            if mismatches > max_mm {
+                continue;
+            }
+            if !chrom_filter.is_kept(&chrom.name) {
+                continue;
+            }
+            hits.push(variant_match(
+                patterns_4bit,
+                variant,
+                genome,
+                mask,
+                site,
+                &chrom.name,
+                chrom_pos as u64,
+                mismatches,
+            ));
+            if hits.len() == HITS_PER_SEND {
+                dest.send(std::mem::take(&mut hits))?;
+            }
+        }
+        if !hits.is_empty() {
+            dest.send(hits)?;
+        }
+        Ok(())
+    }
+    pub fn search(
+        &self,
+        patterns: &[Vec<u8>],
+        max_mismatches: &[u32],
+        chrom_filter: &ChromFilter,
+        n_threads: usize,
+        dest: &mpsc::SyncSender<Vec<Match>>,
+    ) -> Result<()> {
+        /* sends the hits of the patterns on both strands to dest as they are verified,
+        pattern_idx counts the reverse complements after the patterns like search() does.
+        Every thread verifies its own patterns, so the hits of different patterns are
+        interleaved. */
+        if patterns.len() != max_mismatches.len() {
+            return Err(CliError::ArgumentError(
+                "Every pattern needs its own mismatch threshold",
+            ));
+        }
+        if patterns.iter().any(|p| p.len() != self.search_filter.len()) {
+            return Err(CliError::ArgumentError(
+                "Patterns need the length of the search filter of the index",
+            ));
+        }
+        if max_mismatches.iter().any(|m| *m > self.max_mismatches) {
+            return Err(CliError::ArgumentError(
+                "The index was built for fewer mismatches than requested",
+            ));
+        }
+        let (pattern_lens, patterns_4bit) = encode_patterns(patterns);
+        let variants = expand_bulges(&patterns_4bit, &pattern_lens, 0, 0);
+        let variants_per_thread = cdiv(variants.len(), n_threads.max(1));
+        thread::scope(|scope| {
+            let workers: Vec<_> = variants
+                .chunks(variants_per_thread)
+                .map(|thread_variants| {
+                    let patterns_4bit = &patterns_4bit;
+                    let dest = dest.clone();
+                    scope.spawn(move || -> Result<()> {
+                        for variant in thread_variants {
+                            self.send_variant_hits(
+                                patterns_4bit,
+                                variant,
+                                max_mismatches,
+                                chrom_filter,
+                                &dest,
+                            )?;
+                        }
+                        Ok(())
+                    })
+                })
+                .collect();
+            first_error(workers.into_iter().map(join_scoped_worker))
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::read_sequences;
+
+    fn random_genome(len: usize, seed: u64) -> Vec<u8> {
+        let mut state = seed;
+        (0..len)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 7;
+                state ^= state << 17;
+                b"ACGTACGTACGTACGTNacgt"[(state % 21) as usize]
+            })
+            .collect()
+    }
+    // all hits of an index search, sorted
+    fn index_hits(
+        index: &GenomeIndex,
+        patterns: &[Vec<u8>],
+        max_mismatches: &[u32],
+    ) -> Result<Vec<Match>> {
+        let (sender, receiver) = mpsc::sync_channel(4);
+        let collect_thread = thread::spawn(move || receiver.iter().flatten().collect());
+        let res = index.search(
+            patterns,
+            max_mismatches,
+            &ChromFilter::default(),
+            2,
+            &sender,
+        );
+        drop(sender);
+        let mut hits: Vec<Match> = collect_thread.join().unwrap();
+        hits.sort_by_key(|m| (m.chr_name.clone(), m.chrom_idx, m.pattern_idx));
+        res.map(|_| hits)
+    }
+    fn sequence_index(sequences: &[(&str, &[u8])], max_mismatches: u32) -> GenomeIndex {
+        let (sender, receiver) = mpsc::sync_channel(1024);
+        read_sequences(&sender, sequences).unwrap();
+        drop(sender);
+        GenomeIndex::from_chunks(receiver, b"NNNNNNNNNNNNNNNNNNNNNRG", max_mismatches).unwrap()
+    }
+
+    #[test]
+    fn test_guide_seed_keys() {
+        let mut pattern = vec![0_u8; 2];
+        string_to_bit4(&mut pattern, b"ARGT", 0, true);
+        assert_eq!(guide_seed_keys(&pattern, &[0, 3]), Some(vec![0b1000]));
+        assert_eq!(
+            guide_seed_keys(&pattern, &[1, 2]),
+            Some(vec![0b1011, 0b1111])
+        );
+        let mut pattern = vec![0_u8; 4];
+        string_to_bit4(&mut pattern, b"NNNNNNN", 0, true);
+        let open: Vec<usize> = (0..7).collect();
+        assert_eq!(guide_seed_keys(&pattern, &open[..6]).unwrap().len(), 4096);
+        assert_eq!(guide_seed_keys(&pattern, &open), None);
+    }
+    #[test]
+    fn test_index_search() {
+        let mut chr1 = random_genome(150_000, 0x9e3779b97f4a7c15);
+        let chr2 = random_genome(70_001, 0x2545f4914f6cdd1d);
+        chr1[5000..5023].copy_from_slice(b"GGCCGACCTGTCGCTGACGCAGG");
+        chr1[9000..9023].copy_from_slice(&reverse_compliment_char(b"GCCGCTGACGCTGACGCAGAAGG"));
+        let sequences: [(&str, &[u8]); 2] = [("chr1", &chr1), ("chr2", &chr2)];
+        let index = sequence_index(&sequences, 4);
+        let patterns = vec![
+            b"GGCCGACCTGTCGCTGACGCNNN".to_vec(),
+            b"GCCGCTGACGCTGACGCAGNNNN".to_vec(),
+            b"ACGTACGTACGTACGTACGTNNN".to_vec(),
+        ];
+        let max_mismatches = vec![4, 3, 4];
+        let key = |m: &Match| {
+            (
+                m.chr_name.clone(),
+                m.chrom_idx,
+                m.pattern_idx,
+                m.mismatches,
+                m.dna_seq.clone(),
+            )
+        };
+        let mut indexed: Vec<_> = index_hits(&index, &patterns, &max_mismatches)
+            .unwrap()
+            .iter()
+            .map(key)
+            .collect();
+        assert!(indexed
+            .iter()
+            .any(|h| h.0 == "chr1" && h.1 == 5000 && h.3 == 0));
+        assert!(indexed
+            .iter()
+            .any(|h| h.0 == "chr1" && h.1 == 9000 && h.2 >= 3));
+
+        let mut searcher = crate::Searcher::from_sequences(&sequences)
+            .search_filter(b"NNNNNNNNNNNNNNNNNNNNNRG")
+            .device_type(crate::OclDeviceType::CPU);
+        for (pattern, max_mm) in patterns.iter().zip(max_mismatches.iter()) {
+            searcher = searcher.guide("guide", pattern, *max_mm);
+        }
+        let mut scanned: Vec<_> = searcher
+            .run()
+            .unwrap()
+            .map(|hit| key(&hit.unwrap().hit))
+            .collect();
+        indexed.sort();
+        scanned.sort();
+        assert_eq!(indexed, scanned);
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("index.cofidx");
+        index.write(&path).unwrap();
+        let reread = GenomeIndex::read(&path).unwrap();
+        assert_eq!(reread.n_sites(), index.n_sites());
+        let mut reread_hits: Vec<_> = index_hits(&reread, &patterns, &max_mismatches)
+            .unwrap()
+            .iter()
+            .map(key)
+            .collect();
+        reread_hits.sort();
+        assert_eq!(reread_hits, indexed);
+        assert!(index_hits(&index, &patterns[..1], &[5]).is_err());
+        assert!(index_hits(&index, &patterns, &[1]).is_err());
+    }
+    #[test]
+    fn test_read_corrupt_index() {
+        let mut chr1 = random_genome(1000, 0x9e3779b97f4a7c15);
+        chr1[..23].copy_from_slice(b"GGCCGACCTGTCGCTGACGCAGG");
+        let chr2 = random_genome(1000, 0x2545f4914f6cdd1d);
+        let index = sequence_index(&[("chr1", &chr1), ("chr2", &chr2)], 2);
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("index.cofidx");
+        index.write(&path).unwrap();
+        let contents = std::fs::read(&path).unwrap();
+        // file offsets of the chromosome starts, behind the header and the name of each
+        let chr1_start = 8 + 8 + 8 + 23 + 8 + 8 + 4;
+        let chr2_start = chr1_start + 16 + 8 + 4;
+        let read_patched = |patches: &[(usize, u64)]| {
+            let mut bytes = contents.clone();
+            for (offset, value) in patches {
+                bytes[*offset..][..8].copy_from_slice(&value.to_le_bytes());
+            }
+            std::fs::write(&path, bytes).unwrap();
+            GenomeIndex::read(&path)
+        };
+        assert!(read_patched(&[]).is_ok());
+        // leaves the site at position 0 outside of its chromosome, found by the search
+        let short_chr1 = read_patched(&[(chr1_start + 8, 10)]).unwrap();
+        let pattern = b"GGCCGACCTGTCGCTGACGCNNN".to_vec();
+        assert!(matches!(
+            index_hits(&short_chr1, &[pattern], &[2]),
+            Err(CliError::BadFileFormat(_))
+        ));
+        drop(short_chr1);
+        assert!(matches!(
+            read_patched(&[(chr2_start, 0)]),
+            Err(CliError::BadFileFormat(_))
+        ));
+    }
+}
@@ -8,7 +8,6 @@
 //BLOCKS_PER_EXEC: defined in search.rs
 //PATTERN_CHUNK_SIZE: defined in search.rs
 //block_ty: defined in search.rs
-//MAX_MISMATCHES
 
 #define cdiv(x,y) (((x) + (y) - 1) / (y))
 #define uint64_t unsigned long
@@ -27,11 +26,23 @@ struct s_match
 };
 typedef struct s_match match;
 
+#define NIBBLE_LOW_BITS ((block_ty)0x1111111111111111UL)
+
+// lowest bit of every nibble that has any bit set
+block_ty nonzero_nibbles(block_ty block)
+{
+    block_ty folded = block | (block >> 1);
+    return (folded | (folded >> 2)) & NIBBLE_LOW_BITS;
+}
+
 __kernel void find_matches(__global block_ty* genome,
                             __global block_ty* pattern_blocks,
-                            uint32_t max_mismatches,
+                            __global block_ty* filter_blocks,
+                            __global uint32_t* max_mismatches,
                             __global match* match_buffer,
-                           __global int* entrycount)
+                           __global int* entrycount,
+                           const uint32_t out_capacity,
+                           const uint32_t pattern_base)
 {
     size_t genome_idx = get_global_id(0) * BLOCKS_PER_EXEC;
     size_t pattern_block_idx = get_global_id(1);
@@ -41,15 +52,37 @@ __kernel void find_matches(__global block_ty* genome,
     }
     // genome is expected to be at least BIGGER than the genome_size
     for (size_t k = 0; k < BLOCKS_AVAIL; k++) {
-        uint32_t counts[BLOCKS_PER_EXEC] = {0};
+        // the search filter is checked first, nucleotides without filter bits always pass
+        uint32_t passes[BLOCKS_PER_EXEC];
+        uint32_t any_passes = 0;
+#pragma unroll
+        for(size_t o = 0; o < BLOCKS_PER_EXEC; o++){
+            passes[o] = 1;
+        }
 #pragma unroll
         for (size_t l = 0; l < BLOCKS_PER_PATTERN; l++) {
+            block_ty filter = filter_blocks[pattern_block_idx * PATTERN_OFFSET + l];
+            block_ty care = nonzero_nibbles(filter);
 #pragma unroll
             for(size_t o = 0; o < BLOCKS_PER_EXEC; o++){
-                counts[o] += popcount(
-                  shifted_blocks[l + o] &
-                  pattern_blocks[pattern_block_idx * PATTERN_OFFSET + l]
-                );
+                passes[o] &= (nonzero_nibbles(shifted_blocks[l + o] & filter) & care) == care;
+            }
+        }
+#pragma unroll
+        for(size_t o = 0; o < BLOCKS_PER_EXEC; o++){
+            any_passes |= passes[o];
+        }
+        uint32_t counts[BLOCKS_PER_EXEC] = {0};
+        if (any_passes) {
+#pragma unroll
+            for (size_t l = 0; l < BLOCKS_PER_PATTERN; l++) {
+#pragma unroll
+                for(size_t o = 0; o < BLOCKS_PER_EXEC; o++){
+                    counts[o] += popcount(
+                      shifted_blocks[l + o] &
+                      pattern_blocks[pattern_block_idx * PATTERN_OFFSET + l]
+                    );
+                }
             }
         }
 #pragma unroll
@@ -59,17 +92,23 @@ __kernel void find_matches(__global block_ty* genome,
               (shifted_blocks[l + 1] << ((BLOCKS_AVAIL - 1) * 4));
         }
         shifted_blocks[BLOCKS_PER_PATTERN + BLOCKS_PER_EXEC - 1] >>= 4;
+        if (!any_passes) {
+            continue;
+        }
 #pragma unroll
         for(size_t o = 0; o < BLOCKS_PER_EXEC; o++){
             int mismatches = PATTERN_LEN - counts[o];
-            if (mismatches <= max_mismatches) {
+            if (passes[o] && mismatches <= max_mismatches[pattern_block_idx]) {
                 int next_idx = atomic_inc(entrycount);
-                match next_item = {
-                    .loc = (genome_idx + o) * BLOCKS_AVAIL + k,
-                    .pattern_idx = pattern_block_idx,
-                    .mismatches = mismatches,
-                };
-                match_buffer[next_idx] = next_item;
+                // past capacity only the count goes on, so the host can rerun with enough room
+                if ((uint32_t)next_idx < out_capacity) {
+                    match next_item = {
+                        .loc = (genome_idx + o) * BLOCKS_AVAIL + k,
+                        .pattern_idx = pattern_base + pattern_block_idx,
+                        .mismatches = mismatches,
+                    };
+                    match_buffer[next_idx] = next_item;
+                }
             }
         }
     }
@@ -1,17 +1,36 @@
+mod annotation;
 mod bit4ops;
+mod bulge;
+mod cfd;
 mod chrom_chunk;
+mod chrom_filter;
 mod cli_err;
+mod cpu_kernel;
+mod genome_index;
 mod read_2bit;
 mod read_fasta;
 mod read_fasta_folder;
+mod read_sequences;
+mod regions;
 mod run_config;
 mod search;
+mod searcher;
+mod specificity;
 
+pub use crate::annotation::*;
 pub use crate::bit4ops::*;
+pub use crate::cfd::*;
 pub use crate::chrom_chunk::*;
+pub use crate::chrom_filter::*;
 pub use crate::cli_err::*;
+pub use crate::cpu_kernel::CpuKernel;
+pub use crate::genome_index::*;
 pub use crate::read_2bit::*;
 pub use crate::read_fasta::*;
 pub use crate::read_fasta_folder::*;
+pub use crate::read_sequences::*;
+pub use crate::regions::*;
 pub use crate::run_config::*;
 pub use crate::search::*;
+pub use crate::searcher::*;
+pub use crate::specificity::*;
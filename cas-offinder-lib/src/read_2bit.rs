@@ -1,10 +1,11 @@
 use crate::cdiv;
-use crate::chrom_chunk::{ChromChunkInfo, CHUNK_SIZE, CHUNK_SIZE_BYTES};
+use crate::chrom_chunk::{ChromChunkInfo, CHUNK_MASK_BYTES, CHUNK_SIZE, CHUNK_SIZE_BYTES};
+use crate::chrom_filter::ChromFilter;
 use crate::cli_err::{CliError, Result};
-use crate::{bit2_to_bit4, memsetbit4};
+use crate::{bit2_to_bit4, memsetbit4, setmaskbits};
 use std::cmp::{max, min};
 use std::fs::File;
-use std::io::{BufReader, Read};
+use std::io::{BufReader, Read, Seek, SeekFrom};
 use std::path::Path;
 use std::sync::mpsc::SyncSender;
 
@@ -31,6 +32,13 @@ fn read_intvec(reader: &mut BufReader<std::fs::File>, n_els: usize) -> Result<Ve
     Ok(int_buf)
 }
 pub fn read_2bit(dest: &SyncSender<ChromChunkInfo>, fname: &Path) -> Result<()> {
+    read_2bit_filtered(dest, fname, &ChromFilter::default())
+}
+pub fn read_2bit_filtered(
+    dest: &SyncSender<ChromChunkInfo>,
+    fname: &Path,
+    chrom_filter: &ChromFilter,
+) -> Result<()> {
     let file = File::open(fname)?;
     let buf_capacity = CHUNK_SIZE;
     let mut reader = BufReader::with_capacity(buf_capacity, file);
@@ -49,13 +57,19 @@ pub fn read_2bit(dest: &SyncSender<ChromChunkInfo>, fname: &Path) -> Result<()>
     reader.seek_relative(4)?; // skip reserved bits
 
     let mut chrom_names: Vec<String> = Vec::with_capacity(chrcnt as usize);
+    let mut chrom_offsets: Vec<u32> = Vec::with_capacity(chrcnt as usize);
     for _ in 0..chrcnt {
         let len_chrname = read_u8(&mut reader)?;
         let chromname = read_str(&mut reader, len_chrname as usize)?;
         chrom_names.push(chromname);
-        reader.seek_relative(4)?; // Absolute position of each sequence
+        chrom_offsets.push(read_u32(&mut reader)?); // Absolute position of each sequence
     }
-    for chrname in chrom_names.iter() {
+    for (chrname, offset) in chrom_names.iter().zip(chrom_offsets.iter()) {
+        if !chrom_filter.is_kept(chrname) {
+            continue;
+        }
+        // excluded sequences are never read, so jump straight to the record
+        reader.seek(SeekFrom::Start(*offset as u64))?;
         let chrlen = read_u32(&mut reader)? as usize;
         let nblockcnt = read_u32(&mut reader)? as usize;
 
@@ -68,9 +82,16 @@ pub fn read_2bit(dest: &SyncSender<ChromChunkInfo>, fname: &Path) -> Result<()>
             .collect();
         nblocks.sort_by_key(|(start, _size)| *start);
 
-        let maskblockcnt = read_u32(&mut reader)?;
-        // skip mask infos
-        reader.seek_relative((maskblockcnt * 8 + 4) as i64)?;
+        let maskblockcnt = read_u32(&mut reader)? as usize;
+        let maskblockstart = read_intvec(&mut reader, maskblockcnt)?;
+        let maskblocksizes = read_intvec(&mut reader, maskblockcnt)?;
+        let mut maskblocks: Vec<(u32, u32)> = maskblockstart
+            .iter()
+            .zip(maskblocksizes.iter())
+            .map(|(a1, a2)| (*a1, *a2))
+            .collect();
+        maskblocks.sort_by_key(|(start, _size)| *start);
+        reader.seek_relative(4)?; // skip reserved bits
 
         assert!(CHUNK_SIZE % 4 == 0);
         const NUCL_PER_BYTE: usize = 4;
@@ -78,6 +99,7 @@ pub fn read_2bit(dest: &SyncSender<ChromChunkInfo>, fname: &Path) -> Result<()>
         let mut raw_buf = [0_u8; RAW_BUF_LEN];
         let mut read_pos = 0;
         let mut block_mask_idx: i64 = 0;
+        let mut soft_mask_idx: i64 = 0;
 
         while read_pos < chrlen {
             let read_size = min(chrlen - read_pos, CHUNK_SIZE);
@@ -101,11 +123,28 @@ pub fn read_2bit(dest: &SyncSender<ChromChunkInfo>, fname: &Path) -> Result<()>
                 );
                 block_mask_idx += 1;
             }
+            let mut chrmask = Box::new([0_u8; CHUNK_MASK_BYTES]);
+            soft_mask_idx = max(soft_mask_idx - 1, 0);
+            while soft_mask_idx < maskblocks.len() as i64 {
+                let (bstart, bsize) = maskblocks[soft_mask_idx as usize];
+                let block_chunk_start = bstart as i64 - read_pos as i64;
+                let block_chunk_end = (bstart + bsize) as i64 - read_pos as i64;
+                if block_chunk_start > read_size as i64 {
+                    break;
+                }
+                setmaskbits(
+                    &mut chrmask[..],
+                    max(0, block_chunk_start) as usize,
+                    min(max(0, block_chunk_end) as usize, read_size),
+                );
+                soft_mask_idx += 1;
+            }
             dest.send(ChromChunkInfo {
                 chr_name: chrname.clone(),
                 chunk_start: read_pos as u64,
                 chunk_end: (read_pos + read_size) as u64,
                 data: chrdata,
+                mask: chrmask,
             })?;
             read_pos += read_size;
         }
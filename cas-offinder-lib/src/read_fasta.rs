@@ -1,35 +1,70 @@
-use crate::chrom_chunk::{ChromChunkInfo, CHUNK_SIZE, CHUNK_SIZE_BYTES};
+use crate::chrom_chunk::{ChromChunkInfo, CHUNK_MASK_BYTES, CHUNK_SIZE, CHUNK_SIZE_BYTES};
+use crate::chrom_filter::ChromFilter;
 use crate::cli_err::CliError;
-use crate::string_to_bit4;
+use crate::{setmaskbits, string_to_bit4};
+use flate2::read::MultiGzDecoder;
+use std::collections::HashSet;
 use std::fs::File;
-use std::io::{BufRead, BufReader};
+use std::io::{BufRead, BufReader, Read};
 use std::path::Path;
 use std::sync::mpsc::SyncSender;
 
+const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+
+pub fn is_gzip_file(fname: &Path) -> Result<bool, CliError> {
+    let mut magic = [0_u8; 2];
+    let mut file = File::open(fname)?;
+    let n_read = file.read(&mut magic)?;
+    Ok(n_read == magic.len() && magic == GZIP_MAGIC)
+}
+
 pub fn read_fasta(dest: &SyncSender<ChromChunkInfo>, fname: &Path) -> Result<(), CliError> {
+    read_fasta_filtered(dest, fname, &ChromFilter::default())
+}
+pub fn read_fasta_filtered(
+    dest: &SyncSender<ChromChunkInfo>,
+    fname: &Path,
+    chrom_filter: &ChromFilter,
+) -> Result<(), CliError> {
     let file = File::open(fname)?;
+    // BGZF files are a series of gzip members, so the multi member decoder reads both
+    let source = if is_gzip_file(fname)? {
+        Box::new(MultiGzDecoder::new(file)) as Box<dyn Read>
+    } else {
+        Box::new(file) as Box<dyn Read>
+    };
     let buf_capacity = CHUNK_SIZE * 4;
-    let buffer_reader = BufReader::with_capacity(buf_capacity, file);
+    let buffer_reader = BufReader::with_capacity(buf_capacity, source);
     let mut started = false;
+    let mut skipping = false;
+    // positions restart for every record, so a repeated name would overlap the first record
+    let mut chr_names: HashSet<String> = HashSet::new();
     let mut cur = ChromChunkInfo {
         chr_name: String::new(),
         chunk_start: 0,
         chunk_end: 0,
         data: Box::new([0_u8; CHUNK_SIZE_BYTES]),
+        mask: Box::new([0_u8; CHUNK_MASK_BYTES]),
     };
     for linerd in buffer_reader.lines() {
         let line = linerd?;
         if line.starts_with('>') {
             let next_chr_name = String::from_iter(line.chars().skip(1));
+            if !chr_names.insert(next_chr_name.clone()) {
+                return Err(CliError::BadFileFormat("fasta record name is repeated"));
+            }
             let next_cur = ChromChunkInfo {
                 chr_name: next_chr_name,
-                chunk_start: cur.chunk_end,
-                chunk_end: cur.chunk_end,
+                // positions are relative to the start of each record
+                chunk_start: 0,
+                chunk_end: 0,
                 data: Box::new([0_u8; CHUNK_SIZE_BYTES]),
+                mask: Box::new([0_u8; CHUNK_MASK_BYTES]),
             };
             if cur.chunk_end != cur.chunk_start {
                 dest.send(cur)?;
             }
+            skipping = !chrom_filter.is_kept(&next_cur.chr_name);
             cur = next_cur;
             started = true;
         } else {
@@ -37,27 +72,41 @@ pub fn read_fasta(dest: &SyncSender<ChromChunkInfo>, fname: &Path) -> Result<(),
                 //catch this error to skip invalid files
                 return Err(CliError::BadFileFormat("fasta file needs to start with >"));
             }
+            if skipping {
+                continue;
+            }
             if cur.chr_name.is_empty() {
                 return Err(CliError::BadFileFormat(
                     "> must be followed by chromosome name",
                 ));
             }
-            if line.len() + cur.size() > CHUNK_SIZE {
-                let next_cur = ChromChunkInfo {
-                    chr_name: cur.chr_name.clone(),
-                    chunk_start: cur.chunk_end,
-                    chunk_end: cur.chunk_end,
-                    data: Box::new([0_u8; CHUNK_SIZE_BYTES]),
-                };
-                dest.send(cur)?;
-                cur = next_cur;
-            }
-            if line.len() > CHUNK_SIZE {
-                return Err(CliError::BadFileFormat("line in fasta too long"));
+            // lines are split over chunks, the search expects all but the last chunk of a
+            // chromosome to be full
+            let mut rest = line.as_bytes();
+            while !rest.is_empty() {
+                if cur.size() == CHUNK_SIZE {
+                    let next_cur = ChromChunkInfo {
+                        chr_name: cur.chr_name.clone(),
+                        chunk_start: cur.chunk_end,
+                        chunk_end: cur.chunk_end,
+                        data: Box::new([0_u8; CHUNK_SIZE_BYTES]),
+                        mask: Box::new([0_u8; CHUNK_MASK_BYTES]),
+                    };
+                    dest.send(cur)?;
+                    cur = next_cur;
+                }
+                let cur_size = cur.size();
+                let part = &rest[..rest.len().min(CHUNK_SIZE - cur_size)];
+                string_to_bit4(&mut cur.data[..], part, cur_size, false);
+                // lowercase bases are soft-masked repeats
+                for (idx, c) in part.iter().enumerate() {
+                    if c.is_ascii_lowercase() {
+                        setmaskbits(&mut cur.mask[..], cur_size + idx, cur_size + idx + 1);
+                    }
+                }
+                cur.chunk_end += part.len() as u64;
+                rest = &rest[part.len()..];
             }
-            let cur_size = cur.size() as usize;
-            string_to_bit4(&mut cur.data[..], line.as_bytes(), cur_size, false);
-            cur.chunk_end += line.len() as u64;
         }
     }
     if cur.size() > 0 {
@@ -1,11 +1,19 @@
 use crate::chrom_chunk::ChromChunkInfo;
+use crate::chrom_filter::ChromFilter;
 use crate::cli_err::CliError;
-use crate::read_fasta::read_fasta;
+use crate::read_fasta::read_fasta_filtered;
 use std::fs::read_dir;
 use std::path::Path;
 use std::sync::mpsc::SyncSender;
 
 pub fn read_fasta_folder(dest: &SyncSender<ChromChunkInfo>, folder: &Path) -> Result<(), CliError> {
+    read_fasta_folder_filtered(dest, folder, &ChromFilter::default())
+}
+pub fn read_fasta_folder_filtered(
+    dest: &SyncSender<ChromChunkInfo>,
+    folder: &Path,
+    chrom_filter: &ChromFilter,
+) -> Result<(), CliError> {
     for path_r in read_dir(folder)? {
         let path = path_r?;
         if path.file_type()?.is_file() {
@@ -14,8 +22,8 @@ pub fn read_fasta_folder(dest: &SyncSender<ChromChunkInfo>, folder: &Path) -> Re
                 std::io::ErrorKind::InvalidData,
                 "paths must be utf-8 encodeable",
             ))?;
-            if path_name.ends_with(".fa") {
-                read_fasta(dest, &path_p)?;
+            if path_name.ends_with(".fa") || path_name.ends_with(".fa.gz") {
+                read_fasta_filtered(dest, &path_p, chrom_filter)?;
             }
         }
     }
@@ -0,0 +1,58 @@
+use crate::chrom_chunk::{ChromChunkInfo, CHUNK_MASK_BYTES, CHUNK_SIZE, CHUNK_SIZE_BYTES};
+use crate::chrom_filter::ChromFilter;
+use crate::cli_err::CliError;
+use crate::{setmaskbits, string_to_bit4};
+use std::collections::HashSet;
+use std::sync::mpsc::SyncSender;
+
+/*
+Reads named sequences held in memory, e.g. plasmid maps, amplicons or synthetic
+constructs, as if they were the records of a fasta file.
+*/
+
+pub fn read_sequences(
+    dest: &SyncSender<ChromChunkInfo>,
+    sequences: &[(&str, &[u8])],
+) -> Result<(), CliError> {
+    read_sequences_filtered(dest, sequences, &ChromFilter::default())
+}
+pub fn read_sequences_filtered(
+    dest: &SyncSender<ChromChunkInfo>,
+    sequences: &[(&str, &[u8])],
+    chrom_filter: &ChromFilter,
+) -> Result<(), CliError> {
+    // checked before the first chunk is sent, the search needs the chunks of a name in order
+    let mut names: HashSet<&str> = HashSet::new();
+    for (name, _) in sequences.iter() {
+        if name.is_empty() {
+            return Err(CliError::BadFileFormat("sequences need a name"));
+        }
+        if !names.insert(name) {
+            return Err(CliError::BadFileFormat("sequence names need to be unique"));
+        }
+    }
+    for (name, seq) in sequences.iter() {
+        if !chrom_filter.is_kept(name) {
+            continue;
+        }
+        for (chunk_idx, part) in seq.chunks(CHUNK_SIZE).enumerate() {
+            let chunk_start = (chunk_idx * CHUNK_SIZE) as u64;
+            let mut chunk = ChromChunkInfo {
+                chr_name: name.to_string(),
+                chunk_start,
+                chunk_end: chunk_start + part.len() as u64,
+                data: Box::new([0_u8; CHUNK_SIZE_BYTES]),
+                mask: Box::new([0_u8; CHUNK_MASK_BYTES]),
+            };
+            string_to_bit4(&mut chunk.data[..], part, 0, false);
+            // lowercase bases are soft-masked repeats, as in fasta files
+            for (idx, c) in part.iter().enumerate() {
+                if c.is_ascii_lowercase() {
+                    setmaskbits(&mut chunk.mask[..], idx, idx + 1);
+                }
+            }
+            dest.send(chunk)?;
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,292 @@
+use crate::chrom_chunk::{ChromChunkInfo, Match};
+use crate::cli_err::{CliError, Result};
+use crate::memsetbit4;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufRead, BufReader};
+use std::path::Path;
+use std::sync::mpsc::{Receiver, SyncSender};
+
+/*
+Genome intervals read from BED files, used to limit the search to target loci
+or to keep it out of blacklisted ones.
+Chunks are filtered before they reach search(): chunks with nothing left to
+search are dropped, and the bases of the remaining chunks that are filtered out
+are set to 0, which never matches any pattern.
+A base set to 0 only counts as a mismatch though. It rules out the hits of a
+search filter that rejects it, but a hit under a filter of N positions can still
+span a few blanked bases, so the hits are checked with overlaps_match as well.
+*/
+
+pub struct GenomeRegions {
+    // sorted, non-overlapping half open intervals per chromosome
+    intervals: HashMap<String, Vec<(u64, u64)>>,
+}
+
+// fasta records keep their full header line as name, BED files only use the first word
+fn chrom_key(chr_name: &str) -> &str {
+    chr_name.split_ascii_whitespace().next().unwrap_or("")
+}
+
+fn merge_intervals(mut intervals: Vec<(u64, u64)>) -> Vec<(u64, u64)> {
+    intervals.sort();
+    let mut merged: Vec<(u64, u64)> = Vec::with_capacity(intervals.len());
+    for (start, end) in intervals {
+        match merged.last_mut() {
+            Some(last) if start <= last.1 => last.1 = last.1.max(end),
+            _ => merged.push((start, end)),
+        }
+    }
+    merged
+}
+
+fn parse_bed_coord(field: Option<&str>) -> Result<u64> {
+    field
+        .ok_or(CliError::BadFileFormat(
+            "BED lines need at least 3 columns: <chrom> <start> <end>",
+        ))?
+        .parse::<u64>()
+        .map_err(|_| CliError::BadFileFormat("BED start and end must be unsigned integers"))
+}
+
+fn read_bed_intervals(
+    fname: &Path,
+    intervals: &mut HashMap<String, Vec<(u64, u64)>>,
+) -> Result<()> {
+    let reader = BufReader::new(File::open(fname)?);
+    for line_r in reader.lines() {
+        let line = line_r?;
+        let is_header =
+            line.starts_with('#') || line.starts_with("track") || line.starts_with("browser");
+        if line.trim().is_empty() || is_header {
+            continue;
+        }
+        let mut fields = line.split('\t');
+        let chrom = fields.next().unwrap().trim();
+        let start = parse_bed_coord(fields.next())?;
+        let end = parse_bed_coord(fields.next())?;
+        if end < start {
+            return Err(CliError::BadFileFormat(
+                "BED end must not be smaller than start",
+            ));
+        }
+        intervals
+            .entry(chrom.to_string())
+            .or_default()
+            .push((start, end));
+    }
+    Ok(())
+}
+
+impl GenomeRegions {
+    pub fn from_bed(fname: &Path) -> Result<GenomeRegions> {
+        GenomeRegions::from_bed_files(&[fname])
+    }
+    pub fn from_bed_files(fnames: &[&Path]) -> Result<GenomeRegions> {
+        let mut intervals: HashMap<String, Vec<(u64, u64)>> = HashMap::new();
+        for fname in fnames {
+            read_bed_intervals(fname, &mut intervals)?;
+        }
+        Ok(GenomeRegions::from_intervals(intervals))
+    }
+    pub fn from_intervals(intervals: HashMap<String, Vec<(u64, u64)>>) -> GenomeRegions {
+        GenomeRegions {
+            intervals: intervals
+                .into_iter()
+                .map(|(chrom, ivs)| (chrom, merge_intervals(ivs)))
+                .collect(),
+        }
+    }
+    pub fn with_flank(&self, flank: u64) -> GenomeRegions {
+        GenomeRegions::from_intervals(
+            self.intervals
+                .iter()
+                .map(|(chrom, ivs)| {
+                    let padded = ivs
+                        .iter()
+                        .map(|(start, end)| (start.saturating_sub(flank), end + flank))
+                        .collect();
+                    (chrom.clone(), padded)
+                })
+                .collect(),
+        )
+    }
+    // intervals overlapping [start, end) on the chromosome
+    pub fn overlapping(&self, chr_name: &str, start: u64, end: u64) -> &[(u64, u64)] {
+        match self.intervals.get(chrom_key(chr_name)) {
+            Some(ivs) => {
+                let first = ivs.partition_point(|iv| iv.1 <= start);
+                let last = ivs.partition_point(|iv| iv.0 < end);
+                &ivs[first..last.max(first)]
+            }
+            None => &[],
+        }
+    }
+    // whether the genome bases of the hit overlap a region
+    pub fn overlaps_match(&self, m: &Match) -> bool {
+        !self
+            .overlapping(&m.chr_name, m.chrom_idx, m.chrom_idx + m.genome_len())
+            .is_empty()
+    }
+}
+
+pub fn restrict_to_regions(
+    mut chunk: ChromChunkInfo,
+    regions: &GenomeRegions,
+) -> Option<ChromChunkInfo> {
+    /* keeps only the bases inside the regions, None if the chunk has none of them */
+    let overlaps = regions.overlapping(&chunk.chr_name, chunk.chunk_start, chunk.chunk_end);
+    if overlaps.is_empty() {
+        return None;
+    }
+    let mut kept_until: usize = 0;
+    for &(start, end) in overlaps {
+        let rel_start = start.saturating_sub(chunk.chunk_start) as usize;
+        memsetbit4(&mut chunk.data[..], 0, kept_until, rel_start);
+        kept_until = (end.min(chunk.chunk_end) - chunk.chunk_start) as usize;
+    }
+    let chunk_size = chunk.size();
+    memsetbit4(&mut chunk.data[..], 0, kept_until, chunk_size);
+    Some(chunk)
+}
+
+pub fn exclude_regions(
+    mut chunk: ChromChunkInfo,
+    regions: &GenomeRegions,
+) -> Option<ChromChunkInfo> {
+    /* blanks the bases inside the regions, None if the whole chunk is excluded
+    hits spanning blanked bases are only ruled out by the search filter, drop the
+    remaining ones with regions.overlaps_match */
+    let overlaps = regions.overlapping(&chunk.chr_name, chunk.chunk_start, chunk.chunk_end);
+    let is_covered = overlaps.len() == 1
+        && overlaps[0].0 <= chunk.chunk_start
+        && overlaps[0].1 >= chunk.chunk_end;
+    if is_covered {
+        return None;
+    }
+    for &(start, end) in overlaps {
+        let rel_start = start.saturating_sub(chunk.chunk_start) as usize;
+        let rel_end = (end.min(chunk.chunk_end) - chunk.chunk_start) as usize;
+        memsetbit4(&mut chunk.data[..], 0, rel_start, rel_end);
+    }
+    Some(chunk)
+}
+
+pub fn filter_chunks<F>(
+    recv: Receiver<ChromChunkInfo>,
+    dest: &SyncSender<ChromChunkInfo>,
+    filter: F,
+) -> Result<()>
+where
+    F: Fn(ChromChunkInfo) -> Option<ChromChunkInfo>,
+{
+    for chunk in recv.iter() {
+        if let Some(kept) = filter(chunk) {
+            dest.send(kept)?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chrom_chunk::{CHUNK_MASK_BYTES, CHUNK_SIZE_BYTES};
+    use crate::run_config::OclRunConfig;
+    use crate::search::search;
+    use crate::searcher::encode_patterns;
+    use crate::{bit4_to_string, string_to_bit4};
+    use std::sync::mpsc;
+
+    fn make_chunk(chr_name: &str, chunk_start: u64, seq: &[u8]) -> ChromChunkInfo {
+        let mut data = Box::new([0_u8; CHUNK_SIZE_BYTES]);
+        string_to_bit4(&mut data[..], seq, 0, false);
+        ChromChunkInfo {
+            chr_name: chr_name.to_string(),
+            data,
+            mask: Box::new([0_u8; CHUNK_MASK_BYTES]),
+            chunk_start,
+            chunk_end: chunk_start + seq.len() as u64,
+        }
+    }
+    fn chunk_str(chunk: &ChromChunkInfo) -> Vec<u8> {
+        let mut res = vec![0_u8; chunk.size()];
+        bit4_to_string(&mut res, &chunk.data[..], 0, chunk.size());
+        res
+    }
+
+    #[test]
+    fn test_merge_and_flank() {
+        let regions = GenomeRegions::from_intervals(HashMap::from([(
+            "chr1".to_string(),
+            vec![(10, 20), (5, 12), (30, 40)],
+        )]));
+        assert_eq!(regions.overlapping("chr1", 0, 100), &[(5, 20), (30, 40)]);
+        let padded = regions.with_flank(6);
+        assert_eq!(padded.overlapping("chr1 extra", 0, 100), &[(0, 46)]);
+        assert!(regions.overlapping("chr2", 0, 100).is_empty());
+        assert_eq!(regions.overlapping("chr1", 20, 30), &[]);
+    }
+    #[test]
+    fn test_restrict_to_regions() {
+        let regions = GenomeRegions::from_intervals(HashMap::from([(
+            "chr1".to_string(),
+            vec![(102, 104), (106, 120)],
+        )]));
+        let chunk = make_chunk("chr1", 100, b"ACGTACGTAC");
+        let restricted = restrict_to_regions(chunk, &regions).unwrap();
+        assert_eq!(restricted.chunk_start, 100);
+        // bases outside the regions decode to 0
+        assert_eq!(chunk_str(&restricted), b"\0\0GT\0\0GTAC");
+        assert!(restrict_to_regions(make_chunk("chr1", 0, b"ACGT"), &regions).is_none());
+        assert!(restrict_to_regions(make_chunk("chr2", 100, b"ACGT"), &regions).is_none());
+    }
+    #[test]
+    fn test_exclude_regions() {
+        let regions = GenomeRegions::from_intervals(HashMap::from([(
+            "chr1".to_string(),
+            vec![(98, 102), (106, 107), (200, 300)],
+        )]));
+        let chunk = make_chunk("chr1", 100, b"ACGTACGTAC");
+        let excluded = exclude_regions(chunk, &regions).unwrap();
+        assert_eq!(chunk_str(&excluded), b"\0\0GTAC\0TAC");
+        assert!(exclude_regions(make_chunk("chr1", 210, b"ACGT"), &regions).is_none());
+        let untouched = exclude_regions(make_chunk("chr2", 100, b"ACGT"), &regions).unwrap();
+        assert_eq!(chunk_str(&untouched), b"ACGT");
+    }
+    #[test]
+    fn test_excluded_hits_need_check() {
+        let guide = b"GAGTCCGAGCAGAAGAAGAANNN".to_vec();
+        let seq = [
+            &b"ACGTTGCAACGTTGCAACGT"[..],
+            b"GAGTCCGAGCAGAAGAAGAATGG",
+            b"CAT",
+        ]
+        .concat();
+        // covers the last two guide bases of the site at 20
+        let regions =
+            GenomeRegions::from_intervals(HashMap::from([("chr1".to_string(), vec![(38, 40)])]));
+        let chunk = exclude_regions(make_chunk("chr1", 0, &seq), &regions).unwrap();
+        let (pattern_lens, patterns) = encode_patterns(&[guide]);
+        let (src_sender, src_receiver) = mpsc::sync_channel::<ChromChunkInfo>(1);
+        let (dest_sender, dest_receiver) = mpsc::sync_channel::<Vec<Match>>(4);
+        src_sender.send(chunk).unwrap();
+        drop(src_sender);
+        // a filter of N positions does not reject the blanked bases
+        search(
+            OclRunConfig::cpu(),
+            &[3, 3],
+            0,
+            0,
+            &pattern_lens,
+            &patterns,
+            &vec![vec![0_u8; 12]; 2],
+            src_receiver,
+            dest_sender,
+        )
+        .unwrap();
+        let hits: Vec<Match> = dest_receiver.iter().flatten().collect();
+        assert!(hits.iter().any(|m| m.chrom_idx == 20 && m.mismatches == 2));
+        assert!(hits.iter().all(|m| regions.overlaps_match(m)));
+    }
+}
@@ -1,7 +1,41 @@
+use crate::cli_err::CliError;
+use crate::cpu_kernel::CpuKernel;
 use opencl3::*;
+use std::env;
 use std::thread;
 pub struct OclRunConfig {
     devices: Vec<(platform::Platform, Vec<device::Device>)>,
+    // thread budget of the Rust CPU backend, None to take it from the environment
+    n_threads: Option<usize>,
+    // kernel of the Rust CPU backend, None to pick the fastest one the processor supports
+    cpu_kernel: Option<CpuKernel>,
+}
+
+// thread count variables of common schedulers and runtimes, checked in this order
+const THREAD_ENV_VARS: [&str; 3] = [
+    "OMP_NUM_THREADS",
+    "RAYON_NUM_THREADS",
+    "SLURM_CPUS_PER_TASK",
+];
+// threads of a scan that keep a core busy next to the search threads: the genome reader
+// and the hit writer. The chunk filter, chunk batching and match conversion threads
+// mostly wait on their channels and are not counted
+pub const SCAN_RESERVED_THREADS: usize = 2;
+// an index search only runs the hit writer next to its search threads
+pub const INDEX_RESERVED_THREADS: usize = 1;
+
+fn threads_from_env(get_var: impl Fn(&str) -> Option<String>) -> Option<usize> {
+    THREAD_ENV_VARS.iter().find_map(|name| {
+        // OMP_NUM_THREADS may list the counts of nested levels, e.g. 8,2
+        let value = get_var(name)?;
+        let n_threads = value.split(',').next()?.trim().parse::<usize>().ok()?;
+        (n_threads > 0).then_some(n_threads)
+    })
+}
+pub fn default_thread_count() -> usize {
+    threads_from_env(|name| env::var(name).ok())
+        .or_else(|| thread::available_parallelism().ok().map(|n| n.into()))
+        .unwrap_or(1)
 }
 
 pub enum OclDeviceType {
@@ -39,27 +73,119 @@ pub fn get_avali_ocl_devices(
     Ok(devices)
 }
 
+// letters of the device types on the command line, in the order devices are listed
+const DEVICE_LETTERS: [(char, OclDeviceType); 3] = [
+    ('C', OclDeviceType::CPU),
+    ('G', OclDeviceType::GPU),
+    ('A', OclDeviceType::ACCEL),
+];
+
 impl OclRunConfig {
     pub fn new(ty: OclDeviceType) -> Result<OclRunConfig> {
         Ok(OclRunConfig {
             devices: get_avali_ocl_devices(ty)?,
+            ..OclRunConfig::cpu()
+        })
+    }
+    // no OpenCL devices, the search runs on the Rust CPU backend
+    pub fn cpu() -> OclRunConfig {
+        OclRunConfig {
+            devices: Vec::new(),
+            n_threads: None,
+            cpu_kernel: None,
+        }
+    }
+    // devices of a type are numbered from 0 over all platforms, as in list_devices
+    pub fn with_device_ids(
+        ty: OclDeviceType,
+        device_ids: &[usize],
+    ) -> std::result::Result<OclRunConfig, CliError> {
+        let all_devices = get_avali_ocl_devices(ty)?;
+        if device_ids.is_empty() {
+            return Ok(OclRunConfig {
+                devices: all_devices,
+                ..OclRunConfig::cpu()
+            });
+        }
+        let n_devices: usize = all_devices.iter().map(|(_, devs)| devs.len()).sum();
+        if device_ids.iter().any(|id| *id >= n_devices) {
+            return Err(CliError::ArgumentError(
+                "Device id is not in the available device list",
+            ));
+        }
+        let mut first_id = 0;
+        let mut devices = Vec::new();
+        for (plat, devs) in all_devices {
+            let n_plat_devices = devs.len();
+            let chosen: Vec<device::Device> = devs
+                .into_iter()
+                .enumerate()
+                .filter(|(idx, _)| device_ids.contains(&(first_id + idx)))
+                .map(|(_, dev)| dev)
+                .collect();
+            first_id += n_plat_devices;
+            if !chosen.is_empty() {
+                devices.push((plat, chosen));
+            }
+        }
+        Ok(OclRunConfig {
+            devices,
+            ..OclRunConfig::cpu()
         })
     }
+    // total thread budget of the Rust CPU backend, counting the threads reserved next to the
+    // search threads, OpenCL devices ignore it
+    pub fn threads(mut self, n_threads: usize) -> OclRunConfig {
+        self.n_threads = Some(n_threads);
+        self
+    }
+    pub fn cpu_kernel(mut self, kernel: CpuKernel) -> OclRunConfig {
+        self.cpu_kernel = Some(kernel);
+        self
+    }
+    // falls back to the detected kernel if the chosen one is not supported
+    pub fn get_cpu_kernel(&self) -> CpuKernel {
+        self.cpu_kernel
+            .filter(|kernel| kernel.is_supported())
+            .unwrap_or_else(CpuKernel::detect)
+    }
+    pub fn n_threads(&self) -> usize {
+        self.n_threads.unwrap_or_else(default_thread_count)
+    }
+    // search threads of the Rust CPU backend, what is left of the budget after the
+    // SCAN_RESERVED_THREADS or INDEX_RESERVED_THREADS
+    pub fn n_search_threads(&self, reserved_threads: usize) -> usize {
+        std::cmp::max(1, self.n_threads().saturating_sub(reserved_threads))
+    }
+    // numbered device list, e.g. "G1: <device name>", for the usage text
+    pub fn list_devices() -> Result<Vec<String>> {
+        let mut device_strs = Vec::new();
+        for (letter, ty) in DEVICE_LETTERS {
+            let devices = get_avali_ocl_devices(ty)?;
+            for (idx, dev) in devices.iter().flat_map(|(_, devs)| devs.iter()).enumerate() {
+                device_strs.push(format!("{}{}: {}", letter, idx, dev.name()?));
+            }
+        }
+        if device_strs.is_empty() {
+            device_strs = OclRunConfig::cpu().get_device_strs()?;
+        }
+        Ok(device_strs)
+    }
     pub fn is_empty(&self) -> bool {
         self.devices.is_empty()
     }
-    pub fn get_device_strs(&self) -> Vec<String> {
+    pub fn get_device_strs(&self) -> Result<Vec<String>> {
         if self.is_empty() {
-            let n_threads: usize = thread::available_parallelism().unwrap().into();
-            vec![format!(
-                "Rust CPU implementation using {} threads",
-                n_threads
-            )]
+            Ok(vec![format!(
+                "Rust CPU implementation ({}) using {} threads",
+                self.get_cpu_kernel().name(),
+                self.n_search_threads(SCAN_RESERVED_THREADS)
+            )])
         } else {
             self.devices
                 .iter()
                 .flat_map(|(_, devs)| devs.iter())
-                .map(|d| d.name().unwrap())
+                .map(|d| d.name())
                 .collect()
         }
     }
@@ -67,3 +193,47 @@ impl OclRunConfig {
         &self.devices
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_threads_from_env() {
+        let env_of = |vars: &'static [(&'static str, &'static str)]| {
+            move |name: &str| {
+                vars.iter()
+                    .find(|(var, _)| *var == name)
+                    .map(|(_, value)| value.to_string())
+            }
+        };
+        assert_eq!(threads_from_env(env_of(&[])), None);
+        assert_eq!(
+            threads_from_env(env_of(&[("SLURM_CPUS_PER_TASK", "8")])),
+            Some(8)
+        );
+        assert_eq!(
+            threads_from_env(env_of(&[
+                ("OMP_NUM_THREADS", "4,2"),
+                ("SLURM_CPUS_PER_TASK", "8")
+            ])),
+            Some(4)
+        );
+        assert_eq!(
+            threads_from_env(env_of(&[
+                ("OMP_NUM_THREADS", "0"),
+                ("RAYON_NUM_THREADS", "x")
+            ])),
+            None
+        );
+    }
+
+    #[test]
+    fn test_n_search_threads() {
+        let cpu_config = |n_threads| OclRunConfig::cpu().threads(n_threads);
+        assert_eq!(cpu_config(8).n_search_threads(SCAN_RESERVED_THREADS), 6);
+        assert_eq!(cpu_config(8).n_search_threads(INDEX_RESERVED_THREADS), 7);
+        assert_eq!(cpu_config(2).n_search_threads(SCAN_RESERVED_THREADS), 1);
+        assert_eq!(cpu_config(1).n_search_threads(INDEX_RESERVED_THREADS), 1);
+    }
+}
@@ -1,9 +1,19 @@
 use opencl3::*;
 // use cl3
 use crate::bit4ops::{cdiv, roundup};
+use crate::bulge::{expand_bulges, gapped_alignment, variant_search_filter, PatternVariant};
+use crate::cfd::cfd_score;
+use crate::cli_err::{self, first_error, join_worker, CliError};
+#[cfg(target_arch = "x86_64")]
+use crate::cpu_kernel::{count_matches_avx2, count_matches_avx512, MAX_SIMD_PATTERN_BLOCKS};
+use crate::cpu_kernel::{
+    count_matches_scalar, filter_lanes_scalar, nonzero_nibbles, nucl_match_mask, BlockCounts,
+    CpuKernel, BLOCKS_PER_EXEC,
+};
 use crate::run_config::*;
-use crate::{bit4_to_string, chrom_chunk::*, reverse_compliment_char_i};
+use crate::{bit4_to_string, chrom_chunk::*, cmp_chars, getmaskbit, reverse_compliment_char_i};
 use opencl3::Result;
+use std::collections::VecDeque;
 use std::ptr::null_mut;
 use std::sync::mpsc;
 use std::sync::Arc;
@@ -13,6 +23,7 @@ pub const KERNEL_CONTENTS: &str = include_str!("./kernel.cl");
 
 const SEARCH_CHUNK_SIZE: usize = 1 << 22; // must be less than 1<<32
 const SEARCH_CHUNK_SIZE_BYTES: usize = SEARCH_CHUNK_SIZE / 2;
+const SEARCH_CHUNK_MASK_BYTES: usize = SEARCH_CHUNK_SIZE / 8;
 const CHUNKS_PER_SEARCH: usize = SEARCH_CHUNK_SIZE / CHUNK_SIZE;
 
 const CPU_BLOCK_SIZE: usize = 8;
@@ -27,15 +38,26 @@ struct SearchChunkMeta {
     pub chunk_ends: Vec<u64>,
 }
 
+impl SearchChunkMeta {
+    // true if chunk idx directly follows chunk idx-1 in the same chromosome
+    pub fn continues_previous(&self, idx: usize) -> bool {
+        self.chr_names[idx] == self.chr_names[idx - 1]
+            && self.chunk_starts[idx] == self.chunk_ends[idx - 1]
+    }
+}
+
 struct SearchChunkInfo {
     // fixed size data, divied into SEARCH_CHUNK_SIZE/CHUNK_SIZE chunks
     pub data: Box<[u8; SEARCH_CHUNK_SIZE_BYTES]>,
+    // soft-mask bitmap, laid out like data
+    pub mask: Box<[u8; SEARCH_CHUNK_MASK_BYTES]>,
     pub meta: SearchChunkMeta,
 }
 struct SearchChunkResult {
     pub matches: Vec<SearchMatch>,
     pub meta: SearchChunkMeta,
     pub data: Box<[u8; SEARCH_CHUNK_SIZE_BYTES]>,
+    pub mask: Box<[u8; SEARCH_CHUNK_MASK_BYTES]>,
 }
 
 #[derive(Clone, Copy)]
@@ -45,18 +67,73 @@ struct SearchMatch {
     pub mismatches: u32,
 }
 
-const MAX_QUEUED: usize = 1;
+// patterns sharing one length, searched together by a single kernel
+struct PatternGroup {
+    pub pattern_len: usize,
+    // index of the group's first pattern among all searched patterns
+    pub first_idx: u32,
+    pub packed_patterns: Vec<u8>,
+    // search filter of every pattern, packed like the patterns
+    pub packed_filters: Vec<u8>,
+    pub max_mismatches: Vec<u32>,
+}
+
+impl PatternGroup {
+    pub fn n_patterns(&self) -> usize {
+        self.max_mismatches.len()
+    }
+    pub fn to_global(&self, matches: Vec<SearchMatch>) -> impl Iterator<Item = SearchMatch> + '_ {
+        matches.into_iter().map(|m| SearchMatch {
+            pattern_idx: m.pattern_idx + self.first_idx,
+            ..m
+        })
+    }
+}
+
+fn group_patterns(
+    pattern_lens: &[usize],
+    patterns: &[Vec<u8>],
+    search_filters: &[Vec<u8>],
+    max_mismatches: &[u32],
+) -> Vec<PatternGroup> {
+    /* expects patterns of the same length to be next to each other */
+    let mut groups: Vec<PatternGroup> = Vec::new();
+    let mut start = 0;
+    while start < patterns.len() {
+        let pattern_len = pattern_lens[start];
+        let end = start
+            + pattern_lens[start..]
+                .iter()
+                .take_while(|l| **l == pattern_len)
+                .count();
+        groups.push(PatternGroup {
+            pattern_len,
+            first_idx: start as u32,
+            packed_patterns: pack_patterns(&patterns[start..end]),
+            packed_filters: pack_patterns(&search_filters[start..end]),
+            max_mismatches: max_mismatches[start..end].to_vec(),
+        });
+        start = end;
+    }
+    groups
+}
+
+// chunks in flight per device, one uploads while another is searched and read back
+const MAX_QUEUED: usize = 3;
+// default initial capacity of the match buffers, they grow if a single work item overflows them
+const OUT_BUF_SIZE: usize = 1 << 22;
+const CL_BLOCK: u32 = 1;
+const CL_NO_BLOCK: u32 = 0;
 unsafe fn create_ocl_buf<T>(context: &context::Context, size: usize) -> Result<memory::Buffer<T>> {
     memory::Buffer::create(context, memory::CL_MEM_READ_WRITE, size, null_mut())
 }
 unsafe fn create_ocl_bufs<T>(
     context: &context::Context,
     size: usize,
-) -> Result<[memory::Buffer<T>; MAX_QUEUED]> {
-    Ok([
-        create_ocl_buf::<T>(context, size)?,
-        // create_ocl_buf::<T>(&context,size)?
-    ])
+) -> Result<Vec<memory::Buffer<T>>> {
+    (0..MAX_QUEUED)
+        .map(|_| create_ocl_buf::<T>(context, size))
+        .collect()
 }
 fn is_gpu(dev: &device::Device) -> Result<bool> {
     Ok(dev.dev_type()? == device::CL_DEVICE_TYPE_GPU)
@@ -68,85 +145,326 @@ fn prefered_block_size(dev: &device::Device) -> Result<usize> {
         CPU_BLOCK_SIZE
     })
 }
+// a chunk whose search is enqueued on one of the slots of a device
+struct InFlightChunk {
+    slot: usize,
+    item: SearchChunkInfo,
+    n_genome_execs: usize,
+    // read back without blocking, valid once count_event completes
+    count: Box<[u32; 1]>,
+    count_event: event::Event,
+}
+/*
+Every slot has its own command queue and buffers, so the upload of one chunk,
+the kernels of another and the readback of a third can overlap on the device.
+All pattern groups of a chunk append to the same match buffer.
+The device reads the genome of the chunks in flight and writes their counts
+straight to host memory, so the queues are finished before that memory is
+freed, also when the search stops with an error.
+*/
+struct OclDeviceSearch<'a> {
+    groups: &'a [PatternGroup],
+    context: &'a context::Context,
+    kernels: Vec<kernel::Kernel>,
+    pattern_bufs: Vec<memory::Buffer<u8>>,
+    filter_bufs: Vec<memory::Buffer<u8>>,
+    mismatch_bufs: Vec<memory::Buffer<u32>>,
+    queues: Vec<command_queue::CommandQueue>,
+    genome_bufs: Vec<memory::Buffer<u8>>,
+    count_bufs: Vec<memory::Buffer<u32>>,
+    out_bufs: Vec<memory::Buffer<SearchMatch>>,
+    out_capacities: Vec<usize>,
+    in_flight: VecDeque<InFlightChunk>,
+}
+impl Drop for OclDeviceSearch<'_> {
+    fn drop(&mut self) {
+        // errors are ignored, the search already failed if the queues cannot be finished
+        for queue in &self.queues {
+            let _ = queue.finish();
+        }
+    }
+}
+impl<'a> OclDeviceSearch<'a> {
+    unsafe fn new(
+        groups: &'a [PatternGroup],
+        context: &'a context::Context,
+        programs: &[program::Program],
+        dev: &device::Device,
+        out_capacity: usize,
+    ) -> Result<OclDeviceSearch<'a>> {
+        let queues = (0..MAX_QUEUED)
+            .map(|_| command_queue::CommandQueue::create(context, dev.id(), 0))
+            .collect::<Result<Vec<command_queue::CommandQueue>>>()?;
+        let mut kernels: Vec<kernel::Kernel> = Vec::with_capacity(groups.len());
+        let mut pattern_bufs: Vec<memory::Buffer<u8>> = Vec::with_capacity(groups.len());
+        let mut filter_bufs: Vec<memory::Buffer<u8>> = Vec::with_capacity(groups.len());
+        let mut mismatch_bufs: Vec<memory::Buffer<u32>> = Vec::with_capacity(groups.len());
+        for (group, program) in groups.iter().zip(programs.iter()) {
+            kernels.push(kernel::Kernel::create(program, "find_matches")?);
+            let mut pattern_buf = create_ocl_buf::<u8>(context, group.packed_patterns.len())?;
+            queues[0].enqueue_write_buffer(
+                &mut pattern_buf,
+                CL_BLOCK,
+                0,
+                &group.packed_patterns,
+                &[],
+            )?;
+            pattern_bufs.push(pattern_buf);
+            let mut filter_buf = create_ocl_buf::<u8>(context, group.packed_filters.len())?;
+            queues[0].enqueue_write_buffer(
+                &mut filter_buf,
+                CL_BLOCK,
+                0,
+                &group.packed_filters,
+                &[],
+            )?;
+            filter_bufs.push(filter_buf);
+            let mut mismatch_buf = create_ocl_buf::<u32>(context, group.max_mismatches.len())?;
+            queues[0].enqueue_write_buffer(
+                &mut mismatch_buf,
+                CL_BLOCK,
+                0,
+                &group.max_mismatches,
+                &[],
+            )?;
+            mismatch_bufs.push(mismatch_buf);
+        }
+        Ok(OclDeviceSearch {
+            groups,
+            context,
+            kernels,
+            pattern_bufs,
+            filter_bufs,
+            mismatch_bufs,
+            queues,
+            genome_bufs: create_ocl_bufs::<u8>(context, SEARCH_CHUNK_SIZE_BYTES)?,
+            count_bufs: create_ocl_bufs::<u32>(context, 1)?,
+            out_bufs: create_ocl_bufs::<SearchMatch>(context, out_capacity)?,
+            out_capacities: vec![out_capacity; MAX_QUEUED],
+            in_flight: VecDeque::with_capacity(MAX_QUEUED),
+        })
+    }
+    unsafe fn enqueue_kernel(
+        &self,
+        slot: usize,
+        group_idx: usize,
+        first_exec: usize,
+        n_execs: usize,
+    ) -> Result<event::Event> {
+        let group = &self.groups[group_idx];
+        kernel::ExecuteKernel::new(&self.kernels[group_idx])
+            .set_arg(&self.genome_bufs[slot])
+            .set_arg(&self.pattern_bufs[group_idx])
+            .set_arg(&self.filter_bufs[group_idx])
+            .set_arg(&self.mismatch_bufs[group_idx])
+            .set_arg(&self.out_bufs[slot])
+            .set_arg(&self.count_bufs[slot])
+            .set_arg(&(self.out_capacities[slot] as u32))
+            .set_arg(&group.first_idx)
+            .set_global_work_offsets(&[first_exec, 0])
+            .set_global_work_sizes(&[n_execs, group.n_patterns()])
+            .enqueue_nd_range(&self.queues[slot])
+    }
+    unsafe fn read_matches(&self, slot: usize, n_matches: usize) -> Result<Vec<SearchMatch>> {
+        let mut matches: Vec<SearchMatch> = vec![
+            SearchMatch {
+                chunk_idx: 0,
+                pattern_idx: 0,
+                mismatches: 0
+            };
+            n_matches
+        ];
+        if n_matches != 0 {
+            self.queues[slot].enqueue_read_buffer(
+                &self.out_bufs[slot],
+                CL_BLOCK,
+                0,
+                &mut matches[..],
+                &[],
+            )?;
+        }
+        Ok(matches)
+    }
+    // queues the search of a chunk, which stays in flight until finish_chunk
+    unsafe fn start_chunk(
+        &mut self,
+        slot: usize,
+        item: SearchChunkInfo,
+        n_genome_execs: usize,
+        n_genome_bytes: usize,
+    ) -> Result<()> {
+        let mut count = Box::new([0_u32; 1]);
+        match self.enqueue_chunk(slot, &item, n_genome_execs, n_genome_bytes, &mut count) {
+            Ok(count_event) => {
+                self.in_flight.push_back(InFlightChunk {
+                    slot,
+                    item,
+                    n_genome_execs,
+                    count,
+                    count_event,
+                });
+                Ok(())
+            }
+            Err(err) => {
+                // commands queued before the error may still use item and count
+                let _ = self.queues[slot].finish();
+                Err(err)
+            }
+        }
+    }
+    unsafe fn enqueue_chunk(
+        &mut self,
+        slot: usize,
+        item: &SearchChunkInfo,
+        n_genome_execs: usize,
+        n_genome_bytes: usize,
+        count: &mut [u32; 1],
+    ) -> Result<event::Event> {
+        // commands of one queue run in order, so the slot needs no events of its own
+        self.queues[slot].enqueue_write_buffer(
+            &mut self.genome_bufs[slot],
+            CL_NO_BLOCK,
+            0,
+            &item.data[..n_genome_bytes],
+            &[],
+        )?;
+        self.queues[slot].enqueue_write_buffer(
+            &mut self.count_bufs[slot],
+            CL_NO_BLOCK,
+            0,
+            &[0],
+            &[],
+        )?;
+        for group_idx in 0..self.groups.len() {
+            self.enqueue_kernel(slot, group_idx, 0, n_genome_execs)?;
+        }
+        let count_event = self.queues[slot].enqueue_read_buffer(
+            &self.count_bufs[slot],
+            CL_NO_BLOCK,
+            0,
+            &mut count[..],
+            &[],
+        )?;
+        self.queues[slot].flush()?;
+        Ok(count_event)
+    }
+    // waits for the oldest chunk in flight, it is only taken off the queue once its slot is idle
+    unsafe fn finish_chunk(&mut self) -> Result<Option<(Vec<SearchMatch>, SearchChunkInfo)>> {
+        let Some(chunk) = self.in_flight.front() else {
+            return Ok(None);
+        };
+        let (slot, n_genome_execs) = (chunk.slot, chunk.n_genome_execs);
+        chunk.count_event.wait()?;
+        let n_matches = chunk.count[0] as usize;
+        let matches = self.chunk_matches(slot, n_genome_execs, n_matches)?;
+        let chunk = self.in_flight.pop_front().unwrap();
+        Ok(Some((matches, chunk.item)))
+    }
+    unsafe fn chunk_matches(
+        &mut self,
+        slot: usize,
+        n_genome_execs: usize,
+        n_matches: usize,
+    ) -> Result<Vec<SearchMatch>> {
+        if n_matches <= self.out_capacities[slot] {
+            return self.read_matches(slot, n_matches);
+        }
+        // the kernels only counted the hits past capacity, so the chunk is searched again
+        let mut matches: Vec<SearchMatch> = Vec::with_capacity(n_matches);
+        for group_idx in 0..self.groups.len() {
+            matches.extend(self.search_group_split(slot, group_idx, n_genome_execs)?);
+        }
+        Ok(matches)
+    }
+    unsafe fn search_group_split(
+        &mut self,
+        slot: usize,
+        group_idx: usize,
+        n_genome_execs: usize,
+    ) -> Result<Vec<SearchMatch>> {
+        // ranges of work items still to run, an overflowing range is run again in halves
+        let mut matches: Vec<SearchMatch> = Vec::new();
+        let mut exec_ranges = vec![(0, n_genome_execs)];
+        while let Some((first_exec, n_execs)) = exec_ranges.pop() {
+            self.queues[slot].enqueue_write_buffer(
+                &mut self.count_bufs[slot],
+                CL_NO_BLOCK,
+                0,
+                &[0],
+                &[],
+            )?;
+            self.enqueue_kernel(slot, group_idx, first_exec, n_execs)?;
+            let mut count = [0];
+            self.queues[slot].enqueue_read_buffer(
+                &self.count_bufs[slot],
+                CL_BLOCK,
+                0,
+                &mut count,
+                &[],
+            )?;
+            let n_matches = count[0] as usize;
+            if n_matches <= self.out_capacities[slot] {
+                matches.extend(self.read_matches(slot, n_matches)?);
+            } else if n_execs > 1 {
+                let half = n_execs / 2;
+                exec_ranges.push((first_exec + half, n_execs - half));
+                exec_ranges.push((first_exec, half));
+            } else {
+                self.out_bufs[slot] = create_ocl_buf::<SearchMatch>(self.context, n_matches)?;
+                self.out_capacities[slot] = n_matches;
+                exec_ranges.push((first_exec, n_execs));
+            }
+        }
+        Ok(matches)
+    }
+}
+fn send_matches(
+    dest: &mpsc::SyncSender<SearchChunkResult>,
+    matches: Vec<SearchMatch>,
+    item: SearchChunkInfo,
+) -> cli_err::Result<()> {
+    if !matches.is_empty() {
+        dest.send(SearchChunkResult {
+            matches,
+            meta: item.meta,
+            data: item.data,
+            mask: item.mask,
+        })?;
+    }
+    Ok(())
+}
 // fn get_prog_args(pattern_len:)
 fn search_device_ocl(
-    max_mismatches: u32,
-    pattern_len: usize,
-    patterns: Arc<Vec<u8>>,
+    groups: Arc<Vec<PatternGroup>>,
     context: Arc<context::Context>,
-    program: Arc<program::Program>,
+    programs: Arc<Vec<program::Program>>,
     dev: Arc<device::Device>,
     recv: crossbeam_channel::Receiver<SearchChunkInfo>,
     dest: mpsc::SyncSender<SearchChunkResult>,
-) -> Result<()> {
+    out_capacity: usize,
+) -> cli_err::Result<()> {
     unsafe {
-        const OUT_BUF_SIZE: usize = 1 << 22;
-        const CL_BLOCK: u32 = 1;
-        const CL_NO_BLOCK: u32 = 0;
-        let queue = command_queue::CommandQueue::create(&context, dev.id(), 0)?;
-        let kernel = kernel::Kernel::create(&program, "find_matches")?;
-        let mut genome_bufs = create_ocl_bufs::<u8>(&context, SEARCH_CHUNK_SIZE_BYTES)?;
-        let mut out_counts = create_ocl_bufs::<u32>(&context, 1)?;
-        let mut out_bufs = create_ocl_bufs::<SearchMatch>(&context, OUT_BUF_SIZE)?;
-        let mut pattern_buf = create_ocl_buf::<u8>(&context, patterns.len())?;
-        queue.enqueue_write_buffer(&mut pattern_buf, CL_BLOCK, 0, &patterns, &[])?;
-        let pattern_blocked_size = roundup(cdiv(pattern_len, 2), PATTERN_CHUNK_SIZE);
-        assert!(patterns.len() % pattern_blocked_size == 0);
-        let n_patterns = patterns.len() / pattern_blocked_size;
-        for item in recv.iter() {
+        let mut device_search =
+            OclDeviceSearch::new(&groups, &context, &programs, &dev, out_capacity)?;
+        for (item_idx, item) in recv.iter().enumerate() {
+            if device_search.in_flight.len() == MAX_QUEUED {
+                let (matches, done_item) = device_search.finish_chunk()?.unwrap();
+                send_matches(&dest, matches, done_item)?;
+            }
             let n_chunks = std::cmp::min(CHUNKS_PER_SEARCH - 1, item.meta.chr_names.len());
             let n_genome_bytes = n_chunks * CHUNK_SIZE_BYTES;
             let n_genome_blocks = n_genome_bytes / prefered_block_size(&dev)?;
             let n_genome_execs = n_genome_blocks / CL_BLOCKS_PER_EXEC;
-            let cur_genome_buf = &mut genome_bufs[0];
-            let cur_size_buf = &mut out_counts[0];
-            let cur_out_buf = &mut out_bufs[0];
-            let write_event = queue.enqueue_write_buffer(
-                cur_genome_buf,
-                CL_NO_BLOCK,
-                0,
-                &item.data[..n_genome_bytes + CHUNK_SIZE_BYTES],
-                &[],
+            // the genome is uploaded once and then searched by every pattern group
+            device_search.start_chunk(
+                item_idx % MAX_QUEUED,
+                item,
+                n_genome_execs,
+                n_genome_bytes + CHUNK_SIZE_BYTES,
             )?;
-            let clear_count_event =
-                queue.enqueue_write_buffer(cur_size_buf, CL_NO_BLOCK, 0, &[0], &[])?;
-
-            let kernel_event = kernel::ExecuteKernel::new(&kernel)
-                .set_arg(cur_genome_buf)
-                .set_arg(&pattern_buf)
-                .set_arg(&max_mismatches)
-                .set_arg(cur_out_buf)
-                .set_arg(cur_size_buf)
-                .set_global_work_sizes(&[n_genome_execs, n_patterns])
-                .set_wait_event(&write_event)
-                .set_wait_event(&clear_count_event)
-                .enqueue_nd_range(&queue)?;
-            let mut readsize_buf = [0];
-            queue.enqueue_read_buffer(
-                cur_size_buf,
-                CL_BLOCK,
-                0,
-                &mut readsize_buf,
-                &[kernel_event.get()],
-            )?;
-            let readsize = readsize_buf[0];
-            if readsize != 0 {
-                let mut outvec: Vec<SearchMatch> = vec![
-                    SearchMatch {
-                        chunk_idx: 0,
-                        pattern_idx: 0,
-                        mismatches: 0
-                    };
-                    readsize as usize
-                ];
-                queue.enqueue_read_buffer(cur_out_buf, CL_BLOCK, 0, &mut outvec[..], &[])?;
-                dest.send(SearchChunkResult {
-                    matches: outvec,
-                    meta: item.meta,
-                    data: item.data,
-                })
-                .unwrap();
-            }
+        }
+        while let Some((matches, item)) = device_search.finish_chunk()? {
+            send_matches(&dest, matches, item)?;
         }
     }
     Ok(())
@@ -165,17 +483,16 @@ fn get_compile_defs(pattern_len: usize, block_ty: &str) -> String {
 }
 fn search_chunk_ocl(
     devices: OclRunConfig,
-    max_mismatches: u32,
-    pattern_len: usize,
-    patterns: &[Vec<u8>],
+    groups: Vec<PatternGroup>,
     recv: crossbeam_channel::Receiver<SearchChunkInfo>,
     dest: mpsc::SyncSender<SearchChunkResult>,
-) -> Result<()> {
+    out_capacity: usize,
+) -> cli_err::Result<()> {
     /* divies off work to opencl devices */
-    let pattern_arc = Arc::new(pack_patterns(patterns));
+    let groups_arc = Arc::new(groups);
     // let devices = get_all_devices()?;
     // assert!(devices.len()>0, "Needs at least one opencl device to run tests!");
-    let mut threads: Vec<JoinHandle<Result<()>>> = Vec::new();
+    let mut threads: Vec<JoinHandle<cli_err::Result<()>>> = Vec::new();
     for (_, devs) in devices.get().iter() {
         let plat_devs: Vec<*mut std::ffi::c_void> = devs.iter().map(|d| d.id()).collect();
         if !plat_devs.is_empty() {
@@ -189,44 +506,44 @@ fn search_chunk_ocl(
                 .iter()
                 .map(|d| Arc::new(device::Device::new(*d)))
                 .collect();
-            let prog_options = get_compile_defs(pattern_len, prefered_block_type(&p_devices[0])?);
-            let program = Arc::new(
-                program::Program::create_and_build_from_source(
-                    &context,
-                    KERNEL_CONTENTS,
-                    &prog_options,
-                )
-                .map_err(|err| {
-                    eprintln!("{}", err);
-                })
-                .unwrap(),
-            );
+            // PATTERN_LEN is baked into the kernel, so every group gets its own program
+            let mut programs: Vec<program::Program> = Vec::with_capacity(groups_arc.len());
+            for group in groups_arc.iter() {
+                let prog_options =
+                    get_compile_defs(group.pattern_len, prefered_block_type(&p_devices[0])?);
+                programs.push(
+                    program::Program::create_and_build_from_source(
+                        &context,
+                        KERNEL_CONTENTS,
+                        &prog_options,
+                    )
+                    // the error is the build log of the kernel
+                    .map_err(|log| CliError::OclError(format!("kernel build failed:\n{}", log)))?,
+                );
+            }
+            let programs = Arc::new(programs);
 
             for p_dev in p_devices {
                 let t_dest = dest.clone();
                 let t_recv = recv.clone();
                 let t_context = context.clone();
-                let t_prog = program.clone();
-                let t_pattern = pattern_arc.clone();
+                let t_progs = programs.clone();
+                let t_groups = groups_arc.clone();
                 threads.push(thread::spawn(move || {
                     search_device_ocl(
-                        max_mismatches,
-                        pattern_len,
-                        t_pattern,
+                        t_groups,
                         t_context,
-                        t_prog,
+                        t_progs,
                         p_dev,
                         t_recv,
                         t_dest,
+                        out_capacity,
                     )
                 }));
             }
         }
     }
-    for t in threads {
-        t.join().unwrap()?;
-    }
-    Ok(())
+    first_error(threads.into_iter().map(join_worker))
 }
 fn checked_div(x: usize, y: usize) -> usize {
     assert!(x % y == 0);
@@ -240,65 +557,172 @@ fn block_data_cpu(data: &[u8]) -> Vec<u64> {
     data.chunks(8).map(pack).collect()
 }
 fn search_chunk_cpu(
-    max_mismatches: u32,
-    pattern_len: usize,
-    packed_patterns: &[u8],
+    kernel: CpuKernel,
+    group: &PatternGroup,
+    data: &[u8; SEARCH_CHUNK_SIZE_BYTES],
+) -> Vec<SearchMatch> {
+    #[cfg(target_arch = "x86_64")]
+    if cdiv(group.pattern_len, CPU_BLOCK_SIZE * 2) <= MAX_SIMD_PATTERN_BLOCKS {
+        // search() only picks kernels the processor supports
+        match kernel {
+            CpuKernel::Avx512 => unsafe { return search_chunk_avx512(group, data) },
+            CpuKernel::Avx2 => unsafe { return search_chunk_avx2(group, data) },
+            CpuKernel::Scalar => {}
+        }
+    }
+    #[cfg(not(target_arch = "x86_64"))]
+    let _ = kernel;
+    search_chunk_blocks(group, data, count_matches_scalar)
+}
+#[cfg(target_arch = "x86_64")]
+#[target_feature(enable = "avx2")]
+fn search_chunk_avx2(
+    group: &PatternGroup,
     data: &[u8; SEARCH_CHUNK_SIZE_BYTES],
 ) -> Vec<SearchMatch> {
+    search_chunk_blocks(group, data, |window, pattern, min_matches| {
+        count_matches_avx2(window, pattern, min_matches)
+    })
+}
+#[cfg(target_arch = "x86_64")]
+#[target_feature(enable = "avx2,avx512vl,avx512vpopcntdq")]
+fn search_chunk_avx512(
+    group: &PatternGroup,
+    data: &[u8; SEARCH_CHUNK_SIZE_BYTES],
+) -> Vec<SearchMatch> {
+    search_chunk_blocks(group, data, |window, pattern, min_matches| {
+        count_matches_avx512(window, pattern, min_matches)
+    })
+}
+// inlined into every kernel, so that the match counting is compiled with its target features
+#[inline(always)]
+fn search_chunk_blocks(
+    group: &PatternGroup,
+    data: &[u8; SEARCH_CHUNK_SIZE_BYTES],
+    count_matches: impl Fn(&[u64], &[u64], u32) -> BlockCounts,
+) -> Vec<SearchMatch> {
+    const NUCL_PER_BLOCK: usize = 2 * std::mem::size_of::<u64>();
+    let max_mismatches = &group.max_mismatches;
+    let pattern_len = group.pattern_len;
     let mut matches: Vec<SearchMatch> = Vec::new();
     assert!(
         data.as_ptr().align_offset(8) == 0,
         "data must be 8 byte aligned"
     );
     let n_patterns = checked_div(
-        packed_patterns.len(),
+        group.packed_patterns.len(),
         roundup(pattern_len, 2 * PATTERN_CHUNK_SIZE) / 2,
     );
-    let pattern_ptr = block_data_cpu(packed_patterns); //packed_patterns.as_ptr() as *const u64;
+    assert!(n_patterns == max_mismatches.len());
+    let pattern_ptr = block_data_cpu(&group.packed_patterns); //packed_patterns.as_ptr() as *const u64;
     let genome_ptr = block_data_cpu(data); //data.as_ptr() as *const u64;
     let genome_blocks = checked_div(data.len(), CPU_BLOCK_SIZE);
     let pattern_blocks = cdiv(pattern_len, CPU_BLOCK_SIZE * 2);
     let packed_pattern_size = roundup(pattern_blocks, PATTERN_CHUNK_SIZE / CPU_BLOCK_SIZE);
-    // assert!(pattern_blocks == 2);
-    // assert!(n_patterns == 2);
-    const NUCL_PER_BLOCK: usize = 2 * std::mem::size_of::<u64>();
-    const BLOCKS_PER_EXEC: usize = 4;
-    let mut shifted_data = vec![0_u64; BLOCKS_PER_EXEC + pattern_blocks + 1];
+    // patterns of one strand usually share their filter, which is then checked only once
+    let filter_ptr = block_data_cpu(&group.packed_filters);
+    let mut filters: Vec<&[u64]> = Vec::new();
+    let filter_of: Vec<usize> = (0..n_patterns)
+        .map(|j| {
+            let filter = &filter_ptr[j * packed_pattern_size..][..pattern_blocks];
+            filters
+                .iter()
+                .position(|f| *f == filter)
+                .unwrap_or_else(|| {
+                    filters.push(filter);
+                    filters.len() - 1
+                })
+        })
+        .collect();
+    let filter_cares: Vec<Vec<u64>> = filters
+        .iter()
+        .map(|filter| filter.iter().map(|f| nonzero_nibbles(*f)).collect())
+        .collect();
+    // filter nucleotides other than N and don't cares, with their index in the pattern
+    let selective_nucls: Vec<Vec<(usize, u64)>> = filters
+        .iter()
+        .map(|filter| {
+            (0..pattern_blocks * NUCL_PER_BLOCK)
+                .map(|i| {
+                    (
+                        i,
+                        (filter[i / NUCL_PER_BLOCK] >> (4 * (i % NUCL_PER_BLOCK))) & 0xf,
+                    )
+                })
+                .filter(|(_, nucl)| *nucl != 0 && *nucl != 0xf)
+                .collect()
+        })
+        .collect();
+    let patterns_of: Vec<Vec<usize>> = (0..filters.len())
+        .map(|f| (0..n_patterns).filter(|j| filter_of[*j] == f).collect())
+        .collect();
+    // a hit needs at least this many matching nucleotides
+    let min_matches: Vec<u32> = max_mismatches
+        .iter()
+        .map(|max_mm| (pattern_len as u32).saturating_sub(*max_mm))
+        .collect();
+    let mut genome_blocks_buf = vec![0_u64; BLOCKS_PER_EXEC + pattern_blocks + 1];
+    let mut shifted_data = vec![0_u64; BLOCKS_PER_EXEC + pattern_blocks - 1];
     for gen_block_idx in 0..checked_div(genome_blocks, BLOCKS_PER_EXEC) {
         let gen_idx = gen_block_idx * BLOCKS_PER_EXEC;
-        shifted_data.fill(0);
+        genome_blocks_buf.fill(0);
         let n_copy = std::cmp::min(
             BLOCKS_PER_EXEC + pattern_blocks + 1,
             genome_blocks - gen_idx,
         );
-        shifted_data[..n_copy].copy_from_slice(&genome_ptr[gen_idx..][..n_copy]);
-        for l in 0..NUCL_PER_BLOCK {
-            for j in 0..n_patterns {
-                let mut num_matches = [0_u32; BLOCKS_PER_EXEC];
-                for k in 0..pattern_blocks {
-                    for o in 0..BLOCKS_PER_EXEC {
-                        num_matches[o] += (shifted_data[k + o]
-                            & pattern_ptr[j * packed_pattern_size + k])
-                            .count_ones()
-                    }
+        genome_blocks_buf[..n_copy].copy_from_slice(&genome_ptr[gen_idx..][..n_copy]);
+        let group_start = matches.len();
+        for (f, nucls) in selective_nucls.iter().enumerate() {
+            // bit o * NUCL_PER_BLOCK + l is set if the position of lane o and shift l passes
+            let passing = nucls.iter().fold(u64::MAX, |pass, (i, nucl)| {
+                let blocks = &genome_blocks_buf[i / NUCL_PER_BLOCK..][..BLOCKS_PER_EXEC + 1];
+                let matched = blocks.iter().rev().fold(0_u128, |mask, block| {
+                    (mask << NUCL_PER_BLOCK) | nucl_match_mask(*block, *nucl) as u128
+                });
+                pass & (matched >> (i % NUCL_PER_BLOCK)) as u64
+            });
+            // only the shifts where some lane passes are scored
+            let mut shifts = (0..BLOCKS_PER_EXEC)
+                .fold(0, |shifts, o| shifts | (passing >> (o * NUCL_PER_BLOCK)))
+                & 0xffff;
+            while shifts != 0 {
+                let l = shifts.trailing_zeros() as usize;
+                shifts &= shifts - 1;
+                for (k, block) in shifted_data.iter_mut().enumerate() {
+                    // the split shift keeps l == 0 from shifting by the full width
+                    *block = (genome_blocks_buf[k] >> (4 * l))
+                        | ((genome_blocks_buf[k + 1] << 1) << (4 * (NUCL_PER_BLOCK - l) - 1));
                 }
-                for o in 0..BLOCKS_PER_EXEC {
-                    let mismatches = pattern_len as u32 - num_matches[o];
-                    if mismatches <= max_mismatches {
-                        matches.push(SearchMatch {
-                            chunk_idx: ((gen_idx + o) * NUCL_PER_BLOCK + l) as u32,
-                            pattern_idx: j as u32,
-                            mismatches: mismatches,
-                        });
+                for &j in patterns_of[f].iter() {
+                    let pattern = &pattern_ptr[j * packed_pattern_size..][..pattern_blocks];
+                    let Some(num_matches) = count_matches(&shifted_data, pattern, min_matches[j])
+                    else {
+                        continue;
+                    };
+                    // N and the other unselective filter nucleotides are checked on the hits
+                    let lanes = filter_lanes_scalar(&shifted_data, filters[f], &filter_cares[f]);
+                    for (o, n_matched) in num_matches.iter().enumerate() {
+                        let mismatches = pattern_len as u32 - n_matched;
+                        let passes = (passing >> (o * NUCL_PER_BLOCK + l)) & 1 != 0;
+                        if passes && lanes & (1 << o) != 0 && mismatches <= max_mismatches[j] {
+                            matches.push(SearchMatch {
+                                chunk_idx: ((gen_idx + o) * NUCL_PER_BLOCK + l) as u32,
+                                pattern_idx: j as u32,
+                                mismatches,
+                            });
+                        }
                     }
                 }
             }
-            for k in 0..(pattern_blocks + BLOCKS_PER_EXEC - 1) {
-                shifted_data[k] >>= 4;
-                shifted_data[k] |= shifted_data[k + 1] << (4 * (NUCL_PER_BLOCK - 1));
-            }
-            shifted_data[pattern_blocks + BLOCKS_PER_EXEC - 1] >>= 4;
         }
+        // hits are reported by shift and pattern, however the filters split them
+        matches[group_start..].sort_unstable_by_key(|m| {
+            (
+                m.chunk_idx as usize % NUCL_PER_BLOCK,
+                m.pattern_idx,
+                m.chunk_idx,
+            )
+        });
     }
     matches
 }
@@ -317,74 +741,157 @@ fn pack_patterns(patterns: &[Vec<u8>]) -> Vec<u8> {
         .collect()
 }
 fn search_device_cpu_thread(
-    max_mismatches: u32,
-    pattern_len: usize,
-    packed_patterns: Arc<Vec<u8>>,
+    kernel: CpuKernel,
+    groups: Arc<Vec<PatternGroup>>,
     recv: crossbeam_channel::Receiver<SearchChunkInfo>,
     dest: mpsc::SyncSender<SearchChunkResult>,
-) {
+) -> cli_err::Result<()> {
     for schunk in recv.iter() {
+        let mut matches: Vec<SearchMatch> = Vec::new();
+        for group in groups.iter() {
+            matches.extend(group.to_global(search_chunk_cpu(kernel, group, &schunk.data)));
+        }
         dest.send(SearchChunkResult {
-            matches: search_chunk_cpu(max_mismatches, pattern_len, &packed_patterns, &schunk.data),
+            matches,
             meta: schunk.meta,
             data: schunk.data,
-        })
-        .unwrap();
+            mask: schunk.mask,
+        })?;
     }
+    Ok(())
 }
 fn search_compute_cpu(
-    max_mismatches: u32,
-    pattern_len: usize,
-    patterns: &[Vec<u8>],
+    groups: Vec<PatternGroup>,
     recv: crossbeam_channel::Receiver<SearchChunkInfo>,
     dest: mpsc::SyncSender<SearchChunkResult>,
-) {
+    n_threads: usize,
+    kernel: CpuKernel,
+) -> cli_err::Result<()> {
     /* divies off work to threads devices */
-    let pattern_arc = Arc::new(pack_patterns(patterns));
-    let n_threads = thread::available_parallelism().unwrap().into();
-    let mut threads: Vec<thread::JoinHandle<()>> = Vec::new();
+    let groups_arc = Arc::new(groups);
+    let mut threads: Vec<JoinHandle<cli_err::Result<()>>> = Vec::new();
     for _ in 0..n_threads {
-        let tpattners = pattern_arc.clone();
+        let tgroups = groups_arc.clone();
         let trecv = recv.clone();
         let tdest = dest.clone();
         threads.push(thread::spawn(move || {
-            search_device_cpu_thread(max_mismatches, pattern_len, tpattners, trecv, tdest)
+            search_device_cpu_thread(kernel, tgroups, trecv, tdest)
         }));
     }
-    for t in threads {
-        t.join().unwrap();
-    }
+    first_error(threads.into_iter().map(join_worker))
 }
 
-fn chunks_to_searchchunk(chunk_buf: &[ChromChunkInfo]) -> SearchChunkInfo {
+fn chunks_to_searchchunk(chunk_buf: &[ChromChunkInfo]) -> cli_err::Result<SearchChunkInfo> {
     let mut search_buf = Box::new([0_u8; SEARCH_CHUNK_SIZE_BYTES]);
+    let mut mask_buf = Box::new([0_u8; SEARCH_CHUNK_MASK_BYTES]);
     let mut names: Vec<String> = Vec::with_capacity(CHUNKS_PER_SEARCH);
     let mut starts: Vec<u64> = Vec::with_capacity(CHUNKS_PER_SEARCH);
     let mut ends: Vec<u64> = Vec::with_capacity(CHUNKS_PER_SEARCH);
     // only takes  CHUNKS_PER_SEARCH-1 chunks because you don't want to leave any hanging data on the end
     for (idx, chunk) in chunk_buf.iter().enumerate() {
-        assert!(
-            idx == 0 || *ends.last().unwrap() == chunk.chunk_start || chunk.chunk_start == 0,
-            "search expects chromosome chunks to arrive in order"
-        );
+        // a chromosome name repeated in the genome starts over at position 0
+        if idx != 0
+            && *names.last().unwrap() == chunk.chr_name
+            && *ends.last().unwrap() > chunk.chunk_start
+        {
+            return Err(CliError::BadFileFormat(
+                "chromosome chunks arrive out of order, is a chromosome name repeated?",
+            ));
+        }
         search_buf[idx * CHUNK_SIZE_BYTES..(idx + 1) * CHUNK_SIZE_BYTES]
             .copy_from_slice(&chunk.data[..]);
+        mask_buf[idx * CHUNK_MASK_BYTES..(idx + 1) * CHUNK_MASK_BYTES]
+            .copy_from_slice(&chunk.mask[..]);
         names.push(chunk.chr_name.clone());
         starts.push(chunk.chunk_start);
         ends.push(chunk.chunk_end);
     }
-    SearchChunkInfo {
+    Ok(SearchChunkInfo {
         data: search_buf,
+        mask: mask_buf,
         meta: SearchChunkMeta {
             chr_names: names,
             chunk_starts: starts,
             chunk_ends: ends,
         },
+    })
+}
+fn count_mismatches(dna: &[u8], rna: &[u8]) -> u32 {
+    dna.iter()
+        .zip(rna.iter())
+        .filter(|(d, r)| **d != b'-' && **r != b'-' && !cmp_chars(**d, **r))
+        .count() as u32
+}
+fn count_masked(dna: &[u8], rna: &[u8], mask: &[u8], genome_start: usize) -> u32 {
+    /* counts masked genome bases aligned to the non-N part of the guide */
+    let mut genome_pos = genome_start;
+    let mut masked_bases = 0;
+    for (d, r) in dna.iter().zip(rna.iter()) {
+        if *d == b'-' {
+            continue;
+        }
+        if *r != b'N' && getmaskbit(mask, genome_pos) {
+            masked_bases += 1;
+        }
+        genome_pos += 1;
+    }
+    masked_bases
+}
+// the hit of a variant at nucleotide data_idx of a 4 bit genome and its soft-mask
+#[allow(clippy::too_many_arguments)]
+pub(crate) fn variant_match(
+    patterns: &[Vec<u8>],
+    variant: &PatternVariant,
+    data: &[u8],
+    mask: &[u8],
+    data_idx: usize,
+    chr_name: &str,
+    pos: u64,
+    kernel_mismatches: u32,
+) -> Match {
+    let pattern_len = variant.pattern_len;
+    let dna_len = variant.dna_len();
+    let is_forward = (variant.src_idx as usize) < patterns.len() / 2;
+    let mut dna_result: Vec<u8> = vec![0_u8; dna_len];
+    let mut rna_result: Vec<u8> = vec![0_u8; pattern_len];
+    bit4_to_string(&mut dna_result, data, data_idx, dna_len);
+    bit4_to_string(
+        &mut rna_result,
+        &patterns[variant.src_idx as usize][..],
+        0,
+        pattern_len,
+    );
+    let (mut dna_result, mut rna_result) = gapped_alignment(variant, &dna_result, &rna_result);
+    // the kernels count the padding as mismatches, so recount over the aligned bases only
+    let mismatches = if variant.padding != 0 {
+        count_mismatches(&dna_result, &rna_result)
+    } else {
+        kernel_mismatches
+    };
+    let masked_bases = count_masked(&dna_result, &rna_result, mask, data_idx);
+    if !is_forward {
+        reverse_compliment_char_i(&mut dna_result);
+        reverse_compliment_char_i(&mut rna_result);
+    }
+    let cfd_score = cfd_score(&dna_result, &rna_result);
+    Match {
+        chr_name: chr_name.to_string(),
+        chrom_idx: pos,
+        pattern_idx: variant.src_idx,
+        mismatches,
+        is_forward,
+        dna_seq: dna_result,
+        rna_seq: rna_result,
+        bulge_type: variant.bulge_type,
+        bulge_size: variant.bulge_size,
+        masked_bases,
+        cfd_score,
+        annotation: None,
     }
 }
 fn convert_matches(
-    pattern_len: usize,
-    patterns: &Vec<Vec<u8>>,
+    patterns: &[Vec<u8>],
+    variants: &[PatternVariant],
     search_res: SearchChunkResult,
 ) -> Vec<Match> {
     let n_blocks = search_res.meta.chr_names.len();
@@ -393,59 +900,76 @@ fn convert_matches(
     assert!(n_blocks == search_res.meta.chr_names.len());
     let mut results: Vec<Match> = Vec::new();
     for smatch in search_res.matches.iter() {
+        let variant = &variants[smatch.pattern_idx as usize];
+        let dna_len = variant.dna_len();
         let idx = smatch.chunk_idx as usize / CHUNK_SIZE;
         let offset = smatch.chunk_idx as usize % CHUNK_SIZE;
         let pos = search_res.meta.chunk_starts[idx] + offset as u64;
         //skip anything in the last chunk, it will be repeated again in the next search item
         let is_last_chunk = idx == CHUNKS_PER_SEARCH - 1;
+        // chunks can be skipped by region filters, so the next chunk may not continue this one
         let is_end_chrom = idx == search_res.meta.chr_names.len() - 1
-            || search_res.meta.chunk_starts[idx + 1] == 0;
-        let is_past_end = pos + pattern_len as u64 > search_res.meta.chunk_ends[idx];
+            || !search_res.meta.continues_previous(idx + 1);
+        let is_past_end = pos + dna_len as u64 > search_res.meta.chunk_ends[idx];
         if !is_last_chunk && !(is_end_chrom && is_past_end) {
-            let is_forward = (smatch.pattern_idx as usize) < patterns.len() / 2;
-            let mut dna_result: Vec<u8> = vec![0_u8; pattern_len];
-            let mut rna_result: Vec<u8> = vec![0_u8; pattern_len];
-            bit4_to_string(
-                &mut dna_result,
+            results.push(variant_match(
+                patterns,
+                variant,
                 &search_res.data[..],
+                &search_res.mask[..],
                 smatch.chunk_idx as usize,
-                pattern_len,
-            );
-            bit4_to_string(
-                &mut rna_result,
-                &patterns[smatch.pattern_idx as usize][..],
-                0,
-                pattern_len,
-            );
-            if !is_forward {
-                reverse_compliment_char_i(&mut dna_result);
-                reverse_compliment_char_i(&mut rna_result);
-            }
-            results.push(Match {
-                chr_name: search_res.meta.chr_names[idx].clone(),
-                chrom_idx: pos,
-                pattern_idx: smatch.pattern_idx,
-                mismatches: smatch.mismatches,
-                is_forward: is_forward,
-                dna_seq: dna_result,
-                rna_seq: rna_result,
-            });
+                &search_res.meta.chr_names[idx],
+                pos,
+                smatch.mismatches,
+            ));
         }
     }
     results
 }
 
+#[allow(clippy::too_many_arguments)]
 pub fn search(
     devices: OclRunConfig,
-    max_mismatches: u32,
-    pattern_len: usize,
+    max_mismatches: &[u32],
+    max_dna_bulge: u32,
+    max_rna_bulge: u32,
+    pattern_lens: &[usize],
     patterns: &Vec<Vec<u8>>,
+    search_filters: &[Vec<u8>],
     recv: mpsc::Receiver<ChromChunkInfo>,
     dest: mpsc::SyncSender<Vec<Match>>,
-) {
-    /* public facing function, sends and receives data chunk by chunk */
-    assert!(!patterns.is_empty());
-    assert!(patterns[0].len() * 2 >= pattern_len);
+) -> cli_err::Result<()> {
+    /* public facing function, sends and receives data chunk by chunk
+    search_filters are 4 bit encoded like the patterns, positions not matching the
+    filter of a pattern are skipped before its mismatches are counted */
+    if patterns.is_empty() {
+        return Err(CliError::ArgumentError("At least one pattern is needed"));
+    }
+    if patterns.len() != pattern_lens.len()
+        || patterns
+            .iter()
+            .zip(pattern_lens)
+            .any(|(p, l)| p.len() * 2 < *l)
+    {
+        return Err(CliError::ArgumentError(
+            "Every pattern needs its own length, at most two nucleotides per byte",
+        ));
+    }
+    if patterns.len() != max_mismatches.len() {
+        return Err(CliError::ArgumentError(
+            "Every pattern needs its own mismatch threshold",
+        ));
+    }
+    if patterns.len() != search_filters.len()
+        || patterns
+            .iter()
+            .zip(search_filters)
+            .any(|(p, f)| p.len() != f.len())
+    {
+        return Err(CliError::ArgumentError(
+            "Every pattern needs its own search filter of the same length",
+        ));
+    }
     let (compute_send_src, compute_recv_src): (
         crossbeam_channel::Sender<SearchChunkInfo>,
         crossbeam_channel::Receiver<SearchChunkInfo>,
@@ -454,13 +978,38 @@ pub fn search(
         mpsc::SyncSender<SearchChunkResult>,
         mpsc::Receiver<SearchChunkResult>,
     ) = mpsc::sync_channel(4);
-    assert!(
-        patterns.iter().all(|p| p.len() == patterns[0].len()),
-        "All patterns must have same length"
+    let mut variants = expand_bulges(patterns, pattern_lens, max_dna_bulge, max_rna_bulge);
+    // variants of equal length are searched as one group
+    variants.sort_by_key(|v| v.search_len);
+    let variant_lens: Vec<usize> = variants.iter().map(|v| v.search_len).collect();
+    let variant_patterns: Vec<Vec<u8>> = variants.iter().map(|v| v.pattern.clone()).collect();
+    // the padding never matches, so every padded position is one more mismatch to allow
+    let variant_mismatches: Vec<u32> = variants
+        .iter()
+        .map(|v| max_mismatches[v.src_idx as usize] + v.padding as u32)
+        .collect();
+    let variant_filters: Vec<Vec<u8>> = variants
+        .iter()
+        .map(|v| {
+            let mut filter_chars = vec![0_u8; v.pattern_len];
+            bit4_to_string(
+                &mut filter_chars,
+                &search_filters[v.src_idx as usize],
+                0,
+                v.pattern_len,
+            );
+            variant_search_filter(v, &filter_chars)
+        })
+        .collect();
+    let groups = group_patterns(
+        &variant_lens,
+        &variant_patterns,
+        &variant_filters,
+        &variant_mismatches,
     );
     let send_thread = thread::Builder::new()
         .stack_size(SEARCH_CHUNK_SIZE_BYTES * 2)
-        .spawn(move || {
+        .spawn(move || -> cli_err::Result<()> {
             let mut buf: Vec<ChromChunkInfo> = Vec::with_capacity(CHUNKS_PER_SEARCH);
             loop {
                 let res = recv.recv();
@@ -468,7 +1017,7 @@ pub fn search(
                     Ok(chunk) => {
                         buf.push(chunk);
                         if buf.len() == CHUNKS_PER_SEARCH {
-                            compute_send_src.send(chunks_to_searchchunk(&buf)).unwrap();
+                            compute_send_src.send(chunks_to_searchchunk(&buf)?)?;
                             let last_el = buf.pop().unwrap();
                             buf.clear();
                             //last element is now first element so that no patterns are cut off
@@ -481,42 +1030,41 @@ pub fn search(
                 }
             }
             if !buf.is_empty() {
-                compute_send_src.send(chunks_to_searchchunk(&buf)).unwrap();
+                compute_send_src.send(chunks_to_searchchunk(&buf)?)?;
             }
-        })
-        .unwrap();
+            Ok(())
+        })?;
     let patern_clone = patterns.clone();
-    let recv_thread = thread::spawn(move || {
+    let recv_thread = thread::spawn(move || -> cli_err::Result<()> {
         for search_chunk in compute_recv_dest.iter() {
-            dest.send(convert_matches(pattern_len, &patern_clone, search_chunk))
-                .unwrap();
+            dest.send(convert_matches(&patern_clone, &variants, search_chunk))?;
         }
+        Ok(())
     });
-    if devices.is_empty() {
+    let compute_res = if devices.is_empty() {
+        let n_threads = devices.n_search_threads(SCAN_RESERVED_THREADS);
+        let kernel = devices.get_cpu_kernel();
         search_compute_cpu(
-            max_mismatches,
-            pattern_len,
-            patterns,
+            groups,
             compute_recv_src,
             compute_send_dest,
-        );
+            n_threads,
+            kernel,
+        )
     } else {
-        match search_chunk_ocl(
+        search_chunk_ocl(
             devices,
-            max_mismatches,
-            pattern_len,
-            patterns,
+            groups,
             compute_recv_src,
             compute_send_dest,
-        ) {
-            Ok(_) => {}
-            Err(err_int) => {
-                panic!("{}", err_int.to_string())
-            }
-        };
-    }
-    send_thread.join().unwrap();
-    recv_thread.join().unwrap();
+            OUT_BUF_SIZE,
+        )
+    };
+    first_error([
+        compute_res,
+        join_worker(send_thread),
+        join_worker(recv_thread),
+    ])
 }
 
 #[cfg(test)]
@@ -579,19 +1127,204 @@ mod tests {
         string_to_bit4(&mut pattern3_bit4, &pattern3, 0, true);
         string_to_bit4(&mut pattern4_bit4, &pattern4, 0, true);
         let patterns = vec![pattern1_bit4, pattern2_bit4, pattern3_bit4, pattern4_bit4];
-        let max_mismatches = 11;
+        let max_mismatches = vec![11; patterns.len()];
         let expected_results_per_file = 117;
         let expected_results = expected_results_per_file * NUM_ITERS;
-        let pattern_len = pattern2.len();
+        let pattern_lens = vec![pattern2.len(); patterns.len()];
+        // filters without any bits keep every position
+        let search_filters = vec![vec![0_u8; 12]; patterns.len()];
         search(
             OclRunConfig::new(OclDeviceType::CPU).unwrap(),
-            max_mismatches,
-            pattern_len,
+            &max_mismatches,
+            0,
+            0,
+            &pattern_lens,
             &patterns,
+            &search_filters,
             src_receiver,
             dest_sender,
-        );
+        )
+        .unwrap();
         send_thread.join().unwrap();
         assert_eq!(result_count.join().unwrap(), expected_results);
     }
+    // the chromosome chunks of upstream1000.2bit, one per search chunk so that several are
+    // in flight at once, and pattern groups with many hits on them
+    fn upstream_search() -> (Vec<SearchChunkInfo>, Vec<PatternGroup>) {
+        let (chunk_sender, chunk_receiver) = mpsc::sync_channel::<ChromChunkInfo>(4);
+        let read_thread = thread::spawn(move || {
+            read_2bit(
+                &chunk_sender,
+                Path::new("tests/test_data/upstream1000.2bit"),
+            )
+        });
+        let items = chunk_receiver
+            .iter()
+            .map(|chunk| chunks_to_searchchunk(std::slice::from_ref(&chunk)).unwrap())
+            .collect();
+        read_thread.join().unwrap().unwrap();
+        let patterns: Vec<Vec<u8>> = vec![
+            b"CCGTGGTTCAACATTTGCTTAGCA".to_vec(),
+            b"GATGTTGGTAAGTGGGATATGGCA".to_vec(),
+        ];
+        let (pattern_lens, patterns_4bit) = crate::encode_patterns(&patterns);
+        let filters_4bit = vec![vec![0_u8; 12]; patterns_4bit.len()];
+        let max_mismatches = vec![11; patterns_4bit.len()];
+        let groups = group_patterns(
+            &pattern_lens,
+            &patterns_4bit,
+            &filters_4bit,
+            &max_mismatches,
+        );
+        (items, groups)
+    }
+    #[test]
+    fn test_ocl_match_buffer_overflow() {
+        let search_with_capacity = |out_capacity: usize| -> Vec<(String, u64, u32, u32, u32)> {
+            let (items, groups) = upstream_search();
+            let (item_sender, item_receiver) = crossbeam_channel::bounded(MAX_QUEUED);
+            let (result_sender, result_receiver) = mpsc::sync_channel(4);
+            let send_thread = thread::spawn(move || {
+                for item in items {
+                    item_sender.send(item).unwrap();
+                }
+            });
+            let collect_thread = thread::spawn(move || {
+                let mut hits: Vec<(String, u64, u32, u32, u32)> = result_receiver
+                    .iter()
+                    .flat_map(|result: SearchChunkResult| {
+                        let (chr_name, start) = (
+                            result.meta.chr_names[0].clone(),
+                            result.meta.chunk_starts[0],
+                        );
+                        result.matches.into_iter().map(move |m| {
+                            (
+                                chr_name.clone(),
+                                start,
+                                m.chunk_idx,
+                                m.pattern_idx,
+                                m.mismatches,
+                            )
+                        })
+                    })
+                    .collect();
+                hits.sort();
+                hits
+            });
+            search_chunk_ocl(
+                OclRunConfig::new(OclDeviceType::ALL).unwrap(),
+                groups,
+                item_receiver,
+                result_sender,
+                out_capacity,
+            )
+            .unwrap();
+            send_thread.join().unwrap();
+            collect_thread.join().unwrap()
+        };
+        let expected = search_with_capacity(OUT_BUF_SIZE);
+        // with a capacity of 1 some chunks overflow, are split and grow their buffer
+        assert!(expected.len() > upstream_search().0.len());
+        assert_eq!(search_with_capacity(1), expected);
+    }
+    #[test]
+    fn test_ocl_receiver_dropped() {
+        const NUM_ITERS: usize = 4;
+        let (item_sender, item_receiver) = crossbeam_channel::bounded(MAX_QUEUED);
+        let (result_sender, result_receiver) = mpsc::sync_channel(0);
+        let send_thread = thread::spawn(move || {
+            for _ in 0..NUM_ITERS {
+                for item in upstream_search().0 {
+                    // the search stops early, so the remaining chunks cannot be sent
+                    if item_sender.send(item).is_err() {
+                        return;
+                    }
+                }
+            }
+        });
+        // takes one result and hangs up while other chunks are still in flight
+        let recv_thread = thread::spawn(move || {
+            let _: SearchChunkResult = result_receiver.recv().unwrap();
+        });
+        let res = search_chunk_ocl(
+            OclRunConfig::new(OclDeviceType::ALL).unwrap(),
+            upstream_search().1,
+            item_receiver,
+            result_sender,
+            OUT_BUF_SIZE,
+        );
+        recv_thread.join().unwrap();
+        send_thread.join().unwrap();
+        assert!(matches!(res, Err(CliError::SendError(_))));
+    }
+    #[test]
+    fn test_search_bad_arguments() {
+        let (_, src_receiver) = mpsc::sync_channel::<ChromChunkInfo>(1);
+        let (dest_sender, _) = mpsc::sync_channel::<Vec<Match>>(1);
+        let (pattern_lens, patterns) =
+            crate::encode_patterns(&[b"ATGTGGGCTCCCTCCACGCCNNN".to_vec()]);
+        let res = search(
+            OclRunConfig::new(OclDeviceType::CPU).unwrap(),
+            &[4],
+            0,
+            0,
+            &pattern_lens,
+            &patterns,
+            &patterns,
+            src_receiver,
+            dest_sender,
+        );
+        // one threshold for two patterns
+        assert!(matches!(res, Err(CliError::ArgumentError(_))));
+    }
+    #[test]
+    fn test_cpu_kernels_agree() {
+        let bases = b"ACGTACGTACGTACGTN";
+        let mut state = 0x9e3779b97f4a7c15_u64;
+        let mut genome: Vec<u8> = (0..SEARCH_CHUNK_SIZE)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 7;
+                state ^= state << 17;
+                bases[(state % bases.len() as u64) as usize]
+            })
+            .collect();
+        // plants a target, so there is at least one exact hit
+        genome[1000..1023].copy_from_slice(b"ATGTGGGCTCCCTCCACGCCAGG");
+        let mut data: Box<[u8; SEARCH_CHUNK_SIZE_BYTES]> =
+            vec![0_u8; SEARCH_CHUNK_SIZE_BYTES].try_into().unwrap();
+        string_to_bit4(&mut data[..], &genome, 0, false);
+        let patterns: Vec<Vec<u8>> = vec![
+            b"ATGTGGGCTCCCTCCACGCCNGG".to_vec(),
+            b"GGCCGACCTGTCGCTGACGCNRG".to_vec(),
+        ];
+        let max_mismatches = vec![6, 8];
+        let (pattern_lens, patterns_4bit) = crate::encode_patterns(&patterns);
+        let (_, filters_4bit) =
+            crate::encode_patterns(&vec![b"NNNNNNNNNNNNNNNNNNNNNRG".to_vec(); 2]);
+        let groups = group_patterns(
+            &pattern_lens[..2],
+            &patterns_4bit[..2],
+            &filters_4bit[..2],
+            &max_mismatches,
+        );
+        let run_kernel = |kernel: CpuKernel| -> Vec<(u32, u32, u32)> {
+            search_chunk_cpu(kernel, &groups[0], &data)
+                .iter()
+                .map(|m| (m.chunk_idx, m.pattern_idx, m.mismatches))
+                .collect()
+        };
+        let expected = run_kernel(CpuKernel::Scalar);
+        assert!(expected.contains(&(1000, 0, 0)));
+        // every hit is followed by the RG of the search filter
+        assert!(expected.iter().all(|(idx, _, _)| {
+            let pam = &genome[*idx as usize + 21..][..2];
+            (pam[0] == b'A' || pam[0] == b'G') && pam[1] == b'G'
+        }));
+        for kernel in [CpuKernel::Avx2, CpuKernel::Avx512] {
+            if kernel.is_supported() {
+                assert_eq!(run_kernel(kernel), expected);
+            }
+        }
+    }
 }
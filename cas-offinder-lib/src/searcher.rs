@@ -0,0 +1,398 @@
+use crate::chrom_chunk::{ChromChunkInfo, Match};
+use crate::chrom_filter::ChromFilter;
+use crate::cli_err::{first_error, join_worker, CliError, Result};
+use crate::read_2bit::read_2bit_filtered;
+use crate::read_fasta::{is_gzip_file, read_fasta_filtered};
+use crate::read_fasta_folder::read_fasta_folder_filtered;
+use crate::read_sequences::read_sequences_filtered;
+use crate::run_config::{OclDeviceType, OclRunConfig};
+use crate::search::search;
+use crate::{cdiv, reverse_compliment_char, string_to_bit4};
+use std::fs::{self, File};
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc;
+use std::thread::{self, JoinHandle};
+
+/*
+High level search API. Searcher bundles the plumbing of a search run: reading
+the genome in its own thread, encoding the guides for both strands, running
+search() and labelling the hits with their guides.
+
+    let hits = Searcher::new("hg38.2bit")
+        .search_filter(b"NNNNNNNNNNNNNNNNNNNNNRG")
+        .guide("EMX1", b"GAGTCCGAGCAGAAGAAGAANNN", 4)
+        .run()?;
+    for hit in hits {
+        let hit = hit?;
+        println!("{} {} {}", hit.label, hit.hit.chr_name, hit.hit.chrom_idx);
+    }
+
+Searcher::from_sequences searches named sequences held in memory instead of a genome file.
+*/
+
+pub fn read_genome(
+    dest: &mpsc::SyncSender<ChromChunkInfo>,
+    genome_path: &Path,
+    chrom_filter: &ChromFilter,
+) -> Result<()> {
+    /* reads a folder of fasta files, a (gzipped) fasta file or a 2bit file */
+    if fs::metadata(genome_path)?.is_dir() {
+        return read_fasta_folder_filtered(dest, genome_path, chrom_filter);
+    }
+    let mut first_byte = [0_u8; 1];
+    File::open(genome_path)?.read_exact(&mut first_byte)?;
+    // compressed genomes are always fasta, 2bit files are read uncompressed
+    if first_byte[0] == b'>' || is_gzip_file(genome_path)? {
+        read_fasta_filtered(dest, genome_path, chrom_filter)
+    } else {
+        read_2bit_filtered(dest, genome_path, chrom_filter)
+    }
+}
+
+// lengths and 4 bit encodings of the patterns followed by their reverse complements,
+// the layout search() expects
+pub fn encode_patterns(patterns: &[Vec<u8>]) -> (Vec<usize>, Vec<Vec<u8>>) {
+    let all_patterns: Vec<Vec<u8>> = patterns
+        .iter()
+        .cloned()
+        .chain(patterns.iter().map(|p| reverse_compliment_char(p)))
+        .collect();
+    let pattern_lens = all_patterns.iter().map(|p| p.len()).collect();
+    let patterns_4bit = all_patterns
+        .iter()
+        .map(|pat| {
+            let mut buf = vec![0_u8; cdiv(pat.len(), 2)];
+            string_to_bit4(&mut buf, pat, 0, true);
+            buf
+        })
+        .collect();
+    (pattern_lens, patterns_4bit)
+}
+
+pub struct Guide {
+    pub label: String,
+    pub pattern: Vec<u8>,
+    pub max_mismatches: u32,
+}
+
+pub struct SearchHit {
+    // index of the guide, in the order the guides were added
+    pub guide_idx: usize,
+    pub label: String,
+    pub hit: Match,
+}
+
+enum GenomeSource {
+    Path(PathBuf),
+    Sequences(Vec<(String, Vec<u8>)>),
+}
+
+pub struct Searcher {
+    genome: GenomeSource,
+    search_filters: Vec<Vec<u8>>,
+    guides: Vec<Guide>,
+    max_dna_bulge: u32,
+    max_rna_bulge: u32,
+    dev_ty: OclDeviceType,
+    dev_ids: Vec<usize>,
+    n_threads: Option<usize>,
+    chrom_filter: ChromFilter,
+}
+
+impl Searcher {
+    pub fn new(genome_path: impl AsRef<Path>) -> Searcher {
+        Searcher::with_genome(GenomeSource::Path(genome_path.as_ref().to_path_buf()))
+    }
+    // searches (name, sequence) pairs, e.g. plasmid maps or amplicons
+    pub fn from_sequences(sequences: &[(&str, &[u8])]) -> Searcher {
+        Searcher::with_genome(GenomeSource::Sequences(
+            sequences
+                .iter()
+                .map(|(name, seq)| (name.to_string(), seq.to_vec()))
+                .collect(),
+        ))
+    }
+    fn with_genome(genome: GenomeSource) -> Searcher {
+        Searcher {
+            genome,
+            search_filters: Vec::new(),
+            guides: Vec::new(),
+            max_dna_bulge: 0,
+            max_rna_bulge: 0,
+            dev_ty: OclDeviceType::ALL,
+            dev_ids: Vec::new(),
+            n_threads: None,
+            chrom_filter: ChromFilter::default(),
+        }
+    }
+    // one search filter per guide length, e.g. NNNNNNNNNNNNNNNNNNNNNRG
+    pub fn search_filter(mut self, search_filter: &[u8]) -> Searcher {
+        self.search_filters.push(search_filter.to_ascii_uppercase());
+        self
+    }
+    pub fn guide(mut self, label: &str, pattern: &[u8], max_mismatches: u32) -> Searcher {
+        self.guides.push(Guide {
+            label: label.to_string(),
+            pattern: pattern.to_ascii_uppercase(),
+            max_mismatches,
+        });
+        self
+    }
+    pub fn bulges(mut self, max_dna_bulge: u32, max_rna_bulge: u32) -> Searcher {
+        self.max_dna_bulge = max_dna_bulge;
+        self.max_rna_bulge = max_rna_bulge;
+        self
+    }
+    pub fn device_type(mut self, dev_ty: OclDeviceType) -> Searcher {
+        self.dev_ty = dev_ty;
+        self
+    }
+    // restricts the search to some devices of the device type, numbered as in
+    // OclRunConfig::list_devices
+    pub fn device_ids(mut self, dev_ids: &[usize]) -> Searcher {
+        self.dev_ids = dev_ids.to_vec();
+        self
+    }
+    // thread budget of the Rust CPU backend, see OclRunConfig::threads
+    pub fn threads(mut self, n_threads: usize) -> Searcher {
+        self.n_threads = Some(n_threads);
+        self
+    }
+    pub fn chrom_filter(mut self, chrom_filter: ChromFilter) -> Searcher {
+        self.chrom_filter = chrom_filter;
+        self
+    }
+    pub fn run(self) -> Result<SearchHits> {
+        if self.guides.is_empty() {
+            return Err(CliError::ArgumentError("At least one guide is needed"));
+        }
+        let guide_filters = self
+            .guides
+            .iter()
+            .map(|guide| {
+                self.search_filters
+                    .iter()
+                    .find(|filter| filter.len() == guide.pattern.len())
+                    .cloned()
+                    .ok_or(CliError::ArgumentError(
+                        "Every guide needs a search filter of the same length",
+                    ))
+            })
+            .collect::<Result<Vec<Vec<u8>>>>()?;
+        let mut devices = OclRunConfig::with_device_ids(self.dev_ty, &self.dev_ids)?;
+        if let Some(n_threads) = self.n_threads {
+            devices = devices.threads(n_threads);
+        }
+        let patterns: Vec<Vec<u8>> = self.guides.iter().map(|g| g.pattern.clone()).collect();
+        let (pattern_lens, patterns_4bit) = encode_patterns(&patterns);
+        let (_, filters_4bit) = encode_patterns(&guide_filters);
+        // reverse patterns share the threshold of their forward pattern
+        let max_mismatches: Vec<u32> = self
+            .guides
+            .iter()
+            .chain(self.guides.iter())
+            .map(|g| g.max_mismatches)
+            .collect();
+
+        let (src_sender, src_receiver) = mpsc::sync_channel(4);
+        let (dest_sender, dest_receiver) = mpsc::sync_channel(4);
+        let genome = self.genome;
+        let chrom_filter = self.chrom_filter;
+        let reader_thread = thread::spawn(move || match genome {
+            GenomeSource::Path(genome_path) => {
+                read_genome(&src_sender, &genome_path, &chrom_filter)
+            }
+            GenomeSource::Sequences(sequences) => {
+                let sequences: Vec<(&str, &[u8])> = sequences
+                    .iter()
+                    .map(|(name, seq)| (name.as_str(), seq.as_slice()))
+                    .collect();
+                read_sequences_filtered(&src_sender, &sequences, &chrom_filter)
+            }
+        });
+        let (max_dna_bulge, max_rna_bulge) = (self.max_dna_bulge, self.max_rna_bulge);
+        let search_thread = thread::spawn(move || {
+            search(
+                devices,
+                &max_mismatches,
+                max_dna_bulge,
+                max_rna_bulge,
+                &pattern_lens,
+                &patterns_4bit,
+                &filters_4bit,
+                src_receiver,
+                dest_sender,
+            )
+        });
+        Ok(SearchHits {
+            hits: dest_receiver,
+            pending: Vec::new().into_iter(),
+            guides: self.guides,
+            threads: vec![search_thread, reader_thread],
+        })
+    }
+}
+
+// iterator over the hits of a running search, ends with an error if the genome could not be read
+// or the search failed
+pub struct SearchHits {
+    hits: mpsc::Receiver<Vec<Match>>,
+    pending: std::vec::IntoIter<Match>,
+    guides: Vec<Guide>,
+    // the search and genome reader threads, joined once the hits run out
+    threads: Vec<JoinHandle<Result<()>>>,
+}
+
+impl SearchHits {
+    fn join_threads(&mut self) -> Result<()> {
+        first_error(self.threads.drain(..).map(join_worker))
+    }
+}
+
+impl Iterator for SearchHits {
+    type Item = Result<SearchHit>;
+    fn next(&mut self) -> Option<Result<SearchHit>> {
+        loop {
+            if let Some(m) = self.pending.next() {
+                let guide_idx = m.pattern_idx as usize % self.guides.len();
+                return Some(Ok(SearchHit {
+                    guide_idx,
+                    label: self.guides[guide_idx].label.clone(),
+                    hit: m,
+                }));
+            }
+            match self.hits.recv() {
+                Ok(chunk) => self.pending = chunk.into_iter(),
+                Err(_) => return self.join_threads().err().map(Err),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chrom_chunk::BulgeType;
+
+    #[test]
+    fn test_searcher() {
+        let hits: Vec<SearchHit> = Searcher::new("tests/test_data/upstream1000.2bit")
+            .search_filter(b"NNNNNNNNNNNNNNNNNNNNNRG")
+            .guide("guide1", b"ATGTGGGCTCCCTCCACGCCNNN", 6)
+            .guide("guide2", b"GGCCGACCTGTCGCTGACGCNNN", 6)
+            .device_type(OclDeviceType::CPU)
+            .run()
+            .unwrap()
+            .collect::<Result<Vec<SearchHit>>>()
+            .unwrap();
+        assert_eq!(hits.len(), 2);
+        for hit in hits.iter() {
+            assert_eq!(hit.guide_idx, 0);
+            assert_eq!(hit.label, "guide1");
+            assert_eq!(hit.hit.mismatches, 0);
+            assert!(hit.hit.dna_seq.ends_with(b"GG"));
+        }
+        let missing_filter = Searcher::new("tests/test_data/upstream1000.2bit")
+            .search_filter(b"NNNNNNNNNNNNNNNNNNNNNRG")
+            .guide("guide1", b"ATGTGGGCTCCCTCCACGCCNN", 6)
+            .run();
+        assert!(missing_filter.is_err());
+        let missing_genome = Searcher::new("tests/test_data/missing.2bit")
+            .search_filter(b"NNNNNNNNNNNNNNNNNNNNNRG")
+            .guide("guide1", b"ATGTGGGCTCCCTCCACGCCNNN", 6)
+            .device_type(OclDeviceType::CPU)
+            .run()
+            .unwrap();
+        assert!(missing_genome.last().unwrap().is_err());
+    }
+    #[test]
+    fn test_searcher_sequences() {
+        // the guide with an AGG PAM on the forward strand, and with one mismatch and a TGG PAM
+        // on the reverse strand of the second sequence
+        let plasmid: &[u8] = b"ttacgATGTGGGCTCCCTCCACGCCAGGcgtaa";
+        let amplicon: &[u8] = b"GGCACCAGGCGTGGAGGTAGCCCACATggatc";
+        let hits: Vec<SearchHit> =
+            Searcher::from_sequences(&[("plasmid", plasmid), ("amplicon", amplicon)])
+                .search_filter(b"NNNNNNNNNNNNNNNNNNNNNRG")
+                .guide("guide1", b"ATGTGGGCTCCCTCCACGCCNNN", 1)
+                .device_type(OclDeviceType::CPU)
+                .run()
+                .unwrap()
+                .collect::<Result<Vec<SearchHit>>>()
+                .unwrap();
+        let mut found: Vec<(String, u64, u32, bool)> = hits
+            .iter()
+            .map(|h| {
+                (
+                    h.hit.chr_name.clone(),
+                    h.hit.chrom_idx,
+                    h.hit.mismatches,
+                    h.hit.is_forward,
+                )
+            })
+            .collect();
+        found.sort();
+        assert_eq!(
+            found,
+            vec![
+                ("amplicon".to_string(), 4, 1, false),
+                ("plasmid".to_string(), 5, 0, true),
+            ]
+        );
+    }
+    #[test]
+    fn test_searcher_duplicate_names() {
+        let construct: &[u8] = b"ttacgATGTGGGCTCCCTCCACGCCAGGcgtaa";
+        let hits: Vec<Result<SearchHit>> =
+            Searcher::from_sequences(&[("amplicon", construct), ("amplicon", construct)])
+                .search_filter(b"NNNNNNNNNNNNNNNNNNNNNRG")
+                .guide("guide1", b"ATGTGGGCTCCCTCCACGCCNNN", 1)
+                .device_type(OclDeviceType::CPU)
+                .run()
+                .unwrap()
+                .collect();
+        // no hits are searched, the duplicate name is the error
+        assert_eq!(hits.len(), 1);
+        assert!(matches!(hits[0], Err(CliError::BadFileFormat(_))));
+    }
+    #[test]
+    fn test_searcher_bulge_padding() {
+        // the padding of the bulge variants lies past the end of the first sequence and
+        // over the N run of the second one
+        let chrom_end: &[u8] = b"ttacgATGTGGGCTCCCTCCACGCCAGG";
+        let n_run: &[u8] = b"ttacgATGTGGGCTCCCTCCACGCCAGGNNNNNNNNacgt";
+        let hits: Vec<SearchHit> =
+            Searcher::from_sequences(&[("chrom_end", chrom_end), ("n_run", n_run)])
+                .search_filter(b"NNNNNNNNNNNNNNNNNNNNNRG")
+                .guide("guide1", b"ATGTGGGCTCCCTCCACGCCNNN", 0)
+                .bulges(2, 1)
+                .device_type(OclDeviceType::CPU)
+                .run()
+                .unwrap()
+                .collect::<Result<Vec<SearchHit>>>()
+                .unwrap();
+        let mut found: Vec<(String, u64, u32, BulgeType)> = hits
+            .iter()
+            .map(|h| {
+                (
+                    h.hit.chr_name.clone(),
+                    h.hit.chrom_idx,
+                    h.hit.mismatches,
+                    h.hit.bulge_type,
+                )
+            })
+            .collect();
+        found.sort_by(|a, b| a.0.cmp(&b.0));
+        assert_eq!(
+            found,
+            vec![
+                ("chrom_end".to_string(), 5, 0, BulgeType::None),
+                // leaving out one C at the end of the protospacer lines the guide up as well
+                ("chrom_end".to_string(), 5, 0, BulgeType::Rna),
+                ("n_run".to_string(), 5, 0, BulgeType::None),
+                ("n_run".to_string(), 5, 0, BulgeType::Rna),
+            ]
+        );
+    }
+}
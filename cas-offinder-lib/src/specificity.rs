@@ -0,0 +1,121 @@
+use crate::cfd::{cfd_score, CFD_SPACER_LEN};
+use crate::chrom_chunk::Match;
+use crate::cmp_chars;
+
+/*
+Guide specificity, aggregated over all off-targets of a guide:
+  * MIT (Hsu et al. 2013): 100 * 100 / (100 + sum of the MIT hit scores)
+  * CFD: 100 * 100 / (100 + sum of the CFD scores)
+with hit scores scaled to 0..100, as done by CRISPOR.
+The first perfect match of a guide is taken as its on-target site and left out.
+Hits that can not be scored (bulges, other spacer lengths) do not count.
+*/
+
+// weight of a mismatch at spacer positions 1 (PAM distal) to 20 (PAM proximal)
+const MIT_WEIGHTS: [f64; CFD_SPACER_LEN] = [
+    0.0, 0.0, 0.014, 0.0, 0.0, 0.395, 0.317, 0.0, 0.389, 0.079, 0.445, 0.508, 0.613, 0.851, 0.732,
+    0.828, 0.615, 0.804, 0.685, 0.583,
+];
+
+// MIT off-target hit score in 0..1, None for alignments CFD does not apply to either
+pub fn mit_hit_score(dna_seq: &[u8], rna_seq: &[u8]) -> Option<f64> {
+    cfd_score(dna_seq, rna_seq)?;
+    let mismatch_pos: Vec<usize> = (0..CFD_SPACER_LEN)
+        .filter(|pos| !cmp_chars(dna_seq[*pos], rna_seq[*pos]))
+        .collect();
+    let n_mismatches = mismatch_pos.len();
+    if n_mismatches == 0 {
+        return Some(1.0);
+    }
+    let weight_score: f64 = mismatch_pos
+        .iter()
+        .map(|pos| 1.0 - MIT_WEIGHTS[*pos])
+        .product();
+    let dist_score = if n_mismatches > 1 {
+        let max_dist = (CFD_SPACER_LEN - 1) as f64;
+        let dist_sum = (mismatch_pos[n_mismatches - 1] - mismatch_pos[0]) as f64;
+        let mean_dist = dist_sum / (n_mismatches - 1) as f64;
+        1.0 / ((max_dist - mean_dist) / max_dist * 4.0 + 1.0)
+    } else {
+        1.0
+    };
+    Some(weight_score * dist_score / (n_mismatches * n_mismatches) as f64)
+}
+
+#[derive(Default, Clone)]
+pub struct GuideSpecificity {
+    pub n_hits: u64,
+    // hits that got MIT and CFD scores, without the on-target
+    pub n_scored: u64,
+    mit_sum: f64,
+    cfd_sum: f64,
+    has_on_target: bool,
+}
+
+fn aggregate_specificity(hit_sum: f64) -> f64 {
+    100.0 * 100.0 / (100.0 + 100.0 * hit_sum)
+}
+
+impl GuideSpecificity {
+    pub fn add(&mut self, m: &Match) {
+        self.n_hits += 1;
+        let (Some(cfd), Some(mit)) = (m.cfd_score, mit_hit_score(&m.dna_seq, &m.rna_seq)) else {
+            return;
+        };
+        if m.mismatches == 0 && !self.has_on_target {
+            self.has_on_target = true;
+            return;
+        }
+        self.n_scored += 1;
+        self.mit_sum += mit;
+        self.cfd_sum += cfd;
+    }
+    // None until the guide has its on-target or a scored off-target
+    pub fn mit_specificity(&self) -> Option<f64> {
+        self.is_scored()
+            .then(|| aggregate_specificity(self.mit_sum))
+    }
+    pub fn cfd_specificity(&self) -> Option<f64> {
+        self.is_scored()
+            .then(|| aggregate_specificity(self.cfd_sum))
+    }
+    fn is_scored(&self) -> bool {
+        self.has_on_target || self.n_scored > 0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_match;
+
+    #[test]
+    fn test_mit_hit_score() {
+        let guide = b"GGCCGACCTGTCGCTGACGCNNN";
+        assert_eq!(mit_hit_score(b"GGCCGACCTGTCGCTGACGCTGG", guide), Some(1.0));
+        // single mismatch at position 6
+        let score = mit_hit_score(b"GGCCGTCCTGTCGCTGACGCTGG", guide).unwrap();
+        assert!((score - 0.605).abs() < 1e-9);
+        // mismatches at positions 1 and 20, as far apart as possible
+        let score = mit_hit_score(b"TGCCGACCTGTCGCTGACGATGG", guide).unwrap();
+        assert!((score - 0.417 / 4.0).abs() < 1e-9);
+        assert_eq!(
+            mit_hit_score(b"GGCCGACCTGTCGCTGACGTGG", b"GGCCGACCTGTCGCTGACGNNN"),
+            None
+        );
+    }
+    #[test]
+    fn test_guide_specificity() {
+        let guide = b"GGCCGACCTGTCGCTGACGCNNN";
+        let mut spec = GuideSpecificity::default();
+        assert_eq!(spec.mit_specificity(), None);
+        spec.add(&test_match(b"GGCCGACCTGTCGCTGACGCTGG", guide));
+        assert_eq!(spec.mit_specificity(), Some(100.0));
+        assert_eq!(spec.cfd_specificity(), Some(100.0));
+        // a second perfect match is an off-target
+        spec.add(&test_match(b"GGCCGACCTGTCGCTGACGCAGG", guide));
+        assert_eq!(spec.mit_specificity(), Some(50.0));
+        assert_eq!(spec.n_hits, 2);
+        assert_eq!(spec.n_scored, 1);
+    }
+}
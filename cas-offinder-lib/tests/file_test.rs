@@ -23,6 +23,34 @@ fn concat_results_as_str(chunks: &[ChromChunkInfo]) -> Vec<u8> {
     }
     res
 }
+fn concat_results_mask(chunks: &[ChromChunkInfo]) -> Vec<bool> {
+    let mut res: Vec<bool> = Vec::new();
+    for chunk in chunks.iter() {
+        res.extend((0..chunk.size()).map(|idx| getmaskbit(&chunk.mask[..], idx)));
+    }
+    res
+}
+fn get_expected_mask() -> Vec<bool> {
+    let fasta_path = Path::new("./tests/test_data/upstream1000.fa");
+    let mut contents = String::new();
+    File::open(fasta_path)
+        .unwrap()
+        .read_to_string(&mut contents)
+        .unwrap();
+    contents
+        .lines()
+        .filter(|line| !line.starts_with('>'))
+        .flat_map(|line| line.bytes().map(|c| c.is_ascii_lowercase()))
+        .collect()
+}
+fn gather_all_2bit() -> Vec<ChromChunkInfo> {
+    let (sender, receiver): (SyncSender<ChromChunkInfo>, Receiver<ChromChunkInfo>) =
+        mpsc::sync_channel(1);
+    thread::spawn(move || {
+        read_2bit(&sender, Path::new("./tests/test_data/upstream1000.2bit")).unwrap();
+    });
+    gather_chrom_results(&receiver)
+}
 fn get_expected_output() -> Vec<u8> {
     let expected_path = Path::new("./tests/test_data/expected.txt");
     let mut file = File::open(expected_path).unwrap();
@@ -71,3 +99,295 @@ fn test_read_fasta() {
     let expected_results = get_expected_output();
     assert_eq!(result_str, expected_results);
 }
+#[test]
+fn test_read_fasta_gz() {
+    let input_path = Path::new("./tests/test_data/compressed/upstream1000.fa.gz");
+    let (sender, receiver): (SyncSender<ChromChunkInfo>, Receiver<ChromChunkInfo>) =
+        mpsc::sync_channel(1);
+    thread::spawn(move || {
+        read_fasta(&sender, input_path).unwrap();
+    });
+    let results = gather_chrom_results(&receiver);
+    let result_str = concat_results_as_str(&results);
+    let expected_results = get_expected_output();
+    assert_eq!(result_str, expected_results);
+}
+#[test]
+fn test_read_fasta_folder_gz() {
+    let input_path = Path::new("./tests/test_data/compressed/");
+    let (sender, receiver): (SyncSender<ChromChunkInfo>, Receiver<ChromChunkInfo>) =
+        mpsc::sync_channel(1);
+    thread::spawn(move || {
+        read_fasta_folder(&sender, input_path).unwrap();
+    });
+    let results = gather_chrom_results(&receiver);
+    let result_str = concat_results_as_str(&results);
+    let expected_results = get_expected_output();
+    assert_eq!(result_str, expected_results);
+}
+#[test]
+fn test_read_2bit_soft_mask() {
+    let input_path = Path::new("./tests/test_data/upstream1000.2bit");
+    let (sender, receiver): (SyncSender<ChromChunkInfo>, Receiver<ChromChunkInfo>) =
+        mpsc::sync_channel(1);
+    thread::spawn(move || {
+        read_2bit(&sender, input_path).unwrap();
+    });
+    let results = gather_chrom_results(&receiver);
+    assert_eq!(concat_results_mask(&results), get_expected_mask());
+}
+#[test]
+fn test_read_fasta_soft_mask() {
+    let input_path = Path::new("./tests/test_data/upstream1000.fa");
+    let (sender, receiver): (SyncSender<ChromChunkInfo>, Receiver<ChromChunkInfo>) =
+        mpsc::sync_channel(1);
+    thread::spawn(move || {
+        read_fasta(&sender, input_path).unwrap();
+    });
+    let results = gather_chrom_results(&receiver);
+    assert_eq!(concat_results_mask(&results), get_expected_mask());
+}
+#[test]
+fn test_read_fasta_records() {
+    // every record starts at position 0, whatever the length of the records before it
+    let dir = tempfile::tempdir().unwrap();
+    let fasta_path = dir.path().join("two_records.fa");
+    std::fs::write(
+        &fasta_path,
+        b">chr1\nACGTACGTAC\nGTAC\n>chr2\nTTATGTGGGCTCCCTCCACGCCAGGT\n",
+    )
+    .unwrap();
+    let (sender, receiver): (SyncSender<ChromChunkInfo>, Receiver<ChromChunkInfo>) =
+        mpsc::sync_channel(1);
+    let read_path = fasta_path.clone();
+    thread::spawn(move || {
+        read_fasta(&sender, &read_path).unwrap();
+    });
+    let chunks: Vec<(String, u64, u64)> = gather_chrom_results(&receiver)
+        .into_iter()
+        .map(|c| (c.chr_name, c.chunk_start, c.chunk_end))
+        .collect();
+    assert_eq!(
+        chunks,
+        vec![("chr1".to_string(), 0, 14), ("chr2".to_string(), 0, 26)]
+    );
+    let hits: Vec<(String, u64)> = Searcher::new(&fasta_path)
+        .search_filter(b"NNNNNNNNNNNNNNNNNNNNNRG")
+        .guide("guide1", b"ATGTGGGCTCCCTCCACGCCNNN", 0)
+        .device_type(OclDeviceType::CPU)
+        .run()
+        .unwrap()
+        .map(|hit| {
+            let m = hit.unwrap().hit;
+            (m.chr_name, m.chrom_idx)
+        })
+        .collect();
+    assert_eq!(hits, vec![("chr2".to_string(), 2)]);
+}
+#[test]
+fn test_read_filtered() {
+    let make_filter = || {
+        let mut filter = ChromFilter::default();
+        filter.include("NM_00*").unwrap();
+        filter.exclude("re:_r$").unwrap();
+        filter
+    };
+    let (sender_2bit, receiver_2bit): (SyncSender<ChromChunkInfo>, Receiver<ChromChunkInfo>) =
+        mpsc::sync_channel(1);
+    thread::spawn(move || {
+        let input_path = Path::new("./tests/test_data/upstream1000.2bit");
+        read_2bit_filtered(&sender_2bit, input_path, &make_filter()).unwrap();
+    });
+    let results_2bit = gather_chrom_results(&receiver_2bit);
+    let (sender_fa, receiver_fa): (SyncSender<ChromChunkInfo>, Receiver<ChromChunkInfo>) =
+        mpsc::sync_channel(1);
+    thread::spawn(move || {
+        let input_path = Path::new("./tests/test_data/upstream1000.fa");
+        read_fasta_filtered(&sender_fa, input_path, &make_filter()).unwrap();
+    });
+    let results_fa = gather_chrom_results(&receiver_fa);
+    assert!(!results_2bit.is_empty());
+    assert!(results_2bit.len() < gather_all_2bit().len());
+    assert!(results_2bit
+        .iter()
+        .all(|c| c.chr_name.starts_with("NM_00") && !c.chr_name.ends_with("_r")));
+    assert_eq!(
+        concat_results_as_str(&results_2bit),
+        concat_results_as_str(&results_fa)
+    );
+}
+#[test]
+fn test_read_sequences() {
+    let fasta_path = Path::new("./tests/test_data/upstream1000.fa");
+    let mut contents = String::new();
+    File::open(fasta_path)
+        .unwrap()
+        .read_to_string(&mut contents)
+        .unwrap();
+    let mut records: Vec<(String, Vec<u8>)> = Vec::new();
+    for line in contents.lines() {
+        match line.strip_prefix('>') {
+            Some(name) => records.push((name.to_string(), Vec::new())),
+            None => records.last_mut().unwrap().1.extend(line.bytes()),
+        }
+    }
+    let (sender, receiver): (SyncSender<ChromChunkInfo>, Receiver<ChromChunkInfo>) =
+        mpsc::sync_channel(1);
+    thread::spawn(move || {
+        let sequences: Vec<(&str, &[u8])> = records
+            .iter()
+            .map(|(name, seq)| (name.as_str(), seq.as_slice()))
+            .collect();
+        read_sequences(&sender, &sequences).unwrap();
+    });
+    let results = gather_chrom_results(&receiver);
+    assert_eq!(concat_results_as_str(&results), get_expected_output());
+    assert_eq!(concat_results_mask(&results), get_expected_mask());
+}
+#[test]
+fn test_per_guide_mismatches() {
+    type Hit = (usize, String, u64, u32);
+    let run = |guides: &[(&[u8], u32)]| -> Vec<Hit> {
+        let mut searcher = Searcher::new("./tests/test_data/upstream1000.2bit")
+            .search_filter(b"NNNNNNNNNNNNNNNNNNNNNRG")
+            .device_type(OclDeviceType::CPU);
+        for (idx, (pattern, max_mismatches)) in guides.iter().enumerate() {
+            searcher = searcher.guide(&idx.to_string(), pattern, *max_mismatches);
+        }
+        let mut hits: Vec<Hit> = searcher
+            .run()
+            .unwrap()
+            .map(|hit| {
+                let hit = hit.unwrap();
+                (
+                    hit.guide_idx,
+                    hit.hit.chr_name,
+                    hit.hit.chrom_idx,
+                    hit.hit.mismatches,
+                )
+            })
+            .collect();
+        hits.sort();
+        hits
+    };
+    let first: &[u8] = b"TTCCACTCCTTCAGCATAATNNN";
+    let second: &[u8] = b"CCCGTTTCGCAGCTGAGGCGNNN";
+    let hits = run(&[(first, 3), (second, 8)]);
+    let (first_hits, second_hits): (Vec<Hit>, Vec<Hit>) = hits.into_iter().partition(|h| h.0 == 0);
+    // each guide finds what it finds when searched alone with its own threshold
+    assert_eq!(first_hits, run(&[(first, 3)]));
+    let second_alone: Vec<Hit> = run(&[(second, 8)])
+        .into_iter()
+        .map(|(_, chr_name, pos, mismatches)| (1, chr_name, pos, mismatches))
+        .collect();
+    assert_eq!(second_hits, second_alone);
+    assert!(first_hits.iter().all(|h| h.3 <= 3));
+    assert!(second_hits.iter().any(|h| h.3 > 3));
+    // the lower threshold of the first guide does drop some of its hits
+    assert!(run(&[(first, 8)]).len() > first_hits.len());
+}
+#[test]
+fn test_read_fasta_repeated_record() {
+    let dir = tempfile::tempdir().unwrap();
+    let fasta_path = dir.path().join("repeated_record.fa");
+    std::fs::write(
+        &fasta_path,
+        b">amplicon\nTTATGTGGGCTCCCTCCACGCCAGGT\n>amplicon\nACGTACGTAC\n",
+    )
+    .unwrap();
+    let (sender, receiver): (SyncSender<ChromChunkInfo>, Receiver<ChromChunkInfo>) =
+        mpsc::sync_channel(1);
+    let read_path = fasta_path.clone();
+    let read_thread = thread::spawn(move || read_fasta(&sender, &read_path));
+    gather_chrom_results(&receiver);
+    assert!(matches!(
+        read_thread.join().unwrap(),
+        Err(CliError::BadFileFormat(_))
+    ));
+    // the search reports the error instead of panicking
+    let hits: Vec<Result<SearchHit>> = Searcher::new(&fasta_path)
+        .search_filter(b"NNNNNNNNNNNNNNNNNNNNNRG")
+        .guide("guide1", b"ATGTGGGCTCCCTCCACGCCNNN", 0)
+        .device_type(OclDeviceType::CPU)
+        .run()
+        .unwrap()
+        .collect();
+    assert!(matches!(hits.last(), Some(Err(CliError::BadFileFormat(_)))));
+}
+#[test]
+fn test_read_fasta_full_chunks() {
+    // 60 column lines do not add up to a chunk, the line at a chunk end is split
+    let mut seq: Vec<u8> = (0..70_000).map(|i| b"ACGT"[i * 7 % 4]).collect();
+    // a target across the chunk end and a line end is only found if the chunk is full
+    let target_pos = CHUNK_SIZE - 21;
+    seq[target_pos..target_pos + 23].copy_from_slice(b"ATGTGGGCTCCCTCCACGCCAGG");
+    let mut contents = b">chr1\n".to_vec();
+    for line in seq.chunks(60) {
+        contents.extend(line);
+        contents.push(b'\n');
+    }
+    let dir = tempfile::tempdir().unwrap();
+    let fasta_path = dir.path().join("full_chunks.fa");
+    std::fs::write(&fasta_path, &contents).unwrap();
+    let (sender, receiver): (SyncSender<ChromChunkInfo>, Receiver<ChromChunkInfo>) =
+        mpsc::sync_channel(1);
+    let read_path = fasta_path.clone();
+    thread::spawn(move || {
+        read_fasta(&sender, &read_path).unwrap();
+    });
+    let results = gather_chrom_results(&receiver);
+    let sizes: Vec<usize> = results.iter().map(|c| c.size()).collect();
+    assert_eq!(sizes, vec![CHUNK_SIZE, 70_000 - CHUNK_SIZE]);
+    assert_eq!(concat_results_as_str(&results), seq);
+    let hits: Vec<(String, u64)> = Searcher::new(&fasta_path)
+        .search_filter(b"NNNNNNNNNNNNNNNNNNNNNRG")
+        .guide("guide1", b"ATGTGGGCTCCCTCCACGCCNNN", 0)
+        .device_type(OclDeviceType::CPU)
+        .run()
+        .unwrap()
+        .map(|hit| {
+            let m = hit.unwrap().hit;
+            (m.chr_name, m.chrom_idx)
+        })
+        .collect();
+    assert_eq!(hits, vec![("chr1".to_string(), target_pos as u64)]);
+}
+#[test]
+fn test_genome_index_2bit() {
+    let genome_path = Path::new("./tests/test_data/upstream1000.2bit");
+    let search_filter = b"NNNNNNNNNNNNNNNNNNNNNRG";
+    let patterns = vec![
+        b"TTCCACTCCTTCAGCATAATNNN".to_vec(),
+        b"CCCGTTTCGCAGCTGAGGCGNNN".to_vec(),
+    ];
+    let index = GenomeIndex::build(genome_path, search_filter, 5).unwrap();
+    let (sender, receiver) = mpsc::sync_channel(4);
+    let collect_thread = thread::spawn(move || -> Vec<(String, u64, u32, u32)> {
+        receiver
+            .iter()
+            .flatten()
+            .map(|m: Match| (m.chr_name, m.chrom_idx, m.pattern_idx, m.mismatches))
+            .collect()
+    });
+    index
+        .search(&patterns, &[5, 4], &ChromFilter::default(), 2, &sender)
+        .unwrap();
+    drop(sender);
+    let mut indexed = collect_thread.join().unwrap();
+    let mut scanned: Vec<(String, u64, u32, u32)> = Searcher::new(genome_path)
+        .search_filter(search_filter)
+        .guide("first", &patterns[0], 5)
+        .guide("second", &patterns[1], 4)
+        .device_type(OclDeviceType::CPU)
+        .run()
+        .unwrap()
+        .map(|hit| {
+            let m = hit.unwrap().hit;
+            (m.chr_name, m.chrom_idx, m.pattern_idx, m.mismatches)
+        })
+        .collect();
+    indexed.sort();
+    scanned.sort();
+    assert!(!scanned.is_empty());
+    assert_eq!(indexed, scanned);
+}